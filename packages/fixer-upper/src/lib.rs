@@ -1,10 +1,13 @@
 use chrono::{DateTime, Utc};
+pub use matchbook_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use serde_with::skip_serializing_none;
 
+pub mod tagvalue;
+
 pub type UtcTimeStamp = DateTime<Utc>;
-pub type Price = f32;
-pub type Quantity = f32;
+pub type Price = Decimal;
+pub type Quantity = Decimal;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
@@ -26,10 +29,12 @@ pub struct Header {
     #[serde(rename = "TargetCompID")]
     pub target_comp_id: String,
     pub msg_seq_num: u64,
+    pub poss_dup_flag: Option<bool>,
     pub sending_time: UtcTimeStamp,
+    pub orig_sending_time: Option<UtcTimeStamp>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum MessageType {
     #[serde(rename = "0")]
     HeartBeat,
@@ -123,6 +128,9 @@ pub enum MessageType {
     BidResponse,
     #[serde(rename = "m")]
     ListStrikePrice,
+    /// Any MsgType not listed here, so it can still be answered with a reject. It's never sent
+    #[serde(other, skip_serializing)]
+    Unsupported,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -165,8 +173,92 @@ pub struct Body {
     pub ord_type: Option<OrderType>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub order_qty: Option<Quantity>,
+    pub max_floor: Option<Quantity>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub price: Option<Price>,
+    pub stop_px: Option<Price>,
+    pub time_in_force: Option<TimeInForce>,
+    pub exec_inst: Option<String>,
+    pub self_match_prevention_instruction: Option<SelfMatchPreventionInstruction>,
+    pub cxl_rej_response_to: Option<CxlRejResponseTo>,
+    pub text: Option<String>,
+    pub encrypt_method: Option<u64>,
+    pub heart_bt_int: Option<u64>,
+    pub reset_seq_num_flag: Option<bool>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(rename = "TestReqID")]
+    pub test_req_id: Option<String>,
+    pub begin_seq_no: Option<u64>,
+    pub end_seq_no: Option<u64>,
+    pub gap_fill_flag: Option<bool>,
+    pub new_seq_no: Option<u64>,
+    pub ref_seq_num: Option<u64>,
+    #[serde(rename = "RefTagID")]
+    pub ref_tag_id: Option<u64>,
+    pub ref_msg_type: Option<MessageType>,
+    pub session_reject_reason: Option<SessionRejectReason>,
+    #[serde(rename = "BusinessRejectRefID")]
+    pub business_reject_ref_id: Option<String>,
+    pub business_reject_reason: Option<BusinessRejectReason>,
+}
+
+/// Why a `Reject (3)` turned a message away
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SessionRejectReason {
+    #[serde(rename = "0")]
+    InvalidTagNumber,
+    #[serde(rename = "1")]
+    RequiredTagMissing,
+    #[serde(rename = "2")]
+    TagNotDefinedForThisMessageType,
+    #[serde(rename = "3")]
+    UndefinedTag,
+    #[serde(rename = "4")]
+    TagSpecifiedWithoutAValue,
+    #[serde(rename = "5")]
+    ValueIsIncorrect,
+    #[serde(rename = "6")]
+    IncorrectDataFormatForValue,
+    #[serde(rename = "7")]
+    DecryptionProblem,
+    #[serde(rename = "8")]
+    SignatureProblem,
+    #[serde(rename = "9")]
+    CompIdProblem,
+    #[serde(rename = "10")]
+    SendingTimeAccuracyProblem,
+    #[serde(rename = "11")]
+    InvalidMsgType,
+    #[serde(rename = "99")]
+    Other,
+}
+
+/// Why a `BusinessMessageReject (j)` turned a message away
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BusinessRejectReason {
+    #[serde(rename = "0")]
+    Other,
+    #[serde(rename = "1")]
+    UnknownId,
+    #[serde(rename = "2")]
+    UnknownSecurity,
+    #[serde(rename = "3")]
+    UnsupportedMessageType,
+    #[serde(rename = "4")]
+    ApplicationNotAvailable,
+    #[serde(rename = "5")]
+    ConditionallyRequiredFieldMissing,
+    #[serde(rename = "6")]
+    NotAuthorized,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum CxlRejResponseTo {
+    #[serde(rename = "1")]
+    OrderCancelRequest,
+    #[serde(rename = "2")]
+    OrderCancelReplaceRequest,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -213,6 +305,8 @@ pub enum ExecType {
     Restated,
     #[serde(rename = "E")]
     PendingReplace,
+    #[serde(rename = "L")]
+    TriggeredOrActivatedBySystem,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -291,6 +385,37 @@ pub enum OrderType {
     Pegged,
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum TimeInForce {
+    #[serde(rename = "0")]
+    Day,
+    #[serde(rename = "1")]
+    GoodTillCancel,
+    #[serde(rename = "2")]
+    AtTheOpening,
+    #[serde(rename = "3")]
+    ImmediateOrCancel,
+    #[serde(rename = "4")]
+    FillOrKill,
+    #[serde(rename = "5")]
+    GoodTillCrossing,
+    #[serde(rename = "6")]
+    GoodTillDate,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SelfMatchPreventionInstruction {
+    #[serde(rename = "1")]
+    CancelAggressive,
+    #[serde(rename = "2")]
+    CancelPassive,
+    #[serde(rename = "3")]
+    CancelAggressiveAndPassive,
+}
+
+/// `ExecInst` value asking for an order to only add liquidity
+pub const EXEC_INST_PARTICIPATE_DONT_INITIATE: &str = "6";
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum Side {
     #[serde(rename = "1")]
@@ -300,7 +425,7 @@ pub enum Side {
 }
 
 #[skip_serializing_none]
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(rename_all = "PascalCase")]
 pub struct Trailer {
     pub signature_length: Option<usize>,
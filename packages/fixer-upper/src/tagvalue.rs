@@ -0,0 +1,506 @@
+//! The classic FIX `tag=value` wire format, where every field is written as its tag number, an `=` and its value,
+//! terminated by an SOH (`0x01`) byte
+use crate::Message;
+use bytes::{Buf, BufMut, BytesMut};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde_json::{Map, Value};
+use std::io::{Error, ErrorKind};
+use tokio_util::codec::{Decoder, Encoder};
+
+const SOH: u8 = 0x01;
+const BEGIN_STRING_TAG: u32 = 8;
+const BODY_LENGTH_TAG: u32 = 9;
+const CHECK_SUM_TAG: u32 = 10;
+/// Length of the `10=xxx<SOH>` trailer that ends every message
+const CHECK_SUM_FIELD_LENGTH: usize = 7;
+/// Messages claiming to be longer than this are rejected instead of being buffered
+const MAX_BODY_LENGTH: usize = 64 * 1024;
+/// Longest the BeginString and BodyLength fields that come before the body can be together
+const MAX_HEADER_LENGTH: usize = 64;
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H:%M:%S%.3f";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Section {
+    Header,
+    Body,
+    Trailer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    String,
+    Int,
+    /// `Y` or `N`
+    Boolean,
+    UtcTimestamp,
+}
+
+#[derive(Debug)]
+struct Field {
+    tag: u32,
+    name: &'static str,
+    section: Section,
+    kind: Kind,
+}
+
+const fn field(tag: u32, name: &'static str, section: Section, kind: Kind) -> Field {
+    Field {
+        tag,
+        name,
+        section,
+        kind,
+    }
+}
+
+/// Every field we know how to map, by tag and by the name it's serialized under. Fields are written in this order,
+/// so `BeginString`, `BodyLength` and `MsgType` have to stay first
+const FIELDS: &[Field] = &[
+    field(8, "BeginString", Section::Header, Kind::String),
+    field(9, "BodyLength", Section::Header, Kind::Int),
+    field(35, "MsgType", Section::Header, Kind::String),
+    field(49, "SenderCompID", Section::Header, Kind::String),
+    field(56, "TargetCompID", Section::Header, Kind::String),
+    field(34, "MsgSeqNum", Section::Header, Kind::Int),
+    field(43, "PossDupFlag", Section::Header, Kind::Boolean),
+    field(52, "SendingTime", Section::Header, Kind::UtcTimestamp),
+    field(122, "OrigSendingTime", Section::Header, Kind::UtcTimestamp),
+    field(11, "ClOrdID", Section::Body, Kind::String),
+    field(37, "OrderID", Section::Body, Kind::String),
+    field(39, "OrdStatus", Section::Body, Kind::String),
+    field(17, "ExecID", Section::Body, Kind::String),
+    field(20, "ExecTransType", Section::Body, Kind::String),
+    field(150, "ExecType", Section::Body, Kind::String),
+    field(151, "LeavesQty", Section::Body, Kind::String),
+    field(14, "CumQty", Section::Body, Kind::String),
+    field(6, "AvgPx", Section::Body, Kind::String),
+    field(55, "Symbol", Section::Body, Kind::String),
+    field(54, "Side", Section::Body, Kind::String),
+    field(60, "TransactTime", Section::Body, Kind::UtcTimestamp),
+    field(21, "HandlInst", Section::Body, Kind::String),
+    field(40, "OrdType", Section::Body, Kind::String),
+    field(38, "OrderQty", Section::Body, Kind::String),
+    field(111, "MaxFloor", Section::Body, Kind::String),
+    field(44, "Price", Section::Body, Kind::String),
+    field(99, "StopPx", Section::Body, Kind::String),
+    field(59, "TimeInForce", Section::Body, Kind::String),
+    field(18, "ExecInst", Section::Body, Kind::String),
+    field(
+        2964,
+        "SelfMatchPreventionInstruction",
+        Section::Body,
+        Kind::String,
+    ),
+    field(434, "CxlRejResponseTo", Section::Body, Kind::String),
+    field(58, "Text", Section::Body, Kind::String),
+    field(98, "EncryptMethod", Section::Body, Kind::Int),
+    field(108, "HeartBtInt", Section::Body, Kind::Int),
+    field(141, "ResetSeqNumFlag", Section::Body, Kind::Boolean),
+    field(553, "Username", Section::Body, Kind::String),
+    field(554, "Password", Section::Body, Kind::String),
+    field(112, "TestReqID", Section::Body, Kind::String),
+    field(7, "BeginSeqNo", Section::Body, Kind::Int),
+    field(16, "EndSeqNo", Section::Body, Kind::Int),
+    field(123, "GapFillFlag", Section::Body, Kind::Boolean),
+    field(36, "NewSeqNo", Section::Body, Kind::Int),
+    field(45, "RefSeqNum", Section::Body, Kind::Int),
+    field(371, "RefTagID", Section::Body, Kind::Int),
+    field(372, "RefMsgType", Section::Body, Kind::String),
+    field(373, "SessionRejectReason", Section::Body, Kind::String),
+    field(379, "BusinessRejectRefID", Section::Body, Kind::String),
+    field(380, "BusinessRejectReason", Section::Body, Kind::String),
+    field(93, "SignatureLength", Section::Trailer, Kind::Int),
+    field(89, "Signature", Section::Trailer, Kind::String),
+];
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Sum of every byte modulo 256, as used by the `CheckSum` field
+fn check_sum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |acc, &x| acc.wrapping_add(x))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TagValueCodec {
+    body: Vec<u8>,
+}
+
+impl TagValueCodec {
+    pub fn new() -> Self {
+        Self {
+            body: Vec::with_capacity(1024),
+        }
+    }
+}
+
+impl Encoder<Message> for TagValueCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let value = serde_json::to_value(&item)?;
+        let section = |section: Section| match section {
+            Section::Header => &value["Header"],
+            Section::Body => &value["Body"],
+            Section::Trailer => &value["Trailer"],
+        };
+
+        let begin_string = section(Section::Header)["BeginString"]
+            .as_str()
+            .ok_or_else(|| invalid_data("missing BeginString".to_string()))?;
+
+        self.body.clear();
+        for field in FIELDS
+            .iter()
+            .filter(|x| x.tag != BEGIN_STRING_TAG && x.tag != BODY_LENGTH_TAG)
+        {
+            let value = match &section(field.section)[field.name] {
+                Value::Null => continue,
+                Value::String(x) if field.kind == Kind::UtcTimestamp => {
+                    DateTime::parse_from_rfc3339(x)
+                        .map_err(|e| invalid_data(format!("{}: {}", field.name, e)))?
+                        .format(TIMESTAMP_FORMAT)
+                        .to_string()
+                }
+                Value::String(x) => x.clone(),
+                Value::Number(x) => x.to_string(),
+                Value::Bool(x) => if *x { "Y" } else { "N" }.to_string(),
+                x => {
+                    return Err(invalid_data(format!(
+                        "{} can't be written as {}",
+                        x, field.name
+                    )))
+                }
+            };
+
+            if value.as_bytes().contains(&SOH) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} contains an SOH", field.name),
+                ));
+            }
+            self.body
+                .extend_from_slice(format!("{}={}", field.tag, value).as_bytes());
+            self.body.push(SOH);
+        }
+
+        let start = dst.len();
+        dst.put(format!("{}={}", BEGIN_STRING_TAG, begin_string).as_bytes());
+        dst.put_u8(SOH);
+        dst.put(format!("{}={}", BODY_LENGTH_TAG, self.body.len()).as_bytes());
+        dst.put_u8(SOH);
+        dst.put(&self.body[..]);
+        let check_sum = check_sum(&dst[start..]);
+        dst.put(format!("{}={:03}", CHECK_SUM_TAG, check_sum).as_bytes());
+        dst.put_u8(SOH);
+        Ok(())
+    }
+}
+
+impl Decoder for TagValueCodec {
+    type Item = Message;
+
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let (body_end, message_end) = match frame(src) {
+            Ok(Some(x)) => x,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                skip_message(src);
+                return Err(e);
+            }
+        };
+
+        let message = src.split_to(message_end);
+        let expected_check_sum = match parse_field(&message[body_end..message_end - 1])? {
+            (CHECK_SUM_TAG, x) if message[message_end - 1] == SOH => x,
+            _ => {
+                return Err(invalid_data(
+                    "message doesn't end with CheckSum, BodyLength is wrong".to_string(),
+                ))
+            }
+        };
+        let check_sum = format!("{:03}", check_sum(&message[..body_end]));
+        if check_sum != expected_check_sum {
+            return Err(invalid_data(format!(
+                "CheckSum is {} but the message sums to {}",
+                expected_check_sum, check_sum
+            )));
+        }
+
+        let mut header = Map::new();
+        let mut body = Map::new();
+        let mut trailer = Map::new();
+        for x in message[..body_end - 1].split(|&x| x == SOH) {
+            let (tag, value) = parse_field(x)?;
+            // fields we don't know about are skipped rather than rejected
+            let field = match FIELDS.iter().find(|x| x.tag == tag) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            let value = match field.kind {
+                Kind::String => Value::String(value.to_string()),
+                Kind::Int => Value::Number(
+                    value
+                        .parse::<u64>()
+                        .map_err(|e| invalid_data(format!("invalid {}: {}", field.name, e)))?
+                        .into(),
+                ),
+                Kind::Boolean => match value {
+                    "Y" => Value::Bool(true),
+                    "N" => Value::Bool(false),
+                    _ => return Err(invalid_data(format!("invalid {}: {}", field.name, value))),
+                },
+                Kind::UtcTimestamp => Value::String(
+                    NaiveDateTime::parse_from_str(value, TIMESTAMP_FORMAT)
+                        .map(|x| Utc.from_utc_datetime(&x).to_rfc3339())
+                        .map_err(|e| invalid_data(format!("invalid {}: {}", field.name, e)))?,
+                ),
+            };
+
+            let section = match field.section {
+                Section::Header => &mut header,
+                Section::Body => &mut body,
+                Section::Trailer => &mut trailer,
+            };
+            section.insert(field.name.to_string(), value);
+        }
+
+        let mut message = Map::new();
+        message.insert("Header".to_string(), Value::Object(header));
+        message.insert("Body".to_string(), Value::Object(body));
+        message.insert("Trailer".to_string(), Value::Object(trailer));
+        Ok(Some(serde_json::from_value(Value::Object(message))?))
+    }
+}
+
+/// Where the message at the start of `src` ends, as the end of its body and of the whole message, once it's all there
+fn frame(src: &mut BytesMut) -> Result<Option<(usize, usize)>, Error> {
+    let begin_string_prefix = format!("{}=", BEGIN_STRING_TAG);
+    if !begin_string_prefix
+        .as_bytes()
+        .iter()
+        .zip(src.iter())
+        .all(|(a, b)| a == b)
+    {
+        return Err(invalid_data(
+            "message doesn't start with BeginString".to_string(),
+        ));
+    }
+
+    let too_long = || {
+        invalid_data(format!(
+            "BeginString and BodyLength are longer than the maximum of {}",
+            MAX_HEADER_LENGTH
+        ))
+    };
+    let header = &src[..src.len().min(MAX_HEADER_LENGTH)];
+    let begin_string_end = match header.iter().position(|&x| x == SOH) {
+        Some(x) => x + 1,
+        None if src.len() >= MAX_HEADER_LENGTH => return Err(too_long()),
+        None => return Ok(None),
+    };
+    let body_start = match header[begin_string_end..].iter().position(|&x| x == SOH) {
+        Some(x) => begin_string_end + x + 1,
+        None if src.len() >= MAX_HEADER_LENGTH => return Err(too_long()),
+        None => return Ok(None),
+    };
+
+    let body_length = match parse_field(&src[begin_string_end..body_start - 1])? {
+        (BODY_LENGTH_TAG, x) => x
+            .parse::<usize>()
+            .map_err(|e| invalid_data(format!("invalid BodyLength: {}", e)))?,
+        _ => {
+            return Err(invalid_data(
+                "BeginString isn't followed by BodyLength".to_string(),
+            ))
+        }
+    };
+    if body_length > MAX_BODY_LENGTH {
+        return Err(invalid_data(format!(
+            "BodyLength {} is longer than the maximum of {}",
+            body_length, MAX_BODY_LENGTH
+        )));
+    }
+
+    let body_end = body_start + body_length;
+    let message_end = body_end + CHECK_SUM_FIELD_LENGTH;
+    if src.len() < message_end {
+        src.reserve(message_end - src.len());
+        return Ok(None);
+    }
+    Ok(Some((body_end, message_end)))
+}
+
+/// Throw away the bad message at the start of `src`, up to where the next one looks like it starts, so it isn't
+/// decoded again
+fn skip_message(src: &mut BytesMut) {
+    // every BeginString is FIX.x.y or FIXT.x.y
+    let next = format!("{}=FIX", BEGIN_STRING_TAG);
+    let next = next.as_bytes();
+    let skip = (1..src.len())
+        // the next message might only have started arriving
+        .find(|&i| src[i..].starts_with(next) || next.starts_with(&src[i..]))
+        .unwrap_or_else(|| src.len());
+    src.advance(skip);
+}
+
+/// Split a single `tag=value` field, without its SOH
+fn parse_field(field: &[u8]) -> Result<(u32, &str), Error> {
+    let field = std::str::from_utf8(field).map_err(|e| invalid_data(e.to_string()))?;
+    let mut split = field.splitn(2, '=');
+    match (split.next().map(str::parse), split.next()) {
+        (Some(Ok(tag)), Some(value)) => Ok((tag, value)),
+        _ => Err(invalid_data(format!("'{}' isn't a tag=value field", field))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+
+    fn logon() -> &'static [u8] {
+        b"8=FIX.4.2\x019=80\x0135=A\x0149=seller\x0156=matchbook\x0134=1\x0152=20210316-21:58:53.521\x0198=0\x01108=30\x01347=UTF-8\x0110=106\x01"
+    }
+
+    fn new_order_single() -> Message {
+        Message {
+            header: Header {
+                begin_string: BeginString::Fix_4_4,
+                body_length: None,
+                msg_type: MessageType::NewOrderSingle,
+                sender_comp_id: "seller".to_string(),
+                target_comp_id: "matchbook".to_string(),
+                msg_seq_num: 2,
+                poss_dup_flag: Some(true),
+                sending_time: Utc.timestamp_opt(1615931933, 521_000_000).unwrap(),
+                orig_sending_time: Some(Utc.timestamp_opt(1615931930, 0).unwrap()),
+            },
+            body: Body {
+                cl_ord_id: Some("foobar".to_string()),
+                symbol: Some("ADBE".to_string()),
+                side: Some(Side::Buy),
+                ord_type: Some(OrderType::Limit),
+                price: Some("12.75".parse().unwrap()),
+                order_qty: Some(Decimal::from(100)),
+                text: Some("tag=value".to_string()),
+                ..Body::default()
+            },
+            trailer: Trailer::default(),
+        }
+    }
+
+    #[test]
+    fn decodes_a_message_and_skips_unknown_tags() {
+        let mut src = BytesMut::from(logon());
+        let message = TagValueCodec::new().decode(&mut src).unwrap().unwrap();
+
+        assert!(src.is_empty());
+        assert!(matches!(message.header.msg_type, MessageType::Logon));
+        assert_eq!(message.header.sender_comp_id, "seller");
+        assert_eq!(message.header.msg_seq_num, 1);
+        assert_eq!(message.header.body_length, Some(80));
+        assert_eq!(message.body.heart_bt_int, Some(30));
+        assert_eq!(
+            message.header.sending_time,
+            Utc.timestamp_opt(1615931933, 521_000_000).unwrap()
+        );
+    }
+
+    #[test]
+    fn decodes_message_types_it_doesnt_know() {
+        let message = String::from_utf8(logon().to_vec())
+            .unwrap()
+            .replace("35=A", "35=z")
+            .replace("10=106", "10=163");
+        let mut src = BytesMut::from(message.as_bytes());
+        let message = TagValueCodec::new().decode(&mut src).unwrap().unwrap();
+        assert_eq!(message.header.msg_type, MessageType::Unsupported);
+    }
+
+    #[test]
+    fn round_trips_through_the_wire_format() {
+        let mut codec = TagValueCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(new_order_single(), &mut buf).unwrap();
+        codec.encode(new_order_single(), &mut buf).unwrap();
+
+        for _ in 0..2 {
+            let message = codec.decode(&mut buf).unwrap().unwrap();
+            let expected = new_order_single();
+            assert_eq!(message.header.sending_time, expected.header.sending_time);
+            assert_eq!(message.header.poss_dup_flag, Some(true));
+            assert_eq!(
+                message.header.orig_sending_time,
+                expected.header.orig_sending_time
+            );
+            assert_eq!(message.body.price, expected.body.price);
+            assert_eq!(message.body.order_qty, expected.body.order_qty);
+            assert_eq!(message.body.text, expected.body.text);
+            assert_eq!(message.body.cl_ord_id, expected.body.cl_ord_id);
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_partial_message() {
+        let mut codec = TagValueCodec::new();
+        let mut src = BytesMut::new();
+        for &x in &logon()[..logon().len() - 1] {
+            src.put_u8(x);
+            assert!(codec.decode(&mut src).unwrap().is_none());
+        }
+
+        src.put_u8(SOH);
+        assert!(codec.decode(&mut src).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_bad_check_sums_and_body_lengths() {
+        let bad_check_sum = String::from_utf8(logon().to_vec())
+            .unwrap()
+            .replace("10=106", "10=107");
+        let mut src = BytesMut::from(bad_check_sum.as_bytes());
+        assert!(TagValueCodec::new().decode(&mut src).is_err());
+
+        let bad_body_length = String::from_utf8(logon().to_vec())
+            .unwrap()
+            .replace("9=80", "9=79");
+        let mut src = BytesMut::from(bad_body_length.as_bytes());
+        assert!(TagValueCodec::new().decode(&mut src).is_err());
+
+        let mut src = BytesMut::from(&b"35=A\x01"[..]);
+        assert!(TagValueCodec::new().decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn skips_past_bad_messages_to_the_next_one() {
+        let mut codec = TagValueCodec::new();
+        for bad in &[
+            &b"garbage\x01"[..],
+            b"8=FIX.4.2\x019=x\x0135=A\x01",
+            b"8=FIX.4.2\x019=999999999\x0135=A\x01",
+            b"8=FIX.4.2\x01=80\x01",
+            &[b'8', b'=']
+                .iter()
+                .chain(&[b'x'; 100])
+                .copied()
+                .collect::<Vec<_>>(),
+        ] {
+            let mut src = BytesMut::from(*bad);
+            src.put(logon());
+            assert!(codec.decode(&mut src).is_err());
+            assert!(codec.decode(&mut src).unwrap().is_some());
+            assert!(src.is_empty());
+        }
+
+        // the start of the next message arriving straight after a bad one
+        let mut src = BytesMut::from(&b"garbage\x018"[..]);
+        assert!(codec.decode(&mut src).is_err());
+        src.put(&logon()[1..]);
+        assert!(codec.decode(&mut src).unwrap().is_some());
+    }
+}
@@ -0,0 +1,295 @@
+#![deny(clippy::all)]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::TryFrom;
+
+/// Number of decimal places a `Decimal` can represent
+pub const SCALE: u32 = 8;
+const UNITS_PER_ONE: i64 = 10_i64.pow(SCALE);
+
+/// A fixed point decimal number with `SCALE` decimal places. The arithmetic operators panic rather than wrap around
+/// if the result doesn't fit, use the `checked_` methods where that can happen
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Decimal(i64);
+
+impl Decimal {
+    pub const ZERO: Decimal = Decimal(0);
+    pub const MAX: Decimal = Decimal(i64::MAX);
+    pub const MIN: Decimal = Decimal(i64::MIN);
+
+    /// Creates a decimal from a whole number of its smallest units, i.e. `10^-SCALE`
+    pub const fn from_units(units: i64) -> Decimal {
+        Decimal(units)
+    }
+
+    /// The decimal as a whole number of its smallest units, i.e. `10^-SCALE`
+    pub const fn units(self) -> i64 {
+        self.0
+    }
+
+    /// Returns true if the decimal is a whole number of `step`s, e.g. a price that's a whole number of ticks
+    pub fn is_multiple_of(self, step: Decimal) -> bool {
+        self.0.checked_rem(step.0) == Some(0)
+    }
+
+    /// `self + rhs`, or `None` if it doesn't fit
+    pub fn checked_add(self, rhs: Decimal) -> Option<Decimal> {
+        self.0.checked_add(rhs.0).map(Decimal)
+    }
+
+    /// `self - rhs`, or `None` if it doesn't fit
+    pub fn checked_sub(self, rhs: Decimal) -> Option<Decimal> {
+        self.0.checked_sub(rhs.0).map(Decimal)
+    }
+
+    /// `self * rhs` rounded toward zero to `SCALE` decimal places, or `None` if it doesn't fit
+    pub fn checked_mul(self, rhs: Decimal) -> Option<Decimal> {
+        let units = self.0 as i128 * rhs.0 as i128 / UNITS_PER_ONE as i128;
+        i64::try_from(units).ok().map(Decimal)
+    }
+
+    /// `self + rhs`, stopping at `MIN` or `MAX` instead of overflowing
+    pub fn saturating_add(self, rhs: Decimal) -> Decimal {
+        Decimal(self.0.saturating_add(rhs.0))
+    }
+}
+
+impl From<i32> for Decimal {
+    fn from(x: i32) -> Self {
+        Decimal(x as i64 * UNITS_PER_ONE)
+    }
+}
+
+impl std::ops::Add for Decimal {
+    type Output = Decimal;
+
+    fn add(self, rhs: Decimal) -> Decimal {
+        self.checked_add(rhs).expect("decimal addition overflowed")
+    }
+}
+
+impl std::ops::Sub for Decimal {
+    type Output = Decimal;
+
+    fn sub(self, rhs: Decimal) -> Decimal {
+        self.checked_sub(rhs)
+            .expect("decimal subtraction overflowed")
+    }
+}
+
+impl std::ops::AddAssign for Decimal {
+    fn add_assign(&mut self, rhs: Decimal) {
+        *self = *self + rhs;
+    }
+}
+
+impl std::ops::SubAssign for Decimal {
+    fn sub_assign(&mut self, rhs: Decimal) {
+        *self = *self - rhs;
+    }
+}
+
+impl std::iter::Sum for Decimal {
+    fn sum<I: Iterator<Item = Decimal>>(iter: I) -> Decimal {
+        iter.fold(Decimal::ZERO, |acc, x| acc + x)
+    }
+}
+
+impl<'a> std::iter::Sum<&'a Decimal> for Decimal {
+    fn sum<I: Iterator<Item = &'a Decimal>>(iter: I) -> Decimal {
+        iter.copied().sum()
+    }
+}
+
+impl std::str::FromStr for Decimal {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s),
+        };
+
+        let mut split = digits.splitn(2, '.');
+        let whole = split.next().unwrap_or_default();
+        let fraction = split.next().unwrap_or_default();
+
+        if (whole.is_empty() && fraction.is_empty())
+            || !whole
+                .chars()
+                .chain(fraction.chars())
+                .all(|x| x.is_ascii_digit())
+        {
+            return Err(format!("'{}' is not a decimal number", s).into());
+        }
+
+        if fraction.len() > SCALE as usize {
+            return Err(format!(
+                "'{}' has more than {} decimal places, it can't be represented exactly",
+                s, SCALE
+            )
+            .into());
+        }
+
+        let whole: i64 = if whole.is_empty() { 0 } else { whole.parse()? };
+        let fraction: i64 = if fraction.is_empty() {
+            0
+        } else {
+            fraction.parse::<i64>()? * 10_i64.pow(SCALE - fraction.len() as u32)
+        };
+
+        let units = whole
+            .checked_mul(UNITS_PER_ONE)
+            .and_then(|x| x.checked_add(fraction))
+            .ok_or_else(|| format!("'{}' is too large to be represented", s))?;
+
+        Ok(Decimal(if negative { -units } else { units }))
+    }
+}
+
+impl std::fmt::Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let units = (self.0 as i128).abs();
+        let whole = units / UNITS_PER_ONE as i128;
+        let fraction = units % UNITS_PER_ONE as i128;
+
+        if fraction == 0 {
+            return write!(f, "{}{}", sign, whole);
+        }
+
+        let fraction = format!("{:0width$}", fraction, width = SCALE as usize);
+        write!(f, "{}{}.{}", sign, whole, fraction.trim_end_matches('0'))
+    }
+}
+
+impl Serialize for Decimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(DecimalVisitor)
+    }
+}
+
+/// Accepts decimals written as strings as well as JSON numbers, which are converted through their shortest string
+/// representation so that `12.75` stays exactly `12.75`
+struct DecimalVisitor;
+
+impl<'de> de::Visitor<'de> for DecimalVisitor {
+    type Value = Decimal;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a decimal number")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Decimal, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Decimal, E> {
+        self.visit_str(&v.to_string())
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Decimal, E> {
+        self.visit_str(&v.to_string())
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Decimal, E> {
+        self.visit_str(&v.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use quickcheck::quickcheck;
+    use std::str::FromStr;
+
+    impl quickcheck::Arbitrary for Decimal {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Decimal(i64::arbitrary(g))
+        }
+    }
+
+    #[test]
+    fn formats_without_trailing_zeros() {
+        assert_eq!(Decimal::from_str("12.750").unwrap().to_string(), "12.75");
+        assert_eq!(Decimal::from_str("12.0").unwrap().to_string(), "12");
+        assert_eq!(Decimal::from_str("-0.5").unwrap().to_string(), "-0.5");
+        assert_eq!(
+            Decimal::from_str(".00000001").unwrap().to_string(),
+            "0.00000001"
+        );
+    }
+
+    #[test]
+    fn doesnt_parse_what_it_cant_represent_exactly() {
+        assert!(Decimal::from_str("0.000000001").is_err());
+        assert!(Decimal::from_str("100000000000000").is_err());
+        assert!(Decimal::from_str("").is_err());
+        assert!(Decimal::from_str(".").is_err());
+        assert!(Decimal::from_str("1.2.3").is_err());
+        assert!(Decimal::from_str("1e5").is_err());
+    }
+
+    #[test]
+    fn json_numbers_deserialize_exactly() {
+        let x: Decimal = serde_json::from_str("12.75").unwrap();
+        assert_eq!(x, Decimal::from_str("12.75").unwrap());
+
+        let x: Decimal = serde_json::from_str("0.1").unwrap();
+        assert_eq!(x.to_string(), "0.1");
+
+        let x: Decimal = serde_json::from_str("100").unwrap();
+        assert_eq!(x, Decimal::from(100));
+    }
+
+    #[test]
+    fn only_whole_steps_are_multiples() {
+        let tick = Decimal::from_str("0.05").unwrap();
+        assert!(Decimal::from_str("12.75").unwrap().is_multiple_of(tick));
+        assert!(!Decimal::from_str("12.76").unwrap().is_multiple_of(tick));
+        assert!(!Decimal::from(1).is_multiple_of(Decimal::ZERO));
+    }
+
+    #[test]
+    fn checked_arithmetic_catches_overflow() {
+        let price = Decimal::from_str("12.75").unwrap();
+        let quantity = Decimal::from_str("0.5").unwrap();
+        assert_eq!(price.checked_mul(quantity), Decimal::from_str("6.375").ok());
+        assert_eq!(
+            Decimal::from_str("0.00000003")
+                .unwrap()
+                .checked_mul(quantity),
+            Decimal::from_str("0.00000001").ok()
+        );
+        assert_eq!(
+            Decimal::from(1_000_000).checked_mul(Decimal::from(1_000_000)),
+            None
+        );
+        assert_eq!(Decimal::MAX.checked_add(Decimal::from_units(1)), None);
+        assert_eq!(Decimal::MIN.checked_sub(Decimal::from_units(1)), None);
+        assert_eq!(Decimal::MAX.saturating_add(price), Decimal::MAX);
+        assert!(!Decimal::MIN.is_multiple_of(Decimal::from_units(-1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "decimal addition overflowed")]
+    fn adding_past_the_largest_decimal_panics() {
+        let _ = Decimal::MAX + Decimal::from_units(1);
+    }
+
+    quickcheck! {
+        fn round_trips_through_strings(x: Decimal) -> bool {
+            Decimal::from_str(&x.to_string()).map(|y| x == y).unwrap_or(x == Decimal::MIN)
+        }
+
+        fn round_trips_through_json(x: Decimal) -> bool {
+            let json = serde_json::to_string(&x).unwrap();
+            serde_json::from_str::<Decimal>(&json).map(|y| x == y).unwrap_or(x == Decimal::MIN)
+        }
+    }
+}
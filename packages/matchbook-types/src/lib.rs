@@ -1,6 +1,7 @@
 #![deny(clippy::all)]
 use chrono::{DateTime, Utc};
 use itertools::Itertools;
+pub use matchbook_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 
@@ -8,8 +9,8 @@ pub type OrderId = usize;
 pub type UtcTimeStamp = DateTime<Utc>;
 pub type AccountId = u64;
 pub type ParticipantId = String;
-pub type Price = usize;
-pub type Quantity = usize;
+pub type Price = Decimal;
+pub type Quantity = Decimal;
 pub type SymbolOwned = [char; 4];
 pub type SymbolRef<'a> = &'a SymbolOwned;
 
@@ -19,6 +20,9 @@ pub struct Message {
     #[serde_as(as = "DisplayFromStr")]
     pub id: MessageId,
     pub kind: MessageKind,
+    /// Set on copies of earlier messages sent again by the retransmitter, so they can be told apart from originals
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub replay: bool,
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
@@ -66,6 +70,45 @@ pub enum Side {
     Ask,
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TimeInForce {
+    // rests until the end of the trading day
+    Day,
+    // rests until it's filled or canceled
+    GoodTillCancel,
+    // fills as much as possible immediately, whatever is left expires
+    ImmediateOrCancel,
+    // fills completely and immediately or not at all
+    FillOrKill,
+}
+
+/// What happens when an incoming order would trade with a resting order from the same participant
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SelfTradePrevention {
+    // cancel the incoming order
+    CancelNewest,
+    // cancel the resting order
+    CancelOldest,
+    // cancel both orders
+    CancelBoth,
+    // take the smaller quantity off both orders, canceling the smaller order
+    Decrement,
+}
+
+impl std::str::FromStr for SelfTradePrevention {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cancel-newest" => Ok(SelfTradePrevention::CancelNewest),
+            "cancel-oldest" => Ok(SelfTradePrevention::CancelOldest),
+            "cancel-both" => Ok(SelfTradePrevention::CancelBoth),
+            "decrement" => Ok(SelfTradePrevention::Decrement),
+            unknown => Err(format!("self trade prevention mode '{}' is unknown", unknown).into()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum MessageKind {
     LimitOrderSubmitRequest {
@@ -73,6 +116,13 @@ pub enum MessageKind {
         price: Price,
         quantity: Quantity,
         symbol: SymbolOwned,
+        time_in_force: TimeInForce,
+        // only show this much of the order at a time, keeping the rest in reserve
+        display_quantity: Option<Quantity>,
+        // reject the order instead of letting it take liquidity
+        post_only: bool,
+        // overrides the participant's self trade prevention mode
+        self_trade_prevention: Option<SelfTradePrevention>,
     },
     LimitOrderSubmitRequestAcknowledge {
         id: OrderId,
@@ -81,6 +131,89 @@ pub enum MessageKind {
         quantity: Quantity,
         symbol: SymbolOwned,
     },
+    LimitOrderSubmitReject {
+        side: Side,
+        price: Price,
+        quantity: Quantity,
+        symbol: SymbolOwned,
+        reason: String,
+    },
+    MarketOrderSubmitRequest {
+        side: Side,
+        quantity: Quantity,
+        symbol: SymbolOwned,
+    },
+    MarketOrderSubmitRequestAcknowledge {
+        id: OrderId,
+        side: Side,
+        quantity: Quantity,
+        symbol: SymbolOwned,
+    },
+    MarketOrderSubmitReject {
+        side: Side,
+        quantity: Quantity,
+        symbol: SymbolOwned,
+        reason: String,
+    },
+    StopOrderSubmitRequest {
+        side: Side,
+        // the order is triggered once something trades at or through this price
+        stop_price: Price,
+        // price of the limit order submitted once triggered, a market order is submitted if there isn't one
+        limit_price: Option<Price>,
+        quantity: Quantity,
+        symbol: SymbolOwned,
+    },
+    StopOrderSubmitRequestAcknowledge {
+        id: OrderId,
+        side: Side,
+        stop_price: Price,
+        limit_price: Option<Price>,
+        quantity: Quantity,
+        symbol: SymbolOwned,
+    },
+    StopOrderSubmitReject {
+        side: Side,
+        stop_price: Price,
+        limit_price: Option<Price>,
+        quantity: Quantity,
+        symbol: SymbolOwned,
+        reason: String,
+    },
+    // a stop order was triggered and submitted to the book, its executions follow
+    StopOrderTriggered {
+        id: OrderId,
+        side: Side,
+        stop_price: Price,
+        limit_price: Option<Price>,
+        quantity: Quantity,
+        symbol: SymbolOwned,
+    },
+    // the engine canceled whatever was left of an order, e.g. a market order that ran out of liquidity
+    OrderCanceled {
+        id: OrderId,
+        side: Side,
+        quantity: Quantity,
+        canceled_quantity: Quantity,
+        symbol: SymbolOwned,
+    },
+    // the engine took quantity off an order without it trading, e.g. to prevent a self trade
+    OrderRestated {
+        id: OrderId,
+        side: Side,
+        // new quantity of the order
+        quantity: Quantity,
+        leaves_quantity: Quantity,
+        symbol: SymbolOwned,
+    },
+    // whatever was left of an order expired because of its time in force
+    OrderExpired {
+        id: OrderId,
+        side: Side,
+        quantity: Quantity,
+        expired_quantity: Quantity,
+        symbol: SymbolOwned,
+    },
     Execution {
         id: OrderId,
         price: Price,
@@ -88,8 +221,65 @@ pub enum MessageKind {
         symbol: SymbolOwned,
         side: Side,
     },
+    CancelRequest {
+        id: OrderId,
+        // has to match the order's
+        symbol: SymbolOwned,
+    },
+    CancelAcknowledge {
+        id: OrderId,
+        side: Side,
+        price: Price,
+        // quantity of the original order
+        quantity: Quantity,
+        // quantity that was still open when the order was canceled
+        canceled_quantity: Quantity,
+        symbol: SymbolOwned,
+    },
+    CancelReject {
+        id: OrderId,
+        reason: String,
+    },
+    ReplaceRequest {
+        id: OrderId,
+        price: Price,
+        // new total quantity of the order, including anything that has already been filled
+        quantity: Quantity,
+        // has to match the order's
+        symbol: SymbolOwned,
+    },
+    ReplaceAcknowledge {
+        id: OrderId,
+        side: Side,
+        price: Price,
+        quantity: Quantity,
+        // quantity left open after the replace
+        leaves_quantity: Quantity,
+        symbol: SymbolOwned,
+    },
+    ReplaceReject {
+        id: OrderId,
+        reason: String,
+    },
     // uses the id field of the message wrapper to determine what message to resend
     RetransmitRequest,
+    // asks for every message on the topic of the id field, from its sequence number up to and including
+    // `last_topic_sequence_n`
+    RetransmitRangeRequest {
+        last_topic_sequence_n: u64,
+    },
+    // sent in place of a message that was asked for but can't be retransmitted, its sequence number should be skipped
+    GapFill,
+}
+
+impl MessageKind {
+    /// Retransmit requests carry the id of the message they're asking for rather than one of their own
+    pub fn is_retransmit_request(&self) -> bool {
+        matches!(
+            self,
+            MessageKind::RetransmitRequest | MessageKind::RetransmitRangeRequest { .. }
+        )
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq)]
@@ -0,0 +1,1095 @@
+//! A compact binary encoding of `Message` for the multicast backbone.
+//!
+//! Every message starts with a fixed size header, all integers are little endian
+//!
+//! | offset | size | field                          |
+//! |--------|------|--------------------------------|
+//! | 0      | 1    | schema version                 |
+//! | 1      | 2    | length of the whole message    |
+//! | 3      | 1    | message kind                   |
+//! | 4      | 1    | publisher service kind         |
+//! | 5      | 2    | publisher service number       |
+//! | 7      | 8    | topic sequence number          |
+//! | 15     | 1    | flags, bit 0 is set on replays |
+//! | 16     | 1    | topic id length                |
+//! | 17     | n    | topic id                       |
+//!
+//! followed by the fields of the message kind in the order they're declared. Prices and quantities are written as
+//! their number of `10^-SCALE` units, order ids as `u64`, symbols as 4 ASCII bytes, optional values as a presence byte
+//! followed by the value (which is zeroed when absent) and strings as a `u16` length followed by UTF-8. Kind numbers
+//! must never be reused, add new kinds at the end and bump `SCHEMA_VERSION` whenever an existing layout changes
+use bytes::{BufMut, BytesMut};
+use matchbook_types::*;
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind};
+use tokio_util::codec::{Decoder, Encoder};
+
+pub const SCHEMA_VERSION: u8 = 3;
+/// Largest message that fits in a single UDP datagram on a standard ethernet network without being fragmented
+pub const MAX_MESSAGE_LENGTH: usize = 1472;
+const HEADER_LENGTH: usize = 17;
+const REPLAY_FLAG: u8 = 1;
+
+const LIMIT_ORDER_SUBMIT_REQUEST: u8 = 1;
+const LIMIT_ORDER_SUBMIT_REQUEST_ACKNOWLEDGE: u8 = 2;
+const LIMIT_ORDER_SUBMIT_REJECT: u8 = 3;
+const MARKET_ORDER_SUBMIT_REQUEST: u8 = 4;
+const MARKET_ORDER_SUBMIT_REQUEST_ACKNOWLEDGE: u8 = 5;
+const STOP_ORDER_SUBMIT_REQUEST: u8 = 6;
+const STOP_ORDER_SUBMIT_REQUEST_ACKNOWLEDGE: u8 = 7;
+const STOP_ORDER_TRIGGERED: u8 = 8;
+const ORDER_CANCELED: u8 = 9;
+const ORDER_RESTATED: u8 = 10;
+const ORDER_EXPIRED: u8 = 11;
+const EXECUTION: u8 = 12;
+const CANCEL_REQUEST: u8 = 13;
+const CANCEL_ACKNOWLEDGE: u8 = 14;
+const CANCEL_REJECT: u8 = 15;
+const REPLACE_REQUEST: u8 = 16;
+const REPLACE_ACKNOWLEDGE: u8 = 17;
+const REPLACE_REJECT: u8 = 18;
+const RETRANSMIT_REQUEST: u8 = 19;
+const RETRANSMIT_RANGE_REQUEST: u8 = 20;
+const GAP_FILL: u8 = 21;
+const MARKET_ORDER_SUBMIT_REJECT: u8 = 22;
+const STOP_ORDER_SUBMIT_REJECT: u8 = 23;
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn invalid_input(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BinaryMessageCodec;
+
+impl BinaryMessageCodec {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Encoder<Message> for BinaryMessageCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let start = dst.len();
+        let mut w = Writer { dst };
+        let topic_id = item.id.topic_id.as_bytes();
+        if topic_id.len() > u8::MAX as usize {
+            return Err(invalid_input(format!(
+                "topic id '{}' is too long",
+                item.id.topic_id
+            )));
+        }
+
+        w.u8(SCHEMA_VERSION);
+        // the length is filled in once the message has been written
+        w.u16(0);
+        w.u8(kind_number(&item.kind));
+        w.u8(match item.id.publisher_id.kind {
+            ServiceKind::Port => 0,
+            ServiceKind::MatchingEngine => 1,
+            ServiceKind::Retransmitter => 2,
+        });
+        w.u16(item.id.publisher_id.number);
+        w.u64(item.id.topic_sequence_n);
+        w.u8(if item.replay { REPLAY_FLAG } else { 0 });
+        w.u8(topic_id.len() as u8);
+        w.dst.put(topic_id);
+        w.kind(&item.kind)?;
+
+        let length = dst.len() - start;
+        if length > MAX_MESSAGE_LENGTH {
+            dst.truncate(start);
+            return Err(invalid_input(format!(
+                "message is {} bytes, more than the maximum of {}",
+                length, MAX_MESSAGE_LENGTH
+            )));
+        }
+        dst[start + 1..start + 3].copy_from_slice(&(length as u16).to_le_bytes());
+        Ok(())
+    }
+}
+
+impl Decoder for BinaryMessageCodec {
+    type Item = Message;
+
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < HEADER_LENGTH {
+            return Ok(None);
+        }
+
+        // check the version before waiting on a length we might not be able to trust
+        if src[0] != SCHEMA_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported schema version {}, expected {}",
+                src[0], SCHEMA_VERSION
+            )));
+        }
+
+        let length = u16::from_le_bytes([src[1], src[2]]) as usize;
+        if src.len() < length {
+            return Ok(None);
+        }
+
+        let message = MessageView::new(&src[..length])?.to_message()?;
+        let _ = src.split_to(length);
+        Ok(Some(message))
+    }
+}
+
+/// A message read in place from its binary encoding. The header is read without copying anything, the kind is only
+/// decoded when it's asked for
+#[derive(Debug, Clone, Copy)]
+pub struct MessageView<'a> {
+    buf: &'a [u8],
+    topic_id: &'a str,
+}
+
+impl<'a> MessageView<'a> {
+    /// Check that `buf` starts with a complete message in a schema version we understand
+    pub fn new(buf: &'a [u8]) -> Result<Self, Error> {
+        if buf.len() < HEADER_LENGTH {
+            return Err(invalid_data(format!(
+                "{} bytes is too short for a message",
+                buf.len()
+            )));
+        }
+        if buf[0] != SCHEMA_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported schema version {}, expected {}",
+                buf[0], SCHEMA_VERSION
+            )));
+        }
+
+        let length = u16::from_le_bytes([buf[1], buf[2]]) as usize;
+        let topic_id_end = HEADER_LENGTH + buf[16] as usize;
+        if length > buf.len() || topic_id_end > length {
+            return Err(invalid_data(format!(
+                "message length {} doesn't fit in {} bytes",
+                length,
+                buf.len()
+            )));
+        }
+
+        let buf = &buf[..length];
+        let topic_id = std::str::from_utf8(&buf[HEADER_LENGTH..topic_id_end])
+            .map_err(|e| invalid_data(e.to_string()))?;
+        Ok(Self { buf, topic_id })
+    }
+
+    /// Length of the message in bytes
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn publisher_id(&self) -> Result<ServiceId, Error> {
+        Ok(ServiceId {
+            kind: match self.buf[4] {
+                0 => ServiceKind::Port,
+                1 => ServiceKind::MatchingEngine,
+                2 => ServiceKind::Retransmitter,
+                x => return Err(invalid_data(format!("unknown service kind {}", x))),
+            },
+            number: u16::from_le_bytes([self.buf[5], self.buf[6]]),
+        })
+    }
+
+    pub fn topic_id(&self) -> &'a str {
+        self.topic_id
+    }
+
+    pub fn topic_sequence_n(&self) -> u64 {
+        u64::from_le_bytes(self.buf[7..15].try_into().expect("slice is 8 bytes"))
+    }
+
+    /// Whether the message is a copy of an earlier one sent again by the retransmitter
+    pub fn is_replay(&self) -> bool {
+        self.buf[15] & REPLAY_FLAG != 0
+    }
+
+    pub fn id(&self) -> Result<MessageId, Error> {
+        Ok(MessageId {
+            publisher_id: self.publisher_id()?,
+            topic_id: self.topic_id.to_string(),
+            topic_sequence_n: self.topic_sequence_n(),
+        })
+    }
+
+    pub fn kind(&self) -> Result<MessageKind, Error> {
+        let mut r = Reader {
+            buf: &self.buf[HEADER_LENGTH + self.topic_id.len()..],
+        };
+        let kind = r.kind(self.buf[3])?;
+        if !r.buf.is_empty() {
+            return Err(invalid_data(format!(
+                "{} unexpected bytes after the message",
+                r.buf.len()
+            )));
+        }
+        Ok(kind)
+    }
+
+    pub fn to_message(&self) -> Result<Message, Error> {
+        Ok(Message {
+            id: self.id()?,
+            kind: self.kind()?,
+            replay: self.is_replay(),
+        })
+    }
+}
+
+fn kind_number(kind: &MessageKind) -> u8 {
+    use MessageKind::*;
+
+    match kind {
+        LimitOrderSubmitRequest { .. } => LIMIT_ORDER_SUBMIT_REQUEST,
+        LimitOrderSubmitRequestAcknowledge { .. } => LIMIT_ORDER_SUBMIT_REQUEST_ACKNOWLEDGE,
+        LimitOrderSubmitReject { .. } => LIMIT_ORDER_SUBMIT_REJECT,
+        MarketOrderSubmitRequest { .. } => MARKET_ORDER_SUBMIT_REQUEST,
+        MarketOrderSubmitRequestAcknowledge { .. } => MARKET_ORDER_SUBMIT_REQUEST_ACKNOWLEDGE,
+        MarketOrderSubmitReject { .. } => MARKET_ORDER_SUBMIT_REJECT,
+        StopOrderSubmitRequest { .. } => STOP_ORDER_SUBMIT_REQUEST,
+        StopOrderSubmitRequestAcknowledge { .. } => STOP_ORDER_SUBMIT_REQUEST_ACKNOWLEDGE,
+        StopOrderSubmitReject { .. } => STOP_ORDER_SUBMIT_REJECT,
+        StopOrderTriggered { .. } => STOP_ORDER_TRIGGERED,
+        OrderCanceled { .. } => ORDER_CANCELED,
+        OrderRestated { .. } => ORDER_RESTATED,
+        OrderExpired { .. } => ORDER_EXPIRED,
+        Execution { .. } => EXECUTION,
+        CancelRequest { .. } => CANCEL_REQUEST,
+        CancelAcknowledge { .. } => CANCEL_ACKNOWLEDGE,
+        CancelReject { .. } => CANCEL_REJECT,
+        ReplaceRequest { .. } => REPLACE_REQUEST,
+        ReplaceAcknowledge { .. } => REPLACE_ACKNOWLEDGE,
+        ReplaceReject { .. } => REPLACE_REJECT,
+        RetransmitRequest => RETRANSMIT_REQUEST,
+        RetransmitRangeRequest { .. } => RETRANSMIT_RANGE_REQUEST,
+        GapFill => GAP_FILL,
+    }
+}
+
+struct Writer<'a> {
+    dst: &'a mut BytesMut,
+}
+
+impl<'a> Writer<'a> {
+    fn u8(&mut self, x: u8) {
+        self.dst.put_u8(x);
+    }
+
+    fn u16(&mut self, x: u16) {
+        self.dst.put_u16_le(x);
+    }
+
+    fn u64(&mut self, x: u64) {
+        self.dst.put_u64_le(x);
+    }
+
+    fn bool(&mut self, x: bool) {
+        self.u8(x as u8);
+    }
+
+    fn id(&mut self, x: OrderId) {
+        self.u64(x as u64);
+    }
+
+    fn decimal(&mut self, x: Decimal) {
+        self.dst.put_i64_le(x.units());
+    }
+
+    fn optional_decimal(&mut self, x: Option<Decimal>) {
+        self.bool(x.is_some());
+        self.decimal(x.unwrap_or_default());
+    }
+
+    fn side(&mut self, x: Side) {
+        self.u8(match x {
+            Side::Bid => 0,
+            Side::Ask => 1,
+        });
+    }
+
+    fn time_in_force(&mut self, x: TimeInForce) {
+        self.u8(match x {
+            TimeInForce::Day => 0,
+            TimeInForce::GoodTillCancel => 1,
+            TimeInForce::ImmediateOrCancel => 2,
+            TimeInForce::FillOrKill => 3,
+        });
+    }
+
+    fn self_trade_prevention(&mut self, x: Option<SelfTradePrevention>) {
+        self.u8(match x {
+            None => 0,
+            Some(SelfTradePrevention::CancelNewest) => 1,
+            Some(SelfTradePrevention::CancelOldest) => 2,
+            Some(SelfTradePrevention::CancelBoth) => 3,
+            Some(SelfTradePrevention::Decrement) => 4,
+        });
+    }
+
+    fn symbol(&mut self, x: &SymbolOwned) -> Result<(), Error> {
+        for &c in x.iter() {
+            if !c.is_ascii() {
+                return Err(invalid_input(format!("symbol {:?} isn't ASCII", x)));
+            }
+            self.u8(c as u8);
+        }
+        Ok(())
+    }
+
+    fn string(&mut self, x: &str) -> Result<(), Error> {
+        if x.len() > u16::MAX as usize {
+            return Err(invalid_input(format!("'{}' is too long", x)));
+        }
+        self.u16(x.len() as u16);
+        self.dst.put(x.as_bytes());
+        Ok(())
+    }
+
+    fn kind(&mut self, kind: &MessageKind) -> Result<(), Error> {
+        use MessageKind::*;
+
+        match kind {
+            LimitOrderSubmitRequest {
+                side,
+                price,
+                quantity,
+                symbol,
+                time_in_force,
+                display_quantity,
+                post_only,
+                self_trade_prevention,
+            } => {
+                self.side(*side);
+                self.decimal(*price);
+                self.decimal(*quantity);
+                self.symbol(symbol)?;
+                self.time_in_force(*time_in_force);
+                self.optional_decimal(*display_quantity);
+                self.bool(*post_only);
+                self.self_trade_prevention(*self_trade_prevention);
+            }
+            LimitOrderSubmitRequestAcknowledge {
+                id,
+                side,
+                price,
+                quantity,
+                symbol,
+            } => {
+                self.id(*id);
+                self.side(*side);
+                self.decimal(*price);
+                self.decimal(*quantity);
+                self.symbol(symbol)?;
+            }
+            LimitOrderSubmitReject {
+                side,
+                price,
+                quantity,
+                symbol,
+                reason,
+            } => {
+                self.side(*side);
+                self.decimal(*price);
+                self.decimal(*quantity);
+                self.symbol(symbol)?;
+                self.string(reason)?;
+            }
+            MarketOrderSubmitRequest {
+                side,
+                quantity,
+                symbol,
+            } => {
+                self.side(*side);
+                self.decimal(*quantity);
+                self.symbol(symbol)?;
+            }
+            MarketOrderSubmitRequestAcknowledge {
+                id,
+                side,
+                quantity,
+                symbol,
+            } => {
+                self.id(*id);
+                self.side(*side);
+                self.decimal(*quantity);
+                self.symbol(symbol)?;
+            }
+            MarketOrderSubmitReject {
+                side,
+                quantity,
+                symbol,
+                reason,
+            } => {
+                self.side(*side);
+                self.decimal(*quantity);
+                self.symbol(symbol)?;
+                self.string(reason)?;
+            }
+            StopOrderSubmitRequest {
+                side,
+                stop_price,
+                limit_price,
+                quantity,
+                symbol,
+            } => {
+                self.side(*side);
+                self.decimal(*stop_price);
+                self.optional_decimal(*limit_price);
+                self.decimal(*quantity);
+                self.symbol(symbol)?;
+            }
+            StopOrderSubmitReject {
+                side,
+                stop_price,
+                limit_price,
+                quantity,
+                symbol,
+                reason,
+            } => {
+                self.side(*side);
+                self.decimal(*stop_price);
+                self.optional_decimal(*limit_price);
+                self.decimal(*quantity);
+                self.symbol(symbol)?;
+                self.string(reason)?;
+            }
+            StopOrderSubmitRequestAcknowledge {
+                id,
+                side,
+                stop_price,
+                limit_price,
+                quantity,
+                symbol,
+            }
+            | StopOrderTriggered {
+                id,
+                side,
+                stop_price,
+                limit_price,
+                quantity,
+                symbol,
+            } => {
+                self.id(*id);
+                self.side(*side);
+                self.decimal(*stop_price);
+                self.optional_decimal(*limit_price);
+                self.decimal(*quantity);
+                self.symbol(symbol)?;
+            }
+            OrderCanceled {
+                id,
+                side,
+                quantity,
+                canceled_quantity: other_quantity,
+                symbol,
+            }
+            | OrderRestated {
+                id,
+                side,
+                quantity,
+                leaves_quantity: other_quantity,
+                symbol,
+            }
+            | OrderExpired {
+                id,
+                side,
+                quantity,
+                expired_quantity: other_quantity,
+                symbol,
+            } => {
+                self.id(*id);
+                self.side(*side);
+                self.decimal(*quantity);
+                self.decimal(*other_quantity);
+                self.symbol(symbol)?;
+            }
+            Execution {
+                id,
+                price,
+                quantity,
+                symbol,
+                side,
+            } => {
+                self.id(*id);
+                self.decimal(*price);
+                self.decimal(*quantity);
+                self.symbol(symbol)?;
+                self.side(*side);
+            }
+            CancelRequest { id, symbol } => {
+                self.id(*id);
+                self.symbol(symbol)?;
+            }
+            CancelAcknowledge {
+                id,
+                side,
+                price,
+                quantity,
+                canceled_quantity: other_quantity,
+                symbol,
+            }
+            | ReplaceAcknowledge {
+                id,
+                side,
+                price,
+                quantity,
+                leaves_quantity: other_quantity,
+                symbol,
+            } => {
+                self.id(*id);
+                self.side(*side);
+                self.decimal(*price);
+                self.decimal(*quantity);
+                self.decimal(*other_quantity);
+                self.symbol(symbol)?;
+            }
+            CancelReject { id, reason } | ReplaceReject { id, reason } => {
+                self.id(*id);
+                self.string(reason)?;
+            }
+            ReplaceRequest {
+                id,
+                price,
+                quantity,
+                symbol,
+            } => {
+                self.id(*id);
+                self.decimal(*price);
+                self.decimal(*quantity);
+                self.symbol(symbol)?;
+            }
+            RetransmitRangeRequest {
+                last_topic_sequence_n,
+            } => self.u64(*last_topic_sequence_n),
+            RetransmitRequest | GapFill => {}
+        }
+
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() < n {
+            return Err(invalid_data("message ended early".to_string()));
+        }
+        let (x, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(x)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(
+            self.take(2)?.try_into().expect("slice is 2 bytes"),
+        ))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(
+            self.take(8)?.try_into().expect("slice is 8 bytes"),
+        ))
+    }
+
+    fn bool(&mut self) -> Result<bool, Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            x => Err(invalid_data(format!("{} isn't a bool", x))),
+        }
+    }
+
+    fn id(&mut self) -> Result<OrderId, Error> {
+        Ok(self.u64()? as OrderId)
+    }
+
+    fn decimal(&mut self) -> Result<Decimal, Error> {
+        Ok(Decimal::from_units(i64::from_le_bytes(
+            self.take(8)?.try_into().expect("slice is 8 bytes"),
+        )))
+    }
+
+    fn optional_decimal(&mut self) -> Result<Option<Decimal>, Error> {
+        let is_some = self.bool()?;
+        let x = self.decimal()?;
+        Ok(if is_some { Some(x) } else { None })
+    }
+
+    fn side(&mut self) -> Result<Side, Error> {
+        match self.u8()? {
+            0 => Ok(Side::Bid),
+            1 => Ok(Side::Ask),
+            x => Err(invalid_data(format!("unknown side {}", x))),
+        }
+    }
+
+    fn time_in_force(&mut self) -> Result<TimeInForce, Error> {
+        match self.u8()? {
+            0 => Ok(TimeInForce::Day),
+            1 => Ok(TimeInForce::GoodTillCancel),
+            2 => Ok(TimeInForce::ImmediateOrCancel),
+            3 => Ok(TimeInForce::FillOrKill),
+            x => Err(invalid_data(format!("unknown time in force {}", x))),
+        }
+    }
+
+    fn self_trade_prevention(&mut self) -> Result<Option<SelfTradePrevention>, Error> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(SelfTradePrevention::CancelNewest)),
+            2 => Ok(Some(SelfTradePrevention::CancelOldest)),
+            3 => Ok(Some(SelfTradePrevention::CancelBoth)),
+            4 => Ok(Some(SelfTradePrevention::Decrement)),
+            x => Err(invalid_data(format!("unknown self trade prevention {}", x))),
+        }
+    }
+
+    fn symbol(&mut self) -> Result<SymbolOwned, Error> {
+        let x = self.take(4)?;
+        Ok([x[0] as char, x[1] as char, x[2] as char, x[3] as char])
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let length = self.u16()? as usize;
+        std::str::from_utf8(self.take(length)?)
+            .map(str::to_string)
+            .map_err(|e| invalid_data(e.to_string()))
+    }
+
+    fn kind(&mut self, number: u8) -> Result<MessageKind, Error> {
+        use MessageKind::*;
+
+        Ok(match number {
+            LIMIT_ORDER_SUBMIT_REQUEST => LimitOrderSubmitRequest {
+                side: self.side()?,
+                price: self.decimal()?,
+                quantity: self.decimal()?,
+                symbol: self.symbol()?,
+                time_in_force: self.time_in_force()?,
+                display_quantity: self.optional_decimal()?,
+                post_only: self.bool()?,
+                self_trade_prevention: self.self_trade_prevention()?,
+            },
+            LIMIT_ORDER_SUBMIT_REQUEST_ACKNOWLEDGE => LimitOrderSubmitRequestAcknowledge {
+                id: self.id()?,
+                side: self.side()?,
+                price: self.decimal()?,
+                quantity: self.decimal()?,
+                symbol: self.symbol()?,
+            },
+            LIMIT_ORDER_SUBMIT_REJECT => LimitOrderSubmitReject {
+                side: self.side()?,
+                price: self.decimal()?,
+                quantity: self.decimal()?,
+                symbol: self.symbol()?,
+                reason: self.string()?,
+            },
+            MARKET_ORDER_SUBMIT_REQUEST => MarketOrderSubmitRequest {
+                side: self.side()?,
+                quantity: self.decimal()?,
+                symbol: self.symbol()?,
+            },
+            MARKET_ORDER_SUBMIT_REQUEST_ACKNOWLEDGE => MarketOrderSubmitRequestAcknowledge {
+                id: self.id()?,
+                side: self.side()?,
+                quantity: self.decimal()?,
+                symbol: self.symbol()?,
+            },
+            MARKET_ORDER_SUBMIT_REJECT => MarketOrderSubmitReject {
+                side: self.side()?,
+                quantity: self.decimal()?,
+                symbol: self.symbol()?,
+                reason: self.string()?,
+            },
+            STOP_ORDER_SUBMIT_REQUEST => StopOrderSubmitRequest {
+                side: self.side()?,
+                stop_price: self.decimal()?,
+                limit_price: self.optional_decimal()?,
+                quantity: self.decimal()?,
+                symbol: self.symbol()?,
+            },
+            STOP_ORDER_SUBMIT_REQUEST_ACKNOWLEDGE => StopOrderSubmitRequestAcknowledge {
+                id: self.id()?,
+                side: self.side()?,
+                stop_price: self.decimal()?,
+                limit_price: self.optional_decimal()?,
+                quantity: self.decimal()?,
+                symbol: self.symbol()?,
+            },
+            STOP_ORDER_SUBMIT_REJECT => StopOrderSubmitReject {
+                side: self.side()?,
+                stop_price: self.decimal()?,
+                limit_price: self.optional_decimal()?,
+                quantity: self.decimal()?,
+                symbol: self.symbol()?,
+                reason: self.string()?,
+            },
+            STOP_ORDER_TRIGGERED => StopOrderTriggered {
+                id: self.id()?,
+                side: self.side()?,
+                stop_price: self.decimal()?,
+                limit_price: self.optional_decimal()?,
+                quantity: self.decimal()?,
+                symbol: self.symbol()?,
+            },
+            ORDER_CANCELED => OrderCanceled {
+                id: self.id()?,
+                side: self.side()?,
+                quantity: self.decimal()?,
+                canceled_quantity: self.decimal()?,
+                symbol: self.symbol()?,
+            },
+            ORDER_RESTATED => OrderRestated {
+                id: self.id()?,
+                side: self.side()?,
+                quantity: self.decimal()?,
+                leaves_quantity: self.decimal()?,
+                symbol: self.symbol()?,
+            },
+            ORDER_EXPIRED => OrderExpired {
+                id: self.id()?,
+                side: self.side()?,
+                quantity: self.decimal()?,
+                expired_quantity: self.decimal()?,
+                symbol: self.symbol()?,
+            },
+            EXECUTION => Execution {
+                id: self.id()?,
+                price: self.decimal()?,
+                quantity: self.decimal()?,
+                symbol: self.symbol()?,
+                side: self.side()?,
+            },
+            CANCEL_REQUEST => CancelRequest {
+                id: self.id()?,
+                symbol: self.symbol()?,
+            },
+            CANCEL_ACKNOWLEDGE => CancelAcknowledge {
+                id: self.id()?,
+                side: self.side()?,
+                price: self.decimal()?,
+                quantity: self.decimal()?,
+                canceled_quantity: self.decimal()?,
+                symbol: self.symbol()?,
+            },
+            CANCEL_REJECT => CancelReject {
+                id: self.id()?,
+                reason: self.string()?,
+            },
+            REPLACE_REQUEST => ReplaceRequest {
+                id: self.id()?,
+                price: self.decimal()?,
+                quantity: self.decimal()?,
+                symbol: self.symbol()?,
+            },
+            REPLACE_ACKNOWLEDGE => ReplaceAcknowledge {
+                id: self.id()?,
+                side: self.side()?,
+                price: self.decimal()?,
+                quantity: self.decimal()?,
+                leaves_quantity: self.decimal()?,
+                symbol: self.symbol()?,
+            },
+            REPLACE_REJECT => ReplaceReject {
+                id: self.id()?,
+                reason: self.string()?,
+            },
+            RETRANSMIT_REQUEST => RetransmitRequest,
+            RETRANSMIT_RANGE_REQUEST => RetransmitRangeRequest {
+                last_topic_sequence_n: self.u64()?,
+            },
+            GAP_FILL => GapFill,
+            x => return Err(invalid_data(format!("unknown message kind {}", x))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use MessageKind::*;
+
+    const SYMBOL: SymbolOwned = ['A', 'D', 'B', 'E'];
+
+    fn message(kind: MessageKind) -> Message {
+        Message {
+            id: MessageId {
+                publisher_id: ServiceId {
+                    kind: ServiceKind::MatchingEngine,
+                    number: 3,
+                },
+                topic_id: "participant".to_string(),
+                topic_sequence_n: 42,
+            },
+            kind,
+            replay: false,
+        }
+    }
+
+    /// One of every kind of message
+    fn kinds() -> Vec<MessageKind> {
+        let price: Price = "12.75".parse().unwrap();
+        let quantity = Quantity::from(100);
+        vec![
+            LimitOrderSubmitRequest {
+                side: Side::Bid,
+                price,
+                quantity,
+                symbol: SYMBOL,
+                time_in_force: TimeInForce::FillOrKill,
+                display_quantity: Some(Quantity::from(10)),
+                post_only: true,
+                self_trade_prevention: Some(SelfTradePrevention::Decrement),
+            },
+            LimitOrderSubmitRequestAcknowledge {
+                id: 1,
+                side: Side::Ask,
+                price,
+                quantity,
+                symbol: SYMBOL,
+            },
+            LimitOrderSubmitReject {
+                side: Side::Ask,
+                price,
+                quantity,
+                symbol: SYMBOL,
+                reason: "price isn't a multiple of the tick size".to_string(),
+            },
+            MarketOrderSubmitRequest {
+                side: Side::Bid,
+                quantity,
+                symbol: SYMBOL,
+            },
+            MarketOrderSubmitRequestAcknowledge {
+                id: 2,
+                side: Side::Bid,
+                quantity,
+                symbol: SYMBOL,
+            },
+            MarketOrderSubmitReject {
+                side: Side::Bid,
+                quantity,
+                symbol: SYMBOL,
+                reason: "symbol doesn't exist".to_string(),
+            },
+            StopOrderSubmitRequest {
+                side: Side::Ask,
+                stop_price: price,
+                limit_price: None,
+                quantity,
+                symbol: SYMBOL,
+            },
+            StopOrderSubmitRequestAcknowledge {
+                id: 3,
+                side: Side::Ask,
+                stop_price: price,
+                limit_price: Some(price),
+                quantity,
+                symbol: SYMBOL,
+            },
+            StopOrderSubmitReject {
+                side: Side::Bid,
+                stop_price: price,
+                limit_price: Some(price),
+                quantity,
+                symbol: SYMBOL,
+                reason: "stop price isn't a multiple of the tick size".to_string(),
+            },
+            StopOrderTriggered {
+                id: 3,
+                side: Side::Ask,
+                stop_price: price,
+                limit_price: None,
+                quantity,
+                symbol: SYMBOL,
+            },
+            OrderCanceled {
+                id: 4,
+                side: Side::Bid,
+                quantity,
+                canceled_quantity: Quantity::from(5),
+                symbol: SYMBOL,
+            },
+            OrderRestated {
+                id: 5,
+                side: Side::Bid,
+                quantity,
+                leaves_quantity: Quantity::from(6),
+                symbol: SYMBOL,
+            },
+            OrderExpired {
+                id: 6,
+                side: Side::Ask,
+                quantity,
+                expired_quantity: Quantity::from(7),
+                symbol: SYMBOL,
+            },
+            Execution {
+                id: 7,
+                price,
+                quantity,
+                symbol: SYMBOL,
+                side: Side::Ask,
+            },
+            CancelRequest {
+                id: 8,
+                symbol: SYMBOL,
+            },
+            CancelAcknowledge {
+                id: 8,
+                side: Side::Bid,
+                price,
+                quantity,
+                canceled_quantity: Quantity::from(8),
+                symbol: SYMBOL,
+            },
+            CancelReject {
+                id: 9,
+                reason: "unknown order".to_string(),
+            },
+            ReplaceRequest {
+                id: 10,
+                price,
+                quantity,
+                symbol: SYMBOL,
+            },
+            ReplaceAcknowledge {
+                id: 10,
+                side: Side::Ask,
+                price,
+                quantity,
+                leaves_quantity: Quantity::from(9),
+                symbol: SYMBOL,
+            },
+            ReplaceReject {
+                id: 11,
+                reason: "ünknown order".to_string(),
+            },
+            RetransmitRequest,
+            RetransmitRangeRequest {
+                last_topic_sequence_n: u64::MAX,
+            },
+            GapFill,
+        ]
+    }
+
+    #[test]
+    fn every_kind_of_message_round_trips() {
+        let mut codec = BinaryMessageCodec::new();
+        let mut buf = BytesMut::new();
+        for kind in kinds() {
+            codec.encode(message(kind), &mut buf).unwrap();
+        }
+
+        for kind in kinds() {
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(message(kind)));
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn views_read_the_header_in_place() {
+        let mut buf = BytesMut::new();
+        BinaryMessageCodec::new()
+            .encode(
+                message(CancelRequest {
+                    id: 8,
+                    symbol: SYMBOL,
+                }),
+                &mut buf,
+            )
+            .unwrap();
+
+        let view = MessageView::new(&buf).unwrap();
+        assert_eq!(view.len(), buf.len());
+        assert_eq!(view.topic_id(), "participant");
+        assert_eq!(view.topic_sequence_n(), 42);
+        assert!(!view.is_replay());
+        assert_eq!(
+            view.kind().unwrap(),
+            CancelRequest {
+                id: 8,
+                symbol: SYMBOL
+            }
+        );
+    }
+
+    #[test]
+    fn replays_keep_their_flag() {
+        let mut codec = BinaryMessageCodec::new();
+        let mut buf = BytesMut::new();
+        let replay = Message {
+            replay: true,
+            ..message(CancelRequest {
+                id: 8,
+                symbol: SYMBOL,
+            })
+        };
+        codec.encode(replay.clone(), &mut buf).unwrap();
+
+        assert!(MessageView::new(&buf).unwrap().is_replay());
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(replay));
+    }
+
+    #[test]
+    fn partial_messages_wait_for_the_rest() {
+        let mut codec = BinaryMessageCodec::new();
+        let mut encoded = BytesMut::new();
+        codec
+            .encode(message(kinds().remove(0)), &mut encoded)
+            .unwrap();
+
+        let mut src = BytesMut::new();
+        for &x in &encoded[..encoded.len() - 1] {
+            src.put_u8(x);
+            assert!(codec.decode(&mut src).unwrap().is_none());
+        }
+        src.put_u8(encoded[encoded.len() - 1]);
+        assert!(codec.decode(&mut src).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_other_schema_versions() {
+        let mut buf = BytesMut::new();
+        BinaryMessageCodec::new()
+            .encode(message(RetransmitRequest), &mut buf)
+            .unwrap();
+        buf[0] = SCHEMA_VERSION + 1;
+
+        assert!(MessageView::new(&buf).is_err());
+        assert!(BinaryMessageCodec::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn messages_too_big_for_a_datagram_arent_encoded() {
+        let mut buf = BytesMut::new();
+        let kind = CancelReject {
+            id: 0,
+            reason: "x".repeat(MAX_MESSAGE_LENGTH),
+        };
+
+        assert!(BinaryMessageCodec::new()
+            .encode(message(kind), &mut buf)
+            .is_err());
+        assert!(buf.is_empty());
+    }
+}
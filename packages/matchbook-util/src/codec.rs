@@ -1,17 +1,96 @@
 use bytes::{BufMut, BytesMut};
 use fixer_upper::Message as FixMessage;
 use matchbook_types::*;
+use serde::{de::DeserializeOwned, Serialize};
 use tokio_util::codec::{Decoder, Encoder};
 
+/// Messages are framed as one line of JSON each. serde_json escapes newlines inside strings, so a message never
+/// contains the delimiter
+const DELIMITER: u8 = b'\n';
+/// Lines longer than this are rejected instead of being buffered
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// How far a decoder has got through the line it's waiting on the end of
+#[derive(Debug, Clone, Copy, Default)]
+struct LineState {
+    /// How much of the buffer has already been searched for the delimiter
+    scanned: usize,
+    /// Throwing away the rest of a line that was too long
+    discarding: bool,
+}
+
+fn line_too_long() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("line is longer than the maximum of {}", MAX_LINE_LENGTH),
+    )
+}
+
+fn encode_line<T: Serialize>(
+    buf: &mut Vec<u8>,
+    item: &T,
+    dst: &mut BytesMut,
+) -> Result<(), std::io::Error> {
+    serde_json::to_writer(&mut *buf, item)?;
+    buf.push(DELIMITER);
+    dst.put(&buf[..]);
+    buf.clear();
+    Ok(())
+}
+
+fn decode_line<T: DeserializeOwned>(
+    state: &mut LineState,
+    src: &mut BytesMut,
+) -> Result<Option<T>, std::io::Error> {
+    loop {
+        let end = match src[state.scanned..].iter().position(|&x| x == DELIMITER) {
+            Some(x) => state.scanned + x,
+            None => {
+                if state.discarding || src.len() > MAX_LINE_LENGTH {
+                    src.clear();
+                    state.scanned = 0;
+                    if !std::mem::replace(&mut state.discarding, true) {
+                        return Err(line_too_long());
+                    }
+                } else {
+                    state.scanned = src.len();
+                }
+                return Ok(None);
+            }
+        };
+        let line = src.split_to(end + 1);
+        state.scanned = 0;
+
+        // the end of a line that was too long, which has already been rejected
+        if std::mem::replace(&mut state.discarding, false) {
+            continue;
+        }
+        if end > MAX_LINE_LENGTH {
+            return Err(line_too_long());
+        }
+
+        // blank lines, e.g. from someone typing messages in by hand, aren't messages
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+
+        return serde_json::from_slice(&line[..end])
+            .map(Some)
+            .map_err(|e| e.into());
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct MatchbookMessageCodec {
     buf: Vec<u8>,
+    line: LineState,
 }
 
 impl MatchbookMessageCodec {
     pub fn new() -> Self {
         Self {
             buf: Vec::with_capacity(1024),
+            line: LineState::default(),
         }
     }
 }
@@ -24,10 +103,7 @@ impl Encoder<Message> for MatchbookMessageCodec {
         item: Message,
         dst: &mut BytesMut,
     ) -> Result<(), <Self as Encoder<Message>>::Error> {
-        serde_json::to_writer(&mut self.buf, &item)?;
-        dst.put(&self.buf[..self.buf.len()]);
-        self.buf.clear();
-        Ok(())
+        encode_line(&mut self.buf, &item, dst)
     }
 }
 
@@ -37,25 +113,21 @@ impl Decoder for MatchbookMessageCodec {
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.is_empty() {
-            Ok(None)
-        } else {
-            let maybe_message = serde_json::from_slice(src).map(Some).map_err(|e| e.into());
-            src.clear();
-            maybe_message
-        }
+        decode_line(&mut self.line, src)
     }
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct FixJsonCodec {
     buf: Vec<u8>,
+    line: LineState,
 }
 
 impl FixJsonCodec {
     pub fn new() -> Self {
         Self {
             buf: Vec::with_capacity(1024),
+            line: LineState::default(),
         }
     }
 }
@@ -68,10 +140,7 @@ impl Encoder<FixMessage> for FixJsonCodec {
         item: FixMessage,
         dst: &mut BytesMut,
     ) -> Result<(), <Self as Encoder<FixMessage>>::Error> {
-        serde_json::to_writer(&mut self.buf, &item)?;
-        dst.put(&self.buf[..self.buf.len()]);
-        self.buf.clear();
-        Ok(())
+        encode_line(&mut self.buf, &item, dst)
     }
 }
 
@@ -81,12 +150,145 @@ impl Decoder for FixJsonCodec {
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.is_empty() {
-            Ok(None)
-        } else {
-            let maybe_message = serde_json::from_slice(src).map(Some).map_err(|e| e.into());
-            src.clear();
-            maybe_message
+        decode_line(&mut self.line, src)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matchbook_message(topic_sequence_n: u64) -> Message {
+        Message {
+            id: MessageId {
+                publisher_id: ServiceId {
+                    kind: ServiceKind::Port,
+                    number: 0,
+                },
+                topic_id: "participant\nwith a newline".to_string(),
+                topic_sequence_n,
+            },
+            kind: MessageKind::CancelRequest {
+                id: 0,
+                symbol: ['A', 'D', 'B', 'E'],
+            },
+            replay: false,
+        }
+    }
+
+    fn fix_message(msg_seq_num: u64) -> FixMessage {
+        FixMessage {
+            header: fixer_upper::Header {
+                begin_string: fixer_upper::BeginString::Fix_4_4,
+                body_length: None,
+                msg_type: fixer_upper::MessageType::Logon,
+                sender_comp_id: "seller".to_string(),
+                target_comp_id: "matchbook".to_string(),
+                msg_seq_num,
+                poss_dup_flag: None,
+                sending_time: chrono::Utc::now(),
+                orig_sending_time: None,
+            },
+            body: fixer_upper::Body::default(),
+            trailer: fixer_upper::Trailer::default(),
+        }
+    }
+
+    /// Encode `items` back to back, then decode them after feeding the encoded bytes in `chunk_size`d pieces
+    fn feed<C, T>(codec: &mut C, items: Vec<T>, chunk_size: usize) -> Vec<C::Item>
+    where
+        C: Encoder<T, Error = std::io::Error> + Decoder<Error = std::io::Error>,
+    {
+        let mut encoded = BytesMut::new();
+        for item in items {
+            codec.encode(item, &mut encoded).unwrap();
+        }
+
+        let mut src = BytesMut::new();
+        let mut decoded = vec![];
+        for chunk in encoded.chunks(chunk_size) {
+            src.put(chunk);
+            while let Some(item) = codec.decode(&mut src).unwrap() {
+                decoded.push(item);
+            }
+        }
+        assert!(src.is_empty());
+        decoded
+    }
+
+    #[test]
+    fn matchbook_messages_decode_byte_by_byte_and_batched() {
+        let messages: Vec<_> = (0..3).map(matchbook_message).collect();
+        for &chunk_size in &[1, 7, usize::MAX] {
+            let decoded = feed(
+                &mut MatchbookMessageCodec::new(),
+                messages.clone(),
+                chunk_size,
+            );
+            assert_eq!(decoded, messages);
+        }
+    }
+
+    #[test]
+    fn fix_messages_decode_byte_by_byte_and_batched() {
+        let messages: Vec<_> = (1..4).map(fix_message).collect();
+        for &chunk_size in &[1, 7, usize::MAX] {
+            let decoded = feed(&mut FixJsonCodec::new(), messages.clone(), chunk_size);
+            assert_eq!(
+                decoded
+                    .iter()
+                    .map(|x| x.header.msg_seq_num)
+                    .collect::<Vec<_>>(),
+                vec![1, 2, 3]
+            );
+        }
+    }
+
+    #[test]
+    fn blank_lines_are_skipped_and_bad_lines_dont_affect_the_next_one() {
+        let mut codec = FixJsonCodec::new();
+        let mut src = BytesMut::new();
+        src.put(&b"\r\n{\"not\": \"a message\"}\n"[..]);
+        codec.encode(fix_message(1), &mut src).unwrap();
+
+        assert!(codec.decode(&mut src).is_err());
+        assert_eq!(
+            codec.decode(&mut src).unwrap().unwrap().header.msg_seq_num,
+            1
+        );
+    }
+
+    #[test]
+    fn lines_that_are_too_long_are_rejected_without_being_buffered() {
+        let mut codec = FixJsonCodec::new();
+        let mut src = BytesMut::new();
+
+        // all at once
+        src.put(&vec![b'x'; MAX_LINE_LENGTH + 1][..]);
+        src.put(&b"\n"[..]);
+        codec.encode(fix_message(1), &mut src).unwrap();
+        assert!(codec.decode(&mut src).is_err());
+        assert_eq!(
+            codec.decode(&mut src).unwrap().unwrap().header.msg_seq_num,
+            1
+        );
+
+        // a piece at a time, with the rest of the line thrown away as it arrives
+        let mut errors = 0;
+        for _ in 0..20 {
+            src.put(&vec![b'x'; 4096][..]);
+            match codec.decode(&mut src) {
+                Ok(x) => assert!(x.is_none()),
+                Err(_) => errors += 1,
+            }
+            assert!(src.len() <= MAX_LINE_LENGTH);
         }
+        assert_eq!(errors, 1);
+        src.put(&b"xx\n"[..]);
+        codec.encode(fix_message(2), &mut src).unwrap();
+        assert_eq!(
+            codec.decode(&mut src).unwrap().unwrap().header.msg_seq_num,
+            2
+        );
     }
 }
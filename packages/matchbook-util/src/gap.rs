@@ -0,0 +1,502 @@
+use futures::channel::mpsc::UnboundedSender;
+use futures::Stream;
+use matchbook_types::*;
+use std::collections::{hash_map::Entry, BTreeMap, HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{interval, Instant, Interval};
+use tracing::*;
+
+/// How long to wait for a retransmission before asking for it again
+pub const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// How many messages a topic buffers behind a gap before giving up on it
+pub const DEFAULT_MAX_PENDING: usize = 1024;
+
+/// Where a single topic is up to
+#[derive(Debug)]
+struct Topic {
+    /// Sequence number of the next message to deliver
+    next: u64,
+    /// Messages that arrived ahead of a gap, waiting for it to be filled
+    pending: BTreeMap<u64, Message>,
+    /// When the messages missing from the gap, or anything after the last message, were last asked for
+    requested_at: Option<Instant>,
+    /// When a message last arrived on the topic
+    received_at: Instant,
+}
+
+impl Topic {
+    /// Ask for each run of missing messages between the ones that are waiting, or for anything after the last message
+    /// delivered if none are
+    fn request(
+        &mut self,
+        (publisher_id, topic_id): &(ServiceId, String),
+        retransmit_tx: &UnboundedSender<Message>,
+    ) {
+        self.requested_at = Some(Instant::now());
+
+        let send = |first, last| {
+            let request = Message {
+                id: MessageId {
+                    publisher_id: *publisher_id,
+                    topic_id: topic_id.clone(),
+                    topic_sequence_n: first,
+                },
+                kind: MessageKind::RetransmitRangeRequest {
+                    last_topic_sequence_n: last,
+                },
+                replay: false,
+            };
+            debug!(?request.id, last, "requesting retransmission");
+
+            // nobody is publishing our requests any more, there's nothing useful to do about it here
+            let _ = retransmit_tx.unbounded_send(request);
+        };
+
+        if self.pending.is_empty() {
+            // the last message might have been lost without anything after it to show for it
+            send(self.next, u64::MAX);
+            return;
+        }
+
+        let mut from = self.next;
+        for &n in self.pending.keys() {
+            if n > from {
+                send(from, n - 1);
+            }
+            from = n + 1;
+        }
+    }
+}
+
+/// Puts the messages of a backbone stream back in order. Every topic is delivered strictly in `topic_sequence_n` order
+/// without duplicates. Messages that arrive ahead of a gap are held back and a `RetransmitRangeRequest` for each run of
+/// missing messages is sent down `retransmit_tx`, for the caller to publish. A `GapFill` counts as the message it
+/// stands in for but isn't delivered.
+///
+/// A topic starts at the first original message seen on it, anything published before then is never asked for and
+/// replays of it are dropped, so a late joiner doesn't act on old messages someone else asked for. If a gap isn't
+/// filled before `max_pending` messages pile up behind it, the missing messages are given up on so the topic doesn't
+/// stall forever.
+///
+/// Gaps are asked for again every `retry_after` until they're filled, even if nothing else arrives. Once a topic has
+/// been quiet for `retry_after` anything after its last message is asked for once, in case that message was lost.
+/// Timers need a tokio runtime.
+#[derive(Debug)]
+pub struct GapDetector<St> {
+    stream: St,
+    topics: HashMap<(ServiceId, String), Topic>,
+    /// Messages ready to be delivered, in order
+    ready: VecDeque<Message>,
+    retransmit_tx: UnboundedSender<Message>,
+    retry_after: Duration,
+    max_pending: usize,
+    /// Started on the first poll, so `retry_after` can still be changed
+    retry: Option<Interval>,
+}
+
+impl<St> GapDetector<St> {
+    pub fn new(stream: St, retransmit_tx: UnboundedSender<Message>) -> Self {
+        Self {
+            stream,
+            topics: HashMap::new(),
+            ready: VecDeque::new(),
+            retransmit_tx,
+            retry_after: DEFAULT_RETRY_AFTER,
+            max_pending: DEFAULT_MAX_PENDING,
+            retry: None,
+        }
+    }
+
+    /// Ask for missing messages again if they haven't turned up after `retry_after`, and for anything after the last
+    /// message once a topic has been quiet that long
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// Give up on a gap once `max_pending` messages are waiting behind it
+    pub fn max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending;
+        self
+    }
+
+    fn receive(&mut self, message: Message) {
+        // retransmit requests carry the id of the message they're asking for, they aren't part of any sequence
+        if message.kind.is_retransmit_request() {
+            self.ready.push_back(message);
+            return;
+        }
+
+        let (retry_after, max_pending) = (self.retry_after, self.max_pending);
+        let n = message.id.topic_sequence_n;
+        let key = (message.id.publisher_id, message.id.topic_id.clone());
+        let topic = match self.topics.entry(key.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(_) if message.replay => {
+                trace!(?message.id, "dropping replay on a topic we haven't started");
+                return;
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Topic {
+                    next: n + 1,
+                    pending: BTreeMap::new(),
+                    requested_at: None,
+                    received_at: Instant::now(),
+                });
+                if !matches!(message.kind, MessageKind::GapFill) {
+                    self.ready.push_back(message);
+                }
+                return;
+            }
+        };
+
+        topic.received_at = Instant::now();
+        if n < topic.next || topic.pending.contains_key(&n) {
+            trace!(?message.id, "dropping duplicate message");
+            return;
+        }
+        if topic.pending.is_empty() {
+            // whatever was asked for after the last message has turned up, a new gap is asked for straight away
+            topic.requested_at = None;
+        }
+        topic.pending.insert(n, message);
+
+        loop {
+            while let Some(message) = topic.pending.remove(&topic.next) {
+                topic.next += 1;
+                if !matches!(message.kind, MessageKind::GapFill) {
+                    self.ready.push_back(message);
+                }
+            }
+
+            if topic.pending.is_empty() {
+                topic.requested_at = None;
+                return;
+            }
+
+            if topic.pending.len() <= max_pending {
+                break;
+            }
+
+            // the first waiting message is always past the gap, otherwise it would have been delivered
+            let (&first, message) = topic.pending.iter().next().unwrap();
+            warn!(
+                ?message.id,
+                missing = first - topic.next,
+                "giving up on missing messages"
+            );
+            topic.next = first;
+            topic.requested_at = None;
+        }
+
+        if let Some(requested_at) = topic.requested_at {
+            if requested_at.elapsed() < retry_after {
+                return;
+            }
+        }
+        topic.request(&key, &self.retransmit_tx);
+    }
+
+    /// Ask again for gaps that are still open, and for anything after the last message on topics that have gone quiet
+    fn retry(&mut self) {
+        for (key, topic) in &mut self.topics {
+            let due = match topic.requested_at {
+                Some(requested_at) if !topic.pending.is_empty() => {
+                    requested_at.elapsed() >= self.retry_after
+                }
+                // only once per quiet spell, a topic that's finished isn't asked about forever
+                Some(_) => false,
+                None => topic.received_at.elapsed() >= self.retry_after,
+            };
+            if due {
+                topic.request(key, &self.retransmit_tx);
+            }
+        }
+    }
+}
+
+impl<St, E> Stream for GapDetector<St>
+where
+    St: Stream<Item = Result<Message, E>> + Unpin,
+{
+    type Item = Result<Message, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        // an interval can't tick every 0s
+        let period = this.retry_after.max(Duration::from_millis(1));
+        loop {
+            if let Some(message) = this.ready.pop_front() {
+                return Poll::Ready(Some(Ok(message)));
+            }
+
+            if this
+                .retry
+                .get_or_insert_with(|| interval(period))
+                .poll_tick(cx)
+                .is_ready()
+            {
+                this.retry();
+                continue;
+            }
+
+            match Pin::new(&mut this.stream).poll_next(cx) {
+                Poll::Ready(Some(Ok(message))) => this.receive(message),
+                x => return x,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+    use futures::{FutureExt, StreamExt};
+
+    const PORT: ServiceId = ServiceId {
+        kind: ServiceKind::Port,
+        number: 0,
+    };
+
+    fn message(topic_id: &str, topic_sequence_n: u64) -> Message {
+        Message {
+            id: MessageId {
+                publisher_id: PORT,
+                topic_id: topic_id.to_string(),
+                topic_sequence_n,
+            },
+            kind: MessageKind::CancelRequest {
+                id: topic_sequence_n as usize,
+                symbol: ['A', 'D', 'B', 'E'],
+            },
+            replay: false,
+        }
+    }
+
+    type Input = futures::stream::Iter<std::vec::IntoIter<Result<Message, ()>>>;
+
+    type Sender = UnboundedSender<Result<Message, ()>>;
+
+    /// First and last sequence number asked for
+    type Range = (u64, u64);
+
+    fn gap_fill(topic_id: &str, topic_sequence_n: u64) -> Message {
+        Message {
+            kind: MessageKind::GapFill,
+            ..message(topic_id, topic_sequence_n)
+        }
+    }
+
+    fn range(request: Message) -> Range {
+        match request.kind {
+            MessageKind::RetransmitRangeRequest {
+                last_topic_sequence_n,
+            } => (request.id.topic_sequence_n, last_topic_sequence_n),
+            kind => panic!("{:?} isn't a range request", kind),
+        }
+    }
+
+    /// Run `messages` through a detector, returning what it delivered and the ranges of sequence numbers it asked for
+    async fn detect(
+        messages: Vec<Message>,
+        configure: impl FnOnce(GapDetector<Input>) -> GapDetector<Input>,
+    ) -> (Vec<(String, u64)>, Vec<Range>) {
+        // the clock only moves when told to, so the timer never gets in the way
+        tokio::time::pause();
+        let (retransmit_tx, retransmit_rx): (_, UnboundedReceiver<Message>) = unbounded();
+        let stream = futures::stream::iter(messages.into_iter().map(Ok).collect::<Vec<_>>());
+        let detector = configure(GapDetector::new(stream, retransmit_tx));
+
+        let delivered = detector
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .map(|x| x.unwrap().id)
+            .map(|x| (x.topic_id, x.topic_sequence_n))
+            .collect();
+        let requested = retransmit_rx.map(range).collect().await;
+
+        (delivered, requested)
+    }
+
+    /// Run a detector on its own task with the clock paused, so time skips ahead whenever nothing else can happen.
+    /// Returns where to send it messages, what it delivers and what it asks for
+    fn spawn_detector() -> (
+        Sender,
+        UnboundedReceiver<(String, u64)>,
+        UnboundedReceiver<Message>,
+    ) {
+        tokio::time::pause();
+        let (tx, rx) = unbounded();
+        let (retransmit_tx, retransmit_rx) = unbounded();
+        let (delivered_tx, delivered_rx) = unbounded();
+        let mut detector = GapDetector::new(rx, retransmit_tx);
+        tokio::spawn(async move {
+            while let Some(Ok(message)) = detector.next().await {
+                let id = message.id;
+                let _ = delivered_tx.unbounded_send((id.topic_id, id.topic_sequence_n));
+            }
+        });
+        (tx, delivered_rx, retransmit_rx)
+    }
+
+    fn ids(topic_id: &str, ns: &[u64]) -> Vec<(String, u64)> {
+        ns.iter().map(|&n| (topic_id.to_string(), n)).collect()
+    }
+
+    #[tokio::test]
+    async fn delivers_in_order_without_duplicates() {
+        let messages = vec![
+            message("buyer", 1),
+            message("buyer", 2),
+            message("seller", 7),
+            message("buyer", 2),
+            message("seller", 8),
+            message("buyer", 1),
+            message("buyer", 3),
+        ];
+
+        let (delivered, requested) = detect(messages, |x| x).await;
+        let topic = |topic_id: &str| -> Vec<_> {
+            delivered
+                .iter()
+                .filter(|x| x.0 == topic_id)
+                .cloned()
+                .collect()
+        };
+        assert_eq!(topic("buyer"), ids("buyer", &[1, 2, 3]));
+        assert_eq!(topic("seller"), ids("seller", &[7, 8]));
+        assert!(requested.is_empty());
+    }
+
+    #[tokio::test]
+    async fn holds_messages_back_until_the_gap_is_filled() {
+        let messages = vec![
+            message("buyer", 1),
+            message("buyer", 4),
+            message("buyer", 6),
+            message("buyer", 3),
+            message("buyer", 2),
+            message("buyer", 5),
+        ];
+
+        let (delivered, requested) = detect(messages, |x| x).await;
+        assert_eq!(delivered, ids("buyer", &[1, 2, 3, 4, 5, 6]));
+        // asked once when the gap opened, the rest arrived before it was time to ask again
+        assert_eq!(requested, vec![(2, 3)]);
+    }
+
+    #[tokio::test]
+    async fn asks_again_for_messages_that_dont_turn_up() {
+        let messages = vec![
+            message("buyer", 1),
+            message("buyer", 3),
+            message("buyer", 4),
+            message("buyer", 2),
+        ];
+
+        let (delivered, requested) =
+            detect(messages, |x| x.retry_after(Duration::from_secs(0))).await;
+        assert_eq!(delivered, ids("buyer", &[1, 2, 3, 4]));
+        assert_eq!(requested, vec![(2, 2), (2, 2)]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_gap_once_too_many_messages_are_waiting() {
+        let messages = vec![
+            message("buyer", 1),
+            message("buyer", 3),
+            message("buyer", 4),
+            message("buyer", 5),
+            message("buyer", 2),
+            message("buyer", 6),
+        ];
+
+        let (delivered, requested) = detect(messages, |x| x.max_pending(2)).await;
+        assert_eq!(delivered, ids("buyer", &[1, 3, 4, 5, 6]));
+        assert_eq!(requested, vec![(2, 2)]);
+    }
+
+    #[tokio::test]
+    async fn replays_only_fill_gaps() {
+        let replay = |topic_id, topic_sequence_n| Message {
+            replay: true,
+            ..message(topic_id, topic_sequence_n)
+        };
+        let messages = vec![
+            replay("seller", 1),
+            message("buyer", 1),
+            message("buyer", 3),
+            replay("buyer", 1),
+            replay("buyer", 2),
+            message("seller", 2),
+        ];
+
+        let (delivered, requested) = detect(messages, |x| x).await;
+        let mut expected = ids("buyer", &[1, 2, 3]);
+        expected.extend(ids("seller", &[2]));
+        assert_eq!(delivered, expected);
+        assert_eq!(requested, vec![(2, 2)]);
+    }
+
+    #[tokio::test]
+    async fn asks_for_each_missing_run_and_skips_gap_fills() {
+        let messages = vec![
+            message("buyer", 1),
+            message("buyer", 3),
+            message("buyer", 6),
+            gap_fill("buyer", 2),
+            message("buyer", 4),
+            gap_fill("buyer", 5),
+        ];
+
+        let (delivered, requested) =
+            detect(messages, |x| x.retry_after(Duration::from_secs(0))).await;
+        assert_eq!(delivered, ids("buyer", &[1, 3, 4, 6]));
+        assert_eq!(requested, vec![(2, 2), (2, 2), (4, 5), (4, 5), (5, 5)]);
+    }
+
+    #[tokio::test]
+    async fn asks_again_for_gaps_when_nothing_else_arrives() {
+        let (tx, mut delivered, mut requested) = spawn_detector();
+        tx.unbounded_send(Ok(message("buyer", 1))).unwrap();
+        tx.unbounded_send(Ok(message("buyer", 3))).unwrap();
+
+        assert_eq!(delivered.next().await, Some(("buyer".to_string(), 1)));
+        let start = Instant::now();
+        assert_eq!(requested.next().await.map(range), Some((2, 2)));
+        assert_eq!(requested.next().await.map(range), Some((2, 2)));
+        assert!(start.elapsed() >= DEFAULT_RETRY_AFTER);
+
+        tx.unbounded_send(Ok(message("buyer", 2))).unwrap();
+        assert_eq!(delivered.next().await, Some(("buyer".to_string(), 2)));
+        assert_eq!(delivered.next().await, Some(("buyer".to_string(), 3)));
+    }
+
+    #[tokio::test]
+    async fn asks_for_whatever_came_after_the_last_message_once_a_topic_goes_quiet() {
+        let (tx, mut delivered, mut requested) = spawn_detector();
+        tx.unbounded_send(Ok(message("buyer", 1))).unwrap();
+        tx.unbounded_send(Ok(message("buyer", 2))).unwrap();
+        assert_eq!(delivered.next().await, Some(("buyer".to_string(), 1)));
+        assert_eq!(delivered.next().await, Some(("buyer".to_string(), 2)));
+
+        // the lost message 3 is asked for, but only once however long the topic stays quiet
+        assert_eq!(requested.next().await.map(range), Some((3, u64::MAX)));
+        tokio::time::sleep(DEFAULT_RETRY_AFTER * 10).await;
+        assert!(requested.next().now_or_never().is_none());
+
+        // once it turns up the topic can go quiet again
+        let replay = Message {
+            replay: true,
+            ..message("buyer", 3)
+        };
+        tx.unbounded_send(Ok(replay)).unwrap();
+        assert_eq!(delivered.next().await, Some(("buyer".to_string(), 3)));
+        assert_eq!(requested.next().await.map(range), Some((4, u64::MAX)));
+    }
+}
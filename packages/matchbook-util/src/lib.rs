@@ -8,8 +8,21 @@ use tokio_util::udp::UdpFramed;
 
 const IP_ALL: [u8; 4] = [0, 0, 0, 0];
 
+pub mod binary;
 pub mod codec;
+pub mod gap;
+pub mod sequencer;
+pub use binary::{BinaryMessageCodec, MessageView};
 pub use codec::*;
+pub use gap::GapDetector;
+pub use sequencer::Sequencer;
+
+/// Codec used on the multicast backbone. Every service has to agree on it, the `json` feature switches to JSON to make
+/// the backbone easier to debug
+#[cfg(not(feature = "json"))]
+type BackboneCodec = BinaryMessageCodec;
+#[cfg(feature = "json")]
+type BackboneCodec = MatchbookMessageCodec;
 
 /// Bind socket to multicast address with IP_MULTICAST_LOOP and SO_REUSEADDR Enabled
 pub fn bind_multicast(
@@ -37,6 +50,7 @@ pub fn bind_multicast(
     Ok(socket.into_udp_socket())
 }
 
+#[allow(clippy::type_complexity)]
 pub fn make_matchbook_streams(
     multi_addr: SocketAddr,
 ) -> Result<
@@ -48,7 +62,7 @@ pub fn make_matchbook_streams(
 > {
     let socket = bind_multicast(&multi_addr)?;
     let socket = UdpSocket::from_std(socket)?;
-    let (sink, stream) = UdpFramed::new(socket, MatchbookMessageCodec::new()).split();
+    let (sink, stream) = UdpFramed::new(socket, BackboneCodec::new()).split();
     let sink = sink.with(move |x| futures::future::ok((x, multi_addr)));
     let stream = stream.map(|x| x.map(|(y, _)| y));
 
@@ -0,0 +1,152 @@
+use matchbook_types::*;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+/// Hands out the sequence number of every message a service publishes, per publisher and topic. Every id handed out
+/// is written to a journal first so that numbering carries on where it left off after a restart
+#[derive(Debug, Default)]
+pub struct Sequencer {
+    /// Last sequence number handed out for each topic
+    last: HashMap<(ServiceId, String), u64>,
+    journal: Option<File>,
+}
+
+impl Sequencer {
+    /// Restore the sequence numbers recorded in the journal at `path`, creating it if it doesn't exist yet
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let mut last = HashMap::new();
+
+        if path.exists() {
+            let mut lines = BufReader::new(File::open(path)?).lines().peekable();
+            while let Some(line) = lines.next() {
+                let line = line?;
+                let id: MessageId = match serde_json::from_str(&line) {
+                    Ok(id) => id,
+                    // the last line is cut short if we stopped in the middle of writing it, it was never published
+                    Err(_) if lines.peek().is_none() => break,
+                    Err(e) => return Err(format!("corrupt sequence journal: {}", e).into()),
+                };
+
+                let n = last.entry((id.publisher_id, id.topic_id)).or_insert(0);
+                *n = id.topic_sequence_n.max(*n);
+            }
+        }
+
+        // compact the journal down to one line per topic so it doesn't grow forever
+        let compacted = path.with_extension("compacting");
+        {
+            let mut file = File::create(&compacted)?;
+            for ((publisher_id, topic_id), topic_sequence_n) in last.iter() {
+                let id = MessageId {
+                    publisher_id: *publisher_id,
+                    topic_id: topic_id.clone(),
+                    topic_sequence_n: *topic_sequence_n,
+                };
+                writeln!(file, "{}", serde_json::to_string(&id)?)?;
+            }
+            file.sync_all()?;
+        }
+        std::fs::rename(&compacted, path)?;
+
+        Ok(Self {
+            last,
+            journal: Some(OpenOptions::new().append(true).open(path)?),
+        })
+    }
+
+    /// Assign the next id on `topic_id`. Topics start at 1
+    pub fn next_id(
+        &mut self,
+        publisher_id: ServiceId,
+        topic_id: String,
+    ) -> Result<MessageId, Box<dyn std::error::Error>> {
+        let n = self
+            .last
+            .entry((publisher_id, topic_id.clone()))
+            .or_insert(0);
+        let id = MessageId {
+            publisher_id,
+            topic_id,
+            topic_sequence_n: *n + 1,
+        };
+
+        if let Some(journal) = self.journal.as_mut() {
+            writeln!(journal, "{}", serde_json::to_string(&id)?)?;
+            journal.flush()?;
+        }
+
+        *n += 1;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ENGINE: ServiceId = ServiceId {
+        kind: ServiceKind::MatchingEngine,
+        number: 0,
+    };
+
+    fn journal_path(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("sequencer-{}-{}.jsonl", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn next(sequencer: &mut Sequencer, topic_id: &str) -> u64 {
+        sequencer
+            .next_id(ENGINE, topic_id.to_string())
+            .unwrap()
+            .topic_sequence_n
+    }
+
+    #[test]
+    fn every_topic_is_numbered_separately() {
+        let mut sequencer = Sequencer::default();
+        assert_eq!(next(&mut sequencer, "buyer"), 1);
+        assert_eq!(next(&mut sequencer, "buyer"), 2);
+        assert_eq!(next(&mut sequencer, "seller"), 1);
+        assert_eq!(next(&mut sequencer, "buyer"), 3);
+    }
+
+    #[test]
+    fn numbering_carries_on_after_a_restart() {
+        let path = journal_path("restart");
+        {
+            let mut sequencer = Sequencer::open(&path).unwrap();
+            next(&mut sequencer, "buyer");
+            next(&mut sequencer, "buyer");
+            next(&mut sequencer, "seller");
+        }
+
+        let mut sequencer = Sequencer::open(&path).unwrap();
+        assert_eq!(next(&mut sequencer, "buyer"), 3);
+        assert_eq!(next(&mut sequencer, "seller"), 2);
+        assert_eq!(next(&mut sequencer, "someone new"), 1);
+
+        let mut sequencer = Sequencer::open(&path).unwrap();
+        assert_eq!(next(&mut sequencer, "buyer"), 4);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_half_written_last_entry_is_ignored() {
+        let path = journal_path("half-written");
+        {
+            let mut sequencer = Sequencer::open(&path).unwrap();
+            next(&mut sequencer, "buyer");
+        }
+        let mut journal = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(journal, "{{\"publisher_id\":").unwrap();
+
+        let mut sequencer = Sequencer::open(&path).unwrap();
+        assert_eq!(next(&mut sequencer, "buyer"), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,15 +1,26 @@
 use std::str::FromStr;
-use std::{env, net::SocketAddr};
+use std::{env, net::SocketAddr, path::PathBuf};
 
-use matchbook_types::ServiceId;
+use chrono::NaiveTime;
+use matchbook_types::{ParticipantId, SelfTradePrevention, ServiceId};
 
 const MULTICAST_ADDR_ENV_VAR_NAME: &str = "MULTICAST_ADDR";
 const SERVICE_ID_ENV_VAR_NAME: &str = "SERVICE_ID";
+const DAY_END_ENV_VAR_NAME: &str = "DAY_END";
+const SELF_TRADE_PREVENTION_ENV_VAR_NAME: &str = "SELF_TRADE_PREVENTION";
+const SEQUENCE_JOURNAL_ENV_VAR_NAME: &str = "SEQUENCE_JOURNAL";
+const DEFAULT_SEQUENCE_JOURNAL: &str = "sequence-journal.jsonl";
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub service_id: ServiceId,
     pub multicast_addr: SocketAddr,
+    /// Time of day (UTC) when day orders expire. Defaults to midnight
+    pub day_end: NaiveTime,
+    /// Self trade prevention mode of each participant, configured as `participant=mode,...`
+    pub self_trade_prevention: Vec<(ParticipantId, SelfTradePrevention)>,
+    /// File the sequence number of every published message is recorded in, so numbering survives a restart
+    pub sequence_journal: PathBuf,
 }
 
 pub fn source_config_from_env() -> Result<Config, Box<dyn std::error::Error>> {
@@ -17,5 +28,34 @@ pub fn source_config_from_env() -> Result<Config, Box<dyn std::error::Error>> {
         service_id: env::var(SERVICE_ID_ENV_VAR_NAME)
             .map(|x| ServiceId::from_str(x.as_str()))??,
         multicast_addr: env::var(MULTICAST_ADDR_ENV_VAR_NAME).map(|x| x.parse())??,
+        day_end: match env::var(DAY_END_ENV_VAR_NAME) {
+            Ok(x) => x.parse()?,
+            Err(_) => NaiveTime::from_hms_opt(0, 0, 0).expect("midnight is a valid time"),
+        },
+        self_trade_prevention: match env::var(SELF_TRADE_PREVENTION_ENV_VAR_NAME) {
+            Ok(x) => parse_self_trade_prevention(&x)?,
+            Err(_) => vec![],
+        },
+        sequence_journal: env::var(SEQUENCE_JOURNAL_ENV_VAR_NAME)
+            .unwrap_or_else(|_| DEFAULT_SEQUENCE_JOURNAL.to_string())
+            .into(),
     })
 }
+
+fn parse_self_trade_prevention(
+    s: &str,
+) -> Result<Vec<(ParticipantId, SelfTradePrevention)>, Box<dyn std::error::Error>> {
+    s.split(',')
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            let mut split = x.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(participant), Some(mode)) => Ok((
+                    participant.to_string(),
+                    SelfTradePrevention::from_str(mode)?,
+                )),
+                _ => Err(format!("incorrectly formatted self trade prevention '{}'", x).into()),
+            }
+        })
+        .collect()
+}
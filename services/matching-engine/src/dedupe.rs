@@ -0,0 +1,263 @@
+use matchbook_types::*;
+use std::collections::{HashMap, VecDeque};
+use tracing::*;
+
+/// What the engine should do with a message off the backbone
+#[derive(Debug, PartialEq)]
+pub enum Check {
+    /// A request it hasn't handled yet
+    Handle,
+    /// Nothing for the engine to act on
+    Ignore,
+    /// A request it has already handled, the first response is sent again instead
+    Respond(Message),
+}
+
+/// Remembers the most recent requests the engine has handled and the first response each of them got, so a request
+/// that arrives again isn't acted on twice. Only the last `capacity` requests are remembered, past that a replayed
+/// request is only known to be old enough that it might have been handled
+#[derive(Debug)]
+pub struct Dedupe {
+    capacity: usize,
+    /// Oldest first
+    order: VecDeque<MessageId>,
+    responses: HashMap<MessageId, Option<Message>>,
+    /// Highest sequence number forgotten on each topic
+    forgotten: HashMap<(ServiceId, String), u64>,
+}
+
+impl Dedupe {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            order: VecDeque::new(),
+            responses: HashMap::new(),
+            forgotten: HashMap::new(),
+        }
+    }
+
+    /// Decide what to do with `message`, remembering it if it's a request to handle
+    pub fn check(&mut self, message: &Message) -> Check {
+        // retransmit requests carry the id of the message they're asking for and gap fills stand in for one, neither
+        // asks the engine to do anything
+        if message.kind.is_retransmit_request() || matches!(message.kind, MessageKind::GapFill) {
+            return Check::Ignore;
+        }
+
+        if self.seen(&message.id) {
+            warn!(?message.id, "ignoring request that was already handled");
+            return match self.response(&message.id) {
+                Some(response) => Check::Respond(response.clone()),
+                None => Check::Ignore,
+            };
+        }
+
+        // a replay from before what's remembered could have been handled already, acting on it again would be worse
+        // than dropping it
+        if message.replay && self.forgot(&message.id) {
+            warn!(?message.id, "ignoring replayed request too old to know whether it was handled");
+            return Check::Ignore;
+        }
+
+        self.record(message.id.clone());
+        Check::Handle
+    }
+
+    /// Whether the request with `id` has already been handled
+    pub fn seen(&self, id: &MessageId) -> bool {
+        self.responses.contains_key(id)
+    }
+
+    /// Whether the request with `id` is older than what's remembered of its topic
+    fn forgot(&self, id: &MessageId) -> bool {
+        let key = (id.publisher_id, id.topic_id.clone());
+        matches!(self.forgotten.get(&key), Some(&n) if id.topic_sequence_n <= n)
+    }
+
+    /// The first response to the request with `id`, if it got one
+    pub fn response(&self, id: &MessageId) -> Option<&Message> {
+        self.responses.get(id).and_then(Option::as_ref)
+    }
+
+    /// Remember that the request with `id` is being handled, forgetting the oldest request if there are too many
+    pub fn record(&mut self, id: MessageId) {
+        if self.seen(&id) {
+            return;
+        }
+
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.responses.remove(&oldest);
+                let forgotten = self
+                    .forgotten
+                    .entry((oldest.publisher_id, oldest.topic_id))
+                    .or_insert(0);
+                *forgotten = (*forgotten).max(oldest.topic_sequence_n);
+            }
+        }
+        self.order.push_back(id.clone());
+        self.responses.insert(id, None);
+    }
+
+    /// Remember `response` as the answer to the request with `id`, unless it already has one
+    pub fn respond(&mut self, id: &MessageId, response: Message) {
+        if let Some(slot) = self.responses.get_mut(id) {
+            if slot.is_none() {
+                *slot = Some(response);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(topic_sequence_n: u64, kind: MessageKind) -> Message {
+        Message {
+            id: MessageId {
+                publisher_id: ServiceId {
+                    kind: ServiceKind::Port,
+                    number: 0,
+                },
+                topic_id: "buyer".to_string(),
+                topic_sequence_n,
+            },
+            kind,
+            replay: false,
+        }
+    }
+
+    #[test]
+    fn remembers_the_first_response() {
+        let mut dedupe = Dedupe::new(10);
+        let request = message(
+            1,
+            MessageKind::CancelRequest {
+                id: 1,
+                symbol: ['A', 'D', 'B', 'E'],
+            },
+        );
+        assert!(!dedupe.seen(&request.id));
+
+        dedupe.record(request.id.clone());
+        assert!(dedupe.seen(&request.id));
+        assert_eq!(dedupe.response(&request.id), None);
+
+        let ack = message(
+            7,
+            MessageKind::CancelRequest {
+                id: 7,
+                symbol: ['A', 'D', 'B', 'E'],
+            },
+        );
+        dedupe.respond(&request.id, ack.clone());
+        dedupe.respond(
+            &request.id,
+            message(
+                8,
+                MessageKind::CancelRequest {
+                    id: 8,
+                    symbol: ['A', 'D', 'B', 'E'],
+                },
+            ),
+        );
+        assert_eq!(dedupe.response(&request.id), Some(&ack));
+    }
+
+    #[test]
+    fn forgets_the_oldest_requests() {
+        let mut dedupe = Dedupe::new(2);
+        let ids: Vec<_> = (1..=3)
+            .map(|n| {
+                message(
+                    n,
+                    MessageKind::CancelRequest {
+                        id: 0,
+                        symbol: ['A', 'D', 'B', 'E'],
+                    },
+                )
+                .id
+            })
+            .collect();
+        for id in ids.iter().cloned() {
+            dedupe.record(id);
+        }
+
+        assert!(!dedupe.seen(&ids[0]));
+        assert!(dedupe.seen(&ids[1]));
+        assert!(dedupe.seen(&ids[2]));
+    }
+
+    #[test]
+    fn retransmit_requests_for_handled_requests_get_nothing() {
+        let mut dedupe = Dedupe::new(10);
+        let request = message(
+            1,
+            MessageKind::CancelRequest {
+                id: 1,
+                symbol: ['A', 'D', 'B', 'E'],
+            },
+        );
+        assert_eq!(dedupe.check(&request), Check::Handle);
+        dedupe.respond(
+            &request.id,
+            message(
+                7,
+                MessageKind::CancelRequest {
+                    id: 7,
+                    symbol: ['A', 'D', 'B', 'E'],
+                },
+            ),
+        );
+
+        for kind in &[
+            MessageKind::RetransmitRequest,
+            MessageKind::RetransmitRangeRequest {
+                last_topic_sequence_n: 2,
+            },
+            MessageKind::GapFill,
+        ] {
+            assert_eq!(dedupe.check(&message(1, kind.clone())), Check::Ignore);
+        }
+        assert_eq!(
+            dedupe.check(&request),
+            Check::Respond(message(
+                7,
+                MessageKind::CancelRequest {
+                    id: 7,
+                    symbol: ['A', 'D', 'B', 'E']
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn replays_of_forgotten_requests_are_ignored() {
+        let mut dedupe = Dedupe::new(2);
+        for n in 1..=3 {
+            let request = message(
+                n,
+                MessageKind::CancelRequest {
+                    id: 0,
+                    symbol: ['A', 'D', 'B', 'E'],
+                },
+            );
+            assert_eq!(dedupe.check(&request), Check::Handle);
+        }
+
+        let replay = |n| Message {
+            replay: true,
+            ..message(
+                n,
+                MessageKind::CancelRequest {
+                    id: 0,
+                    symbol: ['A', 'D', 'B', 'E'],
+                },
+            )
+        };
+        assert_eq!(dedupe.check(&replay(1)), Check::Ignore);
+        // one that was missed the first time round is still acted on
+        assert_eq!(dedupe.check(&replay(4)), Check::Handle);
+    }
+}
@@ -1,18 +1,33 @@
 #![deny(clippy::all)]
 use matchbook_types::*;
-use std::cmp::Reverse;
-use std::{
-    cmp::PartialOrd,
-    collections::{BinaryHeap, HashMap},
-};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+pub mod dedupe;
+pub use dedupe::{Check, Dedupe};
 
 #[derive(Debug, Default)]
 pub struct MatchingEngine {
     books: HashMap<SymbolOwned, Book>,
     order_counter: usize,
+    /// How each participant's orders are kept from trading with each other, unless an order says otherwise
+    self_trade_prevention: HashMap<ParticipantId, SelfTradePrevention>,
 }
 
 impl MatchingEngine {
+    /// Set how a participant's orders are kept from trading with each other. `None` lets them trade
+    pub fn set_self_trade_prevention(
+        &mut self,
+        participant: ParticipantId,
+        mode: Option<SelfTradePrevention>,
+    ) {
+        match mode {
+            Some(mode) => self.self_trade_prevention.insert(participant, mode),
+            None => self.self_trade_prevention.remove(&participant),
+        };
+    }
+
+    /// Submit a limit order, returning the id it was assigned, its executions and the quantity that expired because
+    /// of the order's time in force
     pub fn submit_limit_order(
         &mut self,
         owner: ParticipantId,
@@ -20,44 +35,324 @@ impl MatchingEngine {
         symbol: SymbolRef,
         price: Price,
         quantity: Quantity,
-    ) -> Result<(OrderId, Vec<Execution>), Box<dyn std::error::Error>> {
+        options: LimitOrderOptions,
+    ) -> Result<(OrderId, Vec<Execution>, Quantity), Box<dyn std::error::Error>> {
+        let LimitOrderOptions {
+            time_in_force,
+            display_quantity,
+            post_only,
+            self_trade_prevention,
+        } = options;
+
+        let self_trade_prevention =
+            self_trade_prevention.or_else(|| self.self_trade_prevention.get(&owner).copied());
+
+        let book = match self.books.get_mut(symbol) {
+            Some(book) => book,
+            None => return Err(format!("symbol '{:?}' does not exist", symbol).into()),
+        };
+
+        book.check_price(price)?;
+        book.check_quantity(quantity)?;
+        book.check_notional(price, quantity)?;
+
+        let mut order = LimitOrder::new(self.order_counter, owner, quantity, price)
+            .with_time_in_force(time_in_force)
+            .with_self_trade_prevention(self_trade_prevention);
+        // orders from the same owner are dealt with by self trade prevention, they don't take liquidity
+        if post_only && !book.dry_run(side, &order).is_empty() {
+            return Err(format!("post only order at {} would take liquidity", price).into());
+        }
+
+        if let Some(display_quantity) = display_quantity {
+            book.check_quantity(display_quantity)?;
+            if display_quantity > quantity {
+                return Err(format!(
+                    "display quantity {} must be at most the order quantity {}",
+                    display_quantity, quantity
+                )
+                .into());
+            }
+            order = order.with_display_quantity(display_quantity);
+        }
+
+        let id = self.order_counter;
+        self.order_counter += 1;
+
+        let (fills, expired) = book.submit_limit(side, order);
+
+        Ok((id, fills, expired))
+    }
+
+    /// Remove every resting day order from every book, returning the symbol and side each order was resting on
+    pub fn expire_day_orders(&mut self) -> Vec<(SymbolOwned, Side, LimitOrder)> {
+        let mut expired = vec![];
+        for (symbol, book) in self.books.iter_mut() {
+            expired.extend(
+                book.expire_day_orders()
+                    .into_iter()
+                    .map(|(side, order)| (*symbol, side, order)),
+            );
+        }
+
+        expired.sort_by_key(|(_, _, order)| order.id);
+        expired
+    }
+
+    /// Submit a market order, returning the id it was assigned, its executions and the quantity that was canceled
+    /// because the opposite side of the book ran out of liquidity before the order was filled
+    pub fn submit_market_order(
+        &mut self,
+        owner: ParticipantId,
+        side: Side,
+        symbol: SymbolRef,
+        quantity: Quantity,
+    ) -> Result<(OrderId, Vec<Execution>, Quantity), Box<dyn std::error::Error>> {
+        let book = match self.books.get_mut(symbol) {
+            Some(book) => book,
+            None => return Err(format!("symbol '{:?}' does not exist", symbol).into()),
+        };
+
+        let self_trade_prevention = self.self_trade_prevention.get(&owner).copied();
+        book.check_quantity(quantity)?;
+
+        let id = self.order_counter;
+        self.order_counter += 1;
+        let order = LimitOrder::new(id, owner, quantity, Price::ZERO)
+            .with_self_trade_prevention(self_trade_prevention);
+
+        let (fills, canceled) = book.submit_market(side, order);
+
+        Ok((id, fills, canceled))
+    }
+
+    /// Submit a stop order, returning the id it was assigned. The order waits off the book until a trade at or through
+    /// `stop_price` turns it into a limit order at `limit_price`, or a market order if it doesn't have one. Stop orders
+    /// are day orders
+    pub fn submit_stop_order(
+        &mut self,
+        owner: ParticipantId,
+        side: Side,
+        symbol: SymbolRef,
+        stop_price: Price,
+        limit_price: Option<Price>,
+        quantity: Quantity,
+    ) -> Result<OrderId, Box<dyn std::error::Error>> {
         let book = match self.books.get_mut(symbol) {
             Some(book) => book,
             None => return Err(format!("symbol '{:?}' does not exist", symbol).into()),
         };
 
+        book.check_price(stop_price)?;
+        book.check_quantity(quantity)?;
+        book.check_notional(stop_price, quantity)?;
+        if let Some(limit_price) = limit_price {
+            book.check_price(limit_price)?;
+            book.check_notional(limit_price, quantity)?;
+        }
+
+        let self_trade_prevention = self.self_trade_prevention.get(&owner).copied();
         let id = self.order_counter;
         self.order_counter += 1;
-        let order = LimitOrder::new(id, owner, quantity, price);
+        book.submit_stop(
+            StopOrder::new(id, owner, side, quantity, stop_price, limit_price)
+                .with_self_trade_prevention(self_trade_prevention),
+        );
+
+        Ok(id)
+    }
+
+    /// Take the orders that were canceled or decremented to prevent self trades since the last call, in the order it
+    /// happened
+    pub fn take_prevented_self_trades(&mut self) -> Vec<(SymbolOwned, PreventedSelfTrade)> {
+        let mut prevented = vec![];
+        for (symbol, book) in self.books.iter_mut() {
+            prevented.extend(
+                book.take_prevented_self_trades()
+                    .into_iter()
+                    .map(|x| (*symbol, x)),
+            );
+        }
+
+        prevented
+    }
+
+    /// Take the stop orders that were triggered since the last call, in the order they were triggered
+    pub fn take_triggered_orders(&mut self) -> Vec<(SymbolOwned, TriggeredOrder)> {
+        let mut triggered = vec![];
+        for (symbol, book) in self.books.iter_mut() {
+            triggered.extend(
+                book.take_triggered_orders()
+                    .into_iter()
+                    .map(|order| (*symbol, order)),
+            );
+        }
+
+        triggered
+    }
+
+    /// Cancel a resting order, returning the symbol it was resting on along with the order as it was when canceled.
+    /// Only the participant that submitted an order is allowed to cancel it, and only if they know its symbol
+    pub fn cancel_order(
+        &mut self,
+        owner: &str,
+        symbol: SymbolRef,
+        order_id: OrderId,
+    ) -> Result<(SymbolOwned, Side, LimitOrder), Box<dyn std::error::Error>> {
+        let (symbol, book) = self.owned_book_mut(owner, symbol, order_id)?;
+        let (side, order) = book.cancel(order_id).expect("order is known to be resting");
+
+        Ok((symbol, side, order))
+    }
+
+    /// Change the price and quantity of a resting order. `quantity` is the new total quantity of the order, including
+    /// anything that has already been filled.
+    ///
+    /// Decreasing the quantity of an order keeps its place in the queue, changing its price or increasing its
+    /// quantity sends it to the back of the queue. Only the participant that submitted an order is allowed to
+    /// replace it, and only if they know its symbol
+    pub fn replace_order(
+        &mut self,
+        owner: &str,
+        symbol: SymbolRef,
+        order_id: OrderId,
+        price: Price,
+        quantity: Quantity,
+    ) -> Result<Replacement, Box<dyn std::error::Error>> {
+        let (symbol, book) = self.owned_book_mut(owner, symbol, order_id)?;
+        let (side, order, executions) = book.replace(order_id, price, quantity)?;
 
-        let fills = match side {
-            Side::Ask => book.submit_limit_ask(order)?,
-            Side::Bid => book.submit_limit_bid(order)?,
+        Ok(Replacement {
+            symbol,
+            side,
+            order,
+            executions,
+        })
+    }
+
+    /// Find the book an order is resting on, making sure that it belongs to `owner` and is for `symbol`
+    fn owned_book_mut(
+        &mut self,
+        owner: &str,
+        symbol: SymbolRef,
+        order_id: OrderId,
+    ) -> Result<(SymbolOwned, &mut Book), Box<dyn std::error::Error>> {
+        let (order_symbol, book) = self
+            .books
+            .iter_mut()
+            .find(|(_, book)| book.order(order_id).is_some() || book.stop(order_id).is_some())
+            .ok_or_else(|| format!("order '{}' does not exist", order_id))?;
+
+        if order_symbol != symbol {
+            return Err(format!(
+                "order '{}' is for symbol '{}', not '{}'",
+                order_id,
+                order_symbol.iter().collect::<String>(),
+                symbol.iter().collect::<String>()
+            )
+            .into());
+        }
+
+        let order_owner = match (book.order(order_id), book.stop(order_id)) {
+            (Some((_, order)), _) => order.owner(),
+            (_, Some(stop)) => stop.owner(),
+            (None, None) => unreachable!("order is known to be in the book"),
         };
 
-        Ok((id, fills))
+        if order_owner != owner {
+            return Err(format!("order '{}' is not owned by '{}'", order_id, owner).into());
+        }
+
+        Ok((*order_symbol, book))
+    }
+
+    /// Displayed quantity at each price level on one side of a symbol's book, best price first
+    pub fn depth(
+        &self,
+        symbol: SymbolRef,
+        side: Side,
+    ) -> Result<Vec<(Price, Quantity)>, Box<dyn std::error::Error>> {
+        match self.books.get(symbol) {
+            Some(book) => Ok(book.depth(side)),
+            None => Err(format!("symbol '{:?}' does not exist", symbol).into()),
+        }
     }
 
-    /// Create a symbol if it doesn't exist and return true, otherwise do nothing and return false
-    pub fn create_symbol(&mut self, symbol: SymbolOwned) -> bool {
-        self.books.insert(symbol, Book::default()).is_none()
+    /// Create a symbol if it doesn't exist and return true, otherwise do nothing and return false. Orders for the
+    /// symbol need to be priced in whole ticks and sized in whole lots
+    pub fn create_symbol(
+        &mut self,
+        symbol: SymbolOwned,
+        tick_size: Price,
+        lot_size: Quantity,
+    ) -> bool {
+        match self.books.entry(symbol) {
+            std::collections::hash_map::Entry::Occupied(_) => false,
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(Book::new(tick_size, lot_size));
+                true
+            }
+        }
     }
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct Book {
-    bids: BinaryHeap<Reverse<LimitOrder>>,
-    asks: BinaryHeap<LimitOrder>,
+    /// Smallest price increment
+    tick_size: Price,
+    /// Smallest quantity increment
+    lot_size: Quantity,
+    /// Resting bids, grouped into FIFO queues by price level
+    bids: BTreeMap<Price, VecDeque<LimitOrder>>,
+    /// Resting asks, grouped into FIFO queues by price level
+    asks: BTreeMap<Price, VecDeque<LimitOrder>>,
+    /// Side and price level of every resting order
+    orders: HashMap<OrderId, (Side, Price)>,
+    /// Stop orders that haven't been triggered yet
+    stops: TriggerBook,
+    /// Price of the most recent trade, if anything has traded
+    last_trade_price: Option<Price>,
+    /// Lowest and highest prices traded at since stops were last checked. A trade that sweeps several levels can pass
+    /// a stop price without ending on it
+    traded: Option<(Price, Price)>,
+    /// Stop orders that were triggered and haven't been taken with `take_triggered_orders` yet
+    triggered: Vec<TriggeredOrder>,
+    /// Self trades that were prevented and haven't been taken with `take_prevented_self_trades` yet
+    prevented: Vec<PreventedSelfTrade>,
+}
+
+/// Books default to whole number ticks and lots
+impl Default for Book {
+    fn default() -> Self {
+        Book::new(Price::from(1), Quantity::from(1))
+    }
 }
 
 impl Book {
+    pub fn new(tick_size: Price, lot_size: Quantity) -> Self {
+        Book {
+            tick_size,
+            lot_size,
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            orders: HashMap::new(),
+            stops: TriggerBook::default(),
+            last_trade_price: None,
+            traded: None,
+            triggered: vec![],
+            prevented: vec![],
+        }
+    }
+
     /// Submit a ask limit order
     pub fn submit_limit_ask(
         &mut self,
         order: LimitOrder,
     ) -> Result<Vec<Execution>, Box<dyn std::error::Error>> {
-        self.asks.push(order);
-        Ok(self.fill_matching())
+        self.check_price(order.price)?;
+        self.check_quantity(order.quantity)?;
+        self.check_notional(order.price, order.quantity)?;
+        Ok(self.submit_limit(Side::Ask, order).0)
     }
 
     /// Submit a bid limit order
@@ -65,126 +360,1883 @@ impl Book {
         &mut self,
         order: LimitOrder,
     ) -> Result<Vec<Execution>, Box<dyn std::error::Error>> {
-        self.bids.push(Reverse(order));
-        Ok(self.fill_matching())
+        self.check_price(order.price)?;
+        self.check_quantity(order.quantity)?;
+        self.check_notional(order.price, order.quantity)?;
+        Ok(self.submit_limit(Side::Bid, order).0)
     }
 
-    /// Attempt to fill any orders
-    fn fill_matching(&mut self) -> Vec<Execution> {
-        let mut fills = vec![];
+    /// Make sure a price is a positive, whole number of ticks. Prices that aren't are rejected rather than rounded
+    pub fn check_price(&self, price: Price) -> Result<(), Box<dyn std::error::Error>> {
+        if price <= Price::ZERO || !price.is_multiple_of(self.tick_size) {
+            return Err(format!(
+                "price {} is not a positive multiple of the tick size {}",
+                price, self.tick_size
+            )
+            .into());
+        }
 
-        'matching: loop {
-            if let Some(mut bid) = self.bids.peek_mut() {
-                let mut ask = match self.asks.peek_mut() {
-                    Some(ask) if ask.price >= bid.0.price => ask,
-                    _ => break 'matching,
-                };
-                let fillable_quantity = ask.remaining().min(bid.0.remaining());
+        Ok(())
+    }
 
-                ask.fill(fillable_quantity);
-                bid.0.fill(fillable_quantity);
+    /// Make sure a quantity is a positive, whole number of lots
+    pub fn check_quantity(&self, quantity: Quantity) -> Result<(), Box<dyn std::error::Error>> {
+        if quantity <= Quantity::ZERO || !quantity.is_multiple_of(self.lot_size) {
+            return Err(format!(
+                "quantity {} is not a positive multiple of the lot size {}",
+                quantity, self.lot_size
+            )
+            .into());
+        }
 
-                fills.push(Execution {
-                    asker_id: ask.owner.clone(),
-                    asker_order_id: ask.id,
-                    bidder_id: bid.0.owner.clone(),
-                    bidder_order_id: bid.0.id,
-                    price: ask.price.max(bid.0.price),
-                    quantity: fillable_quantity,
+        Ok(())
+    }
+
+    /// Make sure an order's value, price times quantity, can be represented, so nothing working with it overflows
+    pub fn check_notional(
+        &self,
+        price: Price,
+        quantity: Quantity,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if price.checked_mul(quantity).is_none() {
+            return Err(format!("order value {} x {} is too large", quantity, price).into());
+        }
+
+        Ok(())
+    }
+
+    /// Match a limit order against the book, then trigger any stop orders its trades reached. Returns the order's
+    /// executions and the quantity that expired because of its time in force
+    fn submit_limit(&mut self, side: Side, order: LimitOrder) -> (Vec<Execution>, Quantity) {
+        let result = self.match_limit(side, order);
+        self.trigger_stops();
+        result
+    }
+
+    /// Match a limit order against the book, resting whatever is left unless its time in force says otherwise. A
+    /// fill-or-kill order that can't be filled completely doesn't trade at all
+    fn match_limit(&mut self, side: Side, mut order: LimitOrder) -> (Vec<Execution>, Quantity) {
+        if order.time_in_force == TimeInForce::FillOrKill
+            && self
+                .dry_run(side, &order)
+                .iter()
+                .map(|x| x.quantity)
+                .fold(Quantity::ZERO, Quantity::saturating_add)
+                < order.remaining
+        {
+            return (vec![], order.remaining);
+        }
+
+        let fills = self.fill_matching(side, &mut order);
+        let rests = match order.time_in_force {
+            TimeInForce::Day | TimeInForce::GoodTillCancel => true,
+            TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill => false,
+        };
+
+        if !rests {
+            return (fills, order.remaining);
+        }
+
+        if !order.is_filled() {
+            self.rest(side, order);
+        }
+
+        (fills, Quantity::ZERO)
+    }
+
+    /// The executions an incoming order on `side` would get, without changing the book. Only the price levels it
+    /// crosses are copied, so self trade prevention and iceberg orders play out the same as they would for real
+    fn dry_run(&self, side: Side, order: &LimitOrder) -> Vec<Execution> {
+        let mut scratch = Book::new(self.tick_size, self.lot_size);
+        let (levels, scratch_levels) = match side {
+            Side::Bid => (&self.asks, &mut scratch.asks),
+            Side::Ask => (&self.bids, &mut scratch.bids),
+        };
+        scratch_levels.extend(
+            levels
+                .iter()
+                .filter(|(&price, _)| crosses(side, order.price, price))
+                .map(|(&price, level)| (price, level.clone())),
+        );
+
+        scratch.fill_matching(side, &mut order.clone())
+    }
+
+    /// Remove every resting day order and untriggered stop order, returning the side each order was on and the order
+    pub fn expire_day_orders(&mut self) -> Vec<(Side, LimitOrder)> {
+        let expiring: Vec<OrderId> = self
+            .bids()
+            .chain(self.asks())
+            .chain(self.stops.iter().map(|x| &x.order))
+            .filter(|x| x.time_in_force == TimeInForce::Day)
+            .map(LimitOrder::id)
+            .collect();
+
+        expiring
+            .into_iter()
+            .filter_map(|id| self.cancel(id))
+            .collect()
+    }
+
+    /// Submit a market order, filling it against the best resting orders on the opposite side of the book until it's
+    /// filled or there's nothing left to fill against. Returns the executions and the quantity that was canceled
+    /// because it couldn't be filled. Market orders never rest on the book and the price of `order` is ignored
+    pub fn submit_market(
+        &mut self,
+        side: Side,
+        mut order: LimitOrder,
+    ) -> (Vec<Execution>, Quantity) {
+        let fills = self.match_market(side, &mut order);
+        self.trigger_stops();
+        (fills, order.remaining)
+    }
+
+    fn match_market(&mut self, side: Side, order: &mut LimitOrder) -> Vec<Execution> {
+        order.price = match side {
+            Side::Bid => Price::MAX,
+            Side::Ask => Price::MIN,
+        };
+
+        self.fill_matching(side, order)
+    }
+
+    /// Submit a stop order. It's triggered right away if the last trade already reached its stop price
+    pub fn submit_stop(&mut self, order: StopOrder) {
+        self.stops.add(order);
+        self.trigger_stops();
+    }
+
+    /// Look up a stop order that hasn't been triggered yet
+    pub fn stop(&self, order_id: OrderId) -> Option<&StopOrder> {
+        self.stops.get(order_id)
+    }
+
+    /// Take the stop orders that were triggered since the last call, in the order they were triggered
+    pub fn take_triggered_orders(&mut self) -> Vec<TriggeredOrder> {
+        std::mem::take(&mut self.triggered)
+    }
+
+    /// Take the orders that were canceled or decremented to prevent self trades since the last call, in the order it
+    /// happened
+    pub fn take_prevented_self_trades(&mut self) -> Vec<PreventedSelfTrade> {
+        std::mem::take(&mut self.prevented)
+    }
+
+    /// Turn stop orders into market or limit orders once a trade reaches their stop price. Stops triggered by the same
+    /// trades are matched in the order they were submitted, after which the trades they made can trigger further stops
+    fn trigger_stops(&mut self) {
+        loop {
+            let (low, high) = match self.traded.take() {
+                Some(x) => x,
+                // a stop that was just submitted is checked against the last trade
+                None => match self.last_trade_price {
+                    Some(x) => (x, x),
+                    None => break,
+                },
+            };
+            let triggered = self.stops.take_triggered(low, high);
+            if triggered.is_empty() {
+                break;
+            }
+
+            for stop in triggered {
+                let mut order = stop.order.clone();
+                let (executions, canceled_quantity) = match stop.limit_price {
+                    Some(_) => (self.match_limit(stop.side, order).0, Quantity::ZERO),
+                    None => {
+                        let fills = self.match_market(stop.side, &mut order);
+                        (fills, order.remaining)
+                    }
+                };
+
+                self.triggered.push(TriggeredOrder {
+                    stop,
+                    executions,
+                    canceled_quantity,
                 });
-            } else {
-                break 'matching;
             }
+        }
+    }
 
-            match self.bids.peek() {
-                Some(Reverse(bid)) if bid.is_filled() => {
-                    self.bids.pop();
-                }
-                _ => {}
+    /// Remove a resting or untriggered stop order from the book, returning the side it was on and the order
+    pub fn cancel(&mut self, order_id: OrderId) -> Option<(Side, LimitOrder)> {
+        if let Some(stop) = self.stops.cancel(order_id) {
+            return Some((stop.side, stop.order));
+        }
+
+        let (side, price) = self.orders.remove(&order_id)?;
+        let levels = match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+
+        let level = levels.get_mut(&price)?;
+        let position = level.iter().position(|x| x.id == order_id)?;
+        let order = level.remove(position)?;
+
+        if level.is_empty() {
+            levels.remove(&price);
+        }
+
+        Some((side, order))
+    }
+
+    /// Change the price and quantity of a resting order, returning the side it's resting on, the order once it was
+    /// amended and matched and any executions caused by the change. The order keeps its place in the queue if only its
+    /// quantity was decreased
+    pub fn replace(
+        &mut self,
+        order_id: OrderId,
+        price: Price,
+        quantity: Quantity,
+    ) -> Result<(Side, LimitOrder, Vec<Execution>), Box<dyn std::error::Error>> {
+        if self.stops.get(order_id).is_some() {
+            return Err(format!(
+                "stop order '{}' can't be replaced until it's triggered",
+                order_id
+            )
+            .into());
+        }
+
+        self.check_price(price)?;
+        self.check_quantity(quantity)?;
+        self.check_notional(price, quantity)?;
+
+        let (side, order) = self
+            .order(order_id)
+            .ok_or_else(|| format!("order '{}' does not exist", order_id))?;
+
+        let filled = order.quantity - order.remaining;
+        if quantity <= filled {
+            return Err(format!(
+                "order '{}' has already filled {}, quantity must be greater than that",
+                order_id, filled
+            )
+            .into());
+        }
+
+        if price == order.price && quantity <= order.quantity {
+            let order = self
+                .order_mut(order_id)
+                .expect("order is known to be resting");
+            order.amend(price, quantity);
+            return Ok((side, order.clone(), vec![]));
+        }
+
+        let (side, mut order) = self.cancel(order_id).expect("order is known to be resting");
+        order.amend(price, quantity);
+
+        let fills = self.fill_matching(side, &mut order);
+        let replaced = order.clone();
+        if !order.is_filled() {
+            self.rest(side, order);
+        }
+        self.trigger_stops();
+
+        Ok((side, replaced, fills))
+    }
+
+    /// Look up a resting order, returning the side it is resting on and the order
+    pub fn order(&self, order_id: OrderId) -> Option<(Side, &LimitOrder)> {
+        let &(side, price) = self.orders.get(&order_id)?;
+        let levels = match side {
+            Side::Bid => &self.bids,
+            Side::Ask => &self.asks,
+        };
+
+        levels
+            .get(&price)?
+            .iter()
+            .find(|x| x.id == order_id)
+            .map(|order| (side, order))
+    }
+
+    fn order_mut(&mut self, order_id: OrderId) -> Option<&mut LimitOrder> {
+        let &(side, price) = self.orders.get(&order_id)?;
+        let levels = match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+
+        levels
+            .get_mut(&price)?
+            .iter_mut()
+            .find(|x| x.id == order_id)
+    }
+
+    /// Add an order to the back of its price level's queue
+    fn rest(&mut self, side: Side, mut order: LimitOrder) {
+        order.refresh();
+        self.orders.insert(order.id, (side, order.price));
+        let levels = match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+        levels.entry(order.price).or_default().push_back(order);
+    }
+
+    /// Fill an incoming order against the best resting orders on the opposite side of the book for as long as their
+    /// prices cross. Trades execute at the resting order's price. If the incoming order would trade with an order
+    /// from the same participant, its self trade prevention mode decides which of the two is canceled or decremented
+    /// instead
+    fn fill_matching(&mut self, side: Side, taker: &mut LimitOrder) -> Vec<Execution> {
+        let mut fills = vec![];
+
+        let levels = match side {
+            Side::Bid => &mut self.asks,
+            Side::Ask => &mut self.bids,
+        };
+
+        while !taker.is_filled() {
+            // an incoming bid takes from the lowest ask, an incoming ask takes from the highest bid
+            let best = match side {
+                Side::Bid => levels.iter_mut().next(),
+                Side::Ask => levels.iter_mut().next_back(),
             };
 
-            match self.asks.peek() {
-                Some(ask) if ask.is_filled() => {
-                    self.asks.pop();
-                }
-                _ => {}
+            let (price, level) = match best {
+                Some((&price, level)) if crosses(side, taker.price, price) => (price, level),
+                _ => break,
             };
+
+            let maker = level.front_mut().expect("price levels are never empty");
+
+            match taker.self_trade_prevention {
+                Some(mode) if maker.owner == taker.owner => {
+                    let (maker_canceled, taker_canceled) = match mode {
+                        SelfTradePrevention::CancelNewest => (Quantity::ZERO, taker.remaining),
+                        SelfTradePrevention::CancelOldest => (maker.remaining, Quantity::ZERO),
+                        SelfTradePrevention::CancelBoth => (maker.remaining, taker.remaining),
+                        SelfTradePrevention::Decrement => {
+                            let decrement = maker.remaining.min(taker.remaining);
+                            (decrement, decrement)
+                        }
+                    };
+
+                    if maker_canceled > Quantity::ZERO {
+                        maker.reduce(maker_canceled);
+                        self.prevented.push(PreventedSelfTrade {
+                            side: opposite(side),
+                            order: maker.clone(),
+                            canceled_quantity: maker_canceled,
+                        });
+                    }
+
+                    if taker_canceled > Quantity::ZERO {
+                        taker.reduce(taker_canceled);
+                        self.prevented.push(PreventedSelfTrade {
+                            side,
+                            order: taker.clone(),
+                            canceled_quantity: taker_canceled,
+                        });
+                    }
+                }
+                _ => {
+                    let fillable_quantity = maker.displayed().min(taker.remaining());
+
+                    maker.fill(fillable_quantity);
+                    taker.fill(fillable_quantity);
+
+                    let (bid, ask) = match side {
+                        Side::Bid => (&*taker, &*maker),
+                        Side::Ask => (&*maker, &*taker),
+                    };
+
+                    fills.push(Execution {
+                        asker_id: ask.owner.clone(),
+                        asker_order_id: ask.id,
+                        bidder_id: bid.owner.clone(),
+                        bidder_order_id: bid.id,
+                        price,
+                        quantity: fillable_quantity,
+                    });
+                    self.last_trade_price = Some(price);
+                    self.traded = Some(match self.traded {
+                        Some((low, high)) => (low.min(price), high.max(price)),
+                        None => (price, price),
+                    });
+                }
+            }
+
+            if maker.is_filled() {
+                self.orders.remove(&maker.id);
+                level.pop_front();
+            } else if maker.displayed() == Quantity::ZERO {
+                // the displayed slice of an iceberg order was filled, the next slice is shown from the back of the
+                // queue
+                let mut maker = level.pop_front().expect("price levels are never empty");
+                maker.refresh();
+                level.push_back(maker);
+            }
+
+            if level.is_empty() {
+                levels.remove(&price);
+            }
         }
 
         fills
     }
 
+    /// Displayed quantity at each price level on one side of the book, best price first. The reserve quantity of
+    /// iceberg orders isn't included
+    pub fn depth(&self, side: Side) -> Vec<(Price, Quantity)> {
+        let displayed = |(&price, level): (&Price, &VecDeque<LimitOrder>)| {
+            // a level can hold more than one order can
+            let displayed = level
+                .iter()
+                .map(LimitOrder::displayed)
+                .fold(Quantity::ZERO, Quantity::saturating_add);
+            (price, displayed)
+        };
+
+        match side {
+            Side::Bid => self.bids.iter().rev().map(displayed).collect(),
+            Side::Ask => self.asks.iter().map(displayed).collect(),
+        }
+    }
+
+    /// Resting asks, in the order they are queued within each price level
     pub fn asks(&self) -> impl Iterator<Item = &LimitOrder> {
-        self.asks.iter()
+        self.asks.values().flatten()
     }
 
+    /// Resting bids, in the order they are queued within each price level
     pub fn bids(&self) -> impl Iterator<Item = &LimitOrder> {
-        self.bids.iter().map(|Reverse(x)| x)
+        self.bids.values().flatten()
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Execution {
-    pub bidder_id: ParticipantId,
-    pub bidder_order_id: usize,
-    pub asker_id: ParticipantId,
-    pub asker_order_id: usize,
-    pub quantity: Quantity,
-    pub price: Price,
+fn opposite(side: Side) -> Side {
+    match side {
+        Side::Bid => Side::Ask,
+        Side::Ask => Side::Bid,
+    }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct LimitOrder {
-    id: OrderId,
-    owner: ParticipantId,
-    quantity: Quantity,
-    price: Price,
-    remaining: Quantity,
+/// Returns true if an incoming order on `side` limited to `limit` can trade with a resting order at `resting`
+fn crosses(side: Side, limit: Price, resting: Price) -> bool {
+    match side {
+        Side::Bid => limit >= resting,
+        Side::Ask => limit <= resting,
+    }
 }
 
-impl PartialOrd for LimitOrder {
-    fn partial_cmp(&self, other: &LimitOrder) -> Option<std::cmp::Ordering> {
-        self.price.partial_cmp(&other.price)
-    }
+/// Stop orders waiting for the last trade price to reach their stop price
+#[derive(Debug, Clone, Default)]
+pub struct TriggerBook {
+    /// Buy stops by stop price, triggered once the last trade price rises to or above their stop price
+    buys: BTreeMap<Price, Vec<StopOrder>>,
+    /// Sell stops by stop price, triggered once the last trade price falls to or below their stop price
+    sells: BTreeMap<Price, Vec<StopOrder>>,
 }
 
-impl std::cmp::Ord for LimitOrder {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.price.cmp(&other.price)
+impl TriggerBook {
+    pub fn add(&mut self, order: StopOrder) {
+        let stops = match order.side {
+            Side::Bid => &mut self.buys,
+            Side::Ask => &mut self.sells,
+        };
+        stops.entry(order.stop_price).or_default().push(order);
     }
-}
 
-impl LimitOrder {
-    pub fn new(id: OrderId, owner: String, quantity: usize, price: usize) -> Self {
-        LimitOrder {
-            id,
-            owner,
-            quantity,
-            price,
-            remaining: quantity,
+    /// Remove every stop order triggered by trades at prices from `low` to `high`, in the order they were submitted
+    pub fn take_triggered(&mut self, low: Price, high: Price) -> Vec<StopOrder> {
+        let buy_prices: Vec<Price> = self.buys.range(..=high).map(|(&price, _)| price).collect();
+        let sell_prices: Vec<Price> = self.sells.range(low..).map(|(&price, _)| price).collect();
+
+        let mut triggered = vec![];
+        for price in buy_prices {
+            triggered.extend(self.buys.remove(&price).unwrap_or_default());
+        }
+        for price in sell_prices {
+            triggered.extend(self.sells.remove(&price).unwrap_or_default());
         }
-    }
 
-    pub fn price(&self) -> Price {
-        self.price
+        triggered.sort_by_key(|x| x.order.id);
+        triggered
     }
 
-    pub fn quantity(&self) -> Quantity {
-        self.quantity
+    pub fn get(&self, order_id: OrderId) -> Option<&StopOrder> {
+        self.iter().find(|x| x.order.id == order_id)
     }
 
-    pub fn remaining(&self) -> Quantity {
-        self.remaining
-    }
+    pub fn cancel(&mut self, order_id: OrderId) -> Option<StopOrder> {
+        let (side, stop_price) = self.get(order_id).map(|x| (x.side, x.stop_price))?;
+        let stops = match side {
+            Side::Bid => &mut self.buys,
+            Side::Ask => &mut self.sells,
+        };
+
+        let level = stops.get_mut(&stop_price)?;
+        let position = level.iter().position(|x| x.order.id == order_id)?;
+        let order = level.remove(position);
+
+        if level.is_empty() {
+            stops.remove(&stop_price);
+        }
+
+        Some(order)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &StopOrder> {
+        self.buys.values().chain(self.sells.values()).flatten()
+    }
+}
+
+/// An order that turns into a market or limit order once the market trades at or through its stop price
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StopOrder {
+    side: Side,
+    stop_price: Price,
+    limit_price: Option<Price>,
+    /// The order that's submitted once the stop is triggered
+    order: LimitOrder,
+}
+
+impl StopOrder {
+    pub fn new(
+        id: OrderId,
+        owner: ParticipantId,
+        side: Side,
+        quantity: Quantity,
+        stop_price: Price,
+        limit_price: Option<Price>,
+    ) -> Self {
+        StopOrder {
+            side,
+            stop_price,
+            limit_price,
+            order: LimitOrder::new(id, owner, quantity, limit_price.unwrap_or_default()),
+        }
+    }
+
+    pub fn id(&self) -> OrderId {
+        self.order.id
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.order.owner
+    }
+
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    pub fn stop_price(&self) -> Price {
+        self.stop_price
+    }
+
+    pub fn limit_price(&self) -> Option<Price> {
+        self.limit_price
+    }
+
+    pub fn quantity(&self) -> Quantity {
+        self.order.quantity
+    }
+
+    pub fn with_self_trade_prevention(mut self, mode: Option<SelfTradePrevention>) -> Self {
+        self.order.self_trade_prevention = mode;
+        self
+    }
+}
+
+/// A stop order that was triggered, along with what happened when it was submitted
+#[derive(Debug, Clone)]
+pub struct TriggeredOrder {
+    pub stop: StopOrder,
+    pub executions: Vec<Execution>,
+    /// Quantity of a stop (market) order that was canceled because it ran out of liquidity
+    pub canceled_quantity: Quantity,
+}
+
+/// An order that was canceled or decremented instead of trading with another order from the same owner
+#[derive(Debug, Clone)]
+pub struct PreventedSelfTrade {
+    pub side: Side,
+    /// The order right after it was canceled or decremented
+    pub order: LimitOrder,
+    pub canceled_quantity: Quantity,
+}
+
+/// The outcome of replacing an order
+#[derive(Debug, Clone)]
+pub struct Replacement {
+    pub symbol: SymbolOwned,
+    pub side: Side,
+    /// The order once it was amended and matched against the book
+    pub order: LimitOrder,
+    pub executions: Vec<Execution>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Execution {
+    pub bidder_id: ParticipantId,
+    pub bidder_order_id: usize,
+    pub asker_id: ParticipantId,
+    pub asker_order_id: usize,
+    pub quantity: Quantity,
+    pub price: Price,
+}
+
+/// Instructions on how a limit order is matched and how long it rests on the book
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitOrderOptions {
+    pub time_in_force: TimeInForce,
+    /// Only show this much of the order at a time, keeping the rest in reserve
+    pub display_quantity: Option<Quantity>,
+    /// Reject the order instead of letting it take liquidity
+    pub post_only: bool,
+    /// Overrides the owner's self trade prevention mode
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+}
+
+impl Default for LimitOrderOptions {
+    fn default() -> Self {
+        LimitOrderOptions {
+            time_in_force: TimeInForce::Day,
+            display_quantity: None,
+            post_only: false,
+            self_trade_prevention: None,
+        }
+    }
+}
+
+impl From<TimeInForce> for LimitOrderOptions {
+    fn from(time_in_force: TimeInForce) -> Self {
+        LimitOrderOptions {
+            time_in_force,
+            ..LimitOrderOptions::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LimitOrder {
+    id: OrderId,
+    owner: ParticipantId,
+    quantity: Quantity,
+    price: Price,
+    remaining: Quantity,
+    time_in_force: TimeInForce,
+    /// Size of each slice an iceberg order shows, `None` shows the whole order
+    display_quantity: Option<Quantity>,
+    /// What's left of the slice that's currently shown
+    displayed: Quantity,
+    /// What happens when the order would trade with another order from the same owner, `None` lets them trade
+    self_trade_prevention: Option<SelfTradePrevention>,
+}
+
+impl LimitOrder {
+    pub fn new(id: OrderId, owner: String, quantity: Quantity, price: Price) -> Self {
+        LimitOrder {
+            id,
+            owner,
+            quantity,
+            price,
+            remaining: quantity,
+            time_in_force: TimeInForce::Day,
+            display_quantity: None,
+            displayed: quantity,
+            self_trade_prevention: None,
+        }
+    }
+
+    pub fn with_self_trade_prevention(mut self, mode: Option<SelfTradePrevention>) -> Self {
+        self.self_trade_prevention = mode;
+        self
+    }
+
+    pub fn with_time_in_force(mut self, time_in_force: TimeInForce) -> Self {
+        self.time_in_force = time_in_force;
+        self
+    }
+
+    /// Turn the order into an iceberg order that only shows `display_quantity` at a time
+    pub fn with_display_quantity(mut self, display_quantity: Quantity) -> Self {
+        self.display_quantity = Some(display_quantity);
+        self.refresh();
+        self
+    }
+
+    pub fn id(&self) -> OrderId {
+        self.id
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    pub fn price(&self) -> Price {
+        self.price
+    }
+
+    pub fn quantity(&self) -> Quantity {
+        self.quantity
+    }
+
+    pub fn remaining(&self) -> Quantity {
+        self.remaining
+    }
+
+    pub fn time_in_force(&self) -> TimeInForce {
+        self.time_in_force
+    }
+
+    pub fn self_trade_prevention(&self) -> Option<SelfTradePrevention> {
+        self.self_trade_prevention
+    }
+
+    pub fn display_quantity(&self) -> Option<Quantity> {
+        self.display_quantity
+    }
+
+    /// Quantity that's visible on the book, which is only the current slice of an iceberg order
+    pub fn displayed(&self) -> Quantity {
+        self.displayed
+    }
 
     /// Fill some shares, returning the shares that still need to be filled
     pub fn fill(&mut self, quantity: Quantity) -> Quantity {
         assert!(self.remaining >= quantity);
         self.remaining -= quantity;
+        self.displayed = self.displayed - self.displayed.min(quantity);
         self.remaining
     }
 
+    /// Take quantity off an order without it trading. An order with something left over is restated to a smaller
+    /// quantity and keeps its place in the queue, an order with nothing left is canceled
+    fn reduce(&mut self, quantity: Quantity) {
+        assert!(self.remaining >= quantity);
+        if quantity < self.remaining {
+            self.quantity -= quantity;
+        }
+        self.remaining -= quantity;
+        self.displayed = self.displayed.min(self.remaining);
+    }
+
+    /// Show the next slice of an iceberg order, or everything that's left of any other order
+    fn refresh(&mut self) {
+        self.displayed = match self.display_quantity {
+            Some(display_quantity) => display_quantity.min(self.remaining),
+            None => self.remaining,
+        };
+    }
+
+    /// Set a new price and total quantity, keeping track of what has already been filled
+    fn amend(&mut self, price: Price, quantity: Quantity) {
+        let filled = self.quantity - self.remaining;
+        assert!(quantity > filled);
+        self.price = price;
+        self.quantity = quantity;
+        self.remaining = quantity - filled;
+        self.displayed = self.displayed.min(self.remaining);
+    }
+
     pub fn is_filled(&self) -> bool {
-        self.remaining == 0
+        self.remaining == Quantity::ZERO
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use matchbook_types::Decimal;
+    use quickcheck::quickcheck;
+
+    fn dec(x: i32) -> Decimal {
+        Decimal::from(x)
+    }
+
+    /// Quantities are shifted by one so that no generated order is empty, and there's always at least one of them
+    fn non_empty(mut quantities: Vec<u8>) -> Vec<Quantity> {
+        if quantities.is_empty() {
+            quantities.push(0);
+        }
+        quantities.into_iter().map(|q| dec(q as i32 + 1)).collect()
+    }
+
+    /// Prices are shifted by one so that every generated price is a positive number of ticks
+    fn positive_price(price: u16) -> Price {
+        dec(price as i32 + 1)
+    }
+
+    /// Turn generated `(is_bid, price, quantity)` triples into orders clustered around a handful of price levels so
+    /// that they're likely to cross
+    fn orders(generated: Vec<(bool, u8, u8)>) -> Vec<(Side, LimitOrder)> {
+        generated
+            .into_iter()
+            .enumerate()
+            .map(|(id, (is_bid, price, quantity))| {
+                let side = if is_bid { Side::Bid } else { Side::Ask };
+                let order = LimitOrder::new(
+                    id,
+                    format!("participant{}", id % 3),
+                    dec(quantity as i32 % 50 + 1),
+                    dec(price as i32 % 10 + 100),
+                );
+                (side, order)
+            })
+            .collect()
+    }
+
+    fn submit(book: &mut Book, side: Side, order: LimitOrder) -> Vec<Execution> {
+        match side {
+            Side::Bid => book.submit_limit_bid(order).unwrap(),
+            Side::Ask => book.submit_limit_ask(order).unwrap(),
+        }
+    }
+
+    /// A naive order book that scans every resting order to find the best match, used as a reference model for `Book`
+    #[derive(Debug, Default)]
+    struct ReferenceBook {
+        /// Resting orders in arrival order
+        resting: Vec<(Side, LimitOrder)>,
+    }
+
+    impl ReferenceBook {
+        fn submit(&mut self, side: Side, mut taker: LimitOrder) -> Vec<Execution> {
+            let mut fills = vec![];
+
+            while !taker.is_filled() {
+                let best = self
+                    .resting
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (resting_side, maker))| {
+                        *resting_side != side && crosses(side, taker.price, maker.price)
+                    })
+                    .min_by(|(i, (_, a)), (j, (_, b))| {
+                        let by_price = match side {
+                            Side::Bid => a.price.cmp(&b.price),
+                            Side::Ask => b.price.cmp(&a.price),
+                        };
+                        by_price.then(i.cmp(j))
+                    })
+                    .map(|(i, _)| i);
+
+                let i = match best {
+                    Some(i) => i,
+                    None => break,
+                };
+
+                let maker = &mut self.resting[i].1;
+                let quantity = maker.remaining().min(taker.remaining());
+                maker.fill(quantity);
+                taker.fill(quantity);
+
+                let (bid, ask) = match side {
+                    Side::Bid => (&taker, &*maker),
+                    Side::Ask => (&*maker, &taker),
+                };
+                fills.push(Execution {
+                    bidder_id: bid.owner.clone(),
+                    bidder_order_id: bid.id,
+                    asker_id: ask.owner.clone(),
+                    asker_order_id: ask.id,
+                    quantity,
+                    price: maker.price,
+                });
+
+                if maker.is_filled() {
+                    self.resting.remove(i);
+                }
+            }
+
+            if !taker.is_filled() {
+                self.resting.push((side, taker));
+            }
+
+            fills
+        }
+    }
+
+    fn engine() -> MatchingEngine {
+        let mut engine = MatchingEngine::default();
+        engine.create_symbol(['A', 'D', 'B', 'E'], dec(1), dec(1));
+        engine
+    }
+
+    /// Submit a good till cancel limit order for `ADBE`
+    fn limit(
+        engine: &mut MatchingEngine,
+        owner: &str,
+        side: Side,
+        price: i32,
+        quantity: i32,
+    ) -> (OrderId, Vec<Execution>) {
+        let (id, fills, _) = engine
+            .submit_limit_order(
+                owner.to_string(),
+                side,
+                &['A', 'D', 'B', 'E'],
+                dec(price),
+                dec(quantity),
+                TimeInForce::GoodTillCancel.into(),
+            )
+            .unwrap();
+        (id, fills)
+    }
+
+    #[test]
+    fn immediate_or_cancel_orders_expire_whatever_isnt_filled() {
+        let mut engine = engine();
+        limit(&mut engine, "asker", Side::Ask, 100, 5);
+
+        let (_, fills, expired) = engine
+            .submit_limit_order(
+                "bidder".to_string(),
+                Side::Bid,
+                &['A', 'D', 'B', 'E'],
+                dec(100),
+                dec(8),
+                TimeInForce::ImmediateOrCancel.into(),
+            )
+            .unwrap();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(expired, dec(3));
+
+        let (_, fills) = limit(&mut engine, "asker", Side::Ask, 100, 5);
+        assert!(fills.is_empty(), "immediate or cancel order rested");
+    }
+
+    #[test]
+    fn fill_or_kill_orders_dont_trade_unless_they_can_be_filled_completely() {
+        let mut engine = engine();
+        limit(&mut engine, "asker", Side::Ask, 100, 5);
+        limit(&mut engine, "asker", Side::Ask, 101, 5);
+        limit(&mut engine, "asker", Side::Ask, 102, 5);
+
+        let (_, fills, expired) = engine
+            .submit_limit_order(
+                "bidder".to_string(),
+                Side::Bid,
+                &['A', 'D', 'B', 'E'],
+                dec(101),
+                dec(11),
+                TimeInForce::FillOrKill.into(),
+            )
+            .unwrap();
+        assert!(fills.is_empty());
+        assert_eq!(expired, dec(11));
+
+        let (_, fills, expired) = engine
+            .submit_limit_order(
+                "bidder".to_string(),
+                Side::Bid,
+                &['A', 'D', 'B', 'E'],
+                dec(101),
+                dec(10),
+                TimeInForce::FillOrKill.into(),
+            )
+            .unwrap();
+        assert_eq!(fills.iter().map(|x| x.quantity).sum::<Quantity>(), dec(10));
+        assert_eq!(expired, dec(0));
+    }
+
+    #[test]
+    fn fill_or_kill_orders_dont_count_liquidity_self_trade_prevention_takes_away() {
+        let symbol = ['A', 'D', 'B', 'E'];
+        let fill_or_kill = |engine: &mut MatchingEngine, quantity| {
+            let (_, fills, expired) = engine
+                .submit_limit_order(
+                    "participant".to_string(),
+                    Side::Bid,
+                    &symbol,
+                    dec(101),
+                    dec(quantity),
+                    TimeInForce::FillOrKill.into(),
+                )
+                .unwrap();
+            (fills, expired)
+        };
+
+        // the order would be canceled when it reached its own ask, part way through filling
+        let mut newest = engine();
+        newest.set_self_trade_prevention(
+            "participant".to_string(),
+            Some(SelfTradePrevention::CancelNewest),
+        );
+        limit(&mut newest, "asker", Side::Ask, 100, 5);
+        limit(&mut newest, "participant", Side::Ask, 100, 5);
+        let (fills, expired) = fill_or_kill(&mut newest, 8);
+        assert!(fills.is_empty());
+        assert_eq!(expired, dec(8));
+        assert!(newest.take_prevented_self_trades().is_empty());
+        assert_eq!(
+            newest.depth(&symbol, Side::Ask).unwrap(),
+            vec![(dec(100), dec(10))]
+        );
+
+        // its own ask is canceled rather than traded with, leaving too little to fill it
+        let mut oldest = engine();
+        oldest.set_self_trade_prevention(
+            "participant".to_string(),
+            Some(SelfTradePrevention::CancelOldest),
+        );
+        limit(&mut oldest, "participant", Side::Ask, 100, 5);
+        limit(&mut oldest, "asker", Side::Ask, 101, 5);
+        let (fills, expired) = fill_or_kill(&mut oldest, 8);
+        assert!(fills.is_empty());
+        assert_eq!(expired, dec(8));
+
+        limit(&mut oldest, "asker", Side::Ask, 101, 5);
+        let (fills, expired) = fill_or_kill(&mut oldest, 8);
+        assert_eq!(fills.iter().map(|x| x.quantity).sum::<Quantity>(), dec(8));
+        assert_eq!(expired, dec(0));
+    }
+
+    #[test]
+    fn only_day_orders_expire_at_the_end_of_the_day() {
+        let mut engine = engine();
+        let (good_till_cancel, _) = limit(&mut engine, "bidder", Side::Bid, 100, 5);
+        let (day, _, _) = engine
+            .submit_limit_order(
+                "bidder".to_string(),
+                Side::Bid,
+                &['A', 'D', 'B', 'E'],
+                dec(100),
+                dec(5),
+                TimeInForce::Day.into(),
+            )
+            .unwrap();
+
+        let expired = engine.expire_day_orders();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].2.id(), day);
+
+        assert!(engine
+            .cancel_order("bidder", &['A', 'D', 'B', 'E'], day)
+            .is_err());
+        assert!(engine
+            .cancel_order("bidder", &['A', 'D', 'B', 'E'], good_till_cancel)
+            .is_ok());
+    }
+
+    #[test]
+    fn owner_can_cancel_a_resting_order() {
+        let mut engine = engine();
+        let (id, _) = limit(&mut engine, "bidder", Side::Bid, 100, 10);
+
+        let (symbol, side, order) = engine
+            .cancel_order("bidder", &['A', 'D', 'B', 'E'], id)
+            .unwrap();
+        assert_eq!(symbol, ['A', 'D', 'B', 'E']);
+        assert_eq!(side, Side::Bid);
+        assert_eq!(order.id(), id);
+        assert_eq!(order.remaining(), dec(10));
+
+        let (_, fills) = limit(&mut engine, "asker", Side::Ask, 100, 10);
+        assert!(fills.is_empty(), "canceled order was filled");
+    }
+
+    #[test]
+    fn only_the_owner_can_cancel_an_order() {
+        let mut engine = engine();
+        let (id, _) = limit(&mut engine, "bidder", Side::Bid, 100, 10);
+
+        assert!(engine
+            .cancel_order("someone else", &['A', 'D', 'B', 'E'], id)
+            .is_err());
+        assert!(engine
+            .cancel_order("bidder", &['A', 'D', 'B', 'E'], id)
+            .is_ok());
+    }
+
+    #[test]
+    fn orders_can_only_be_canceled_or_replaced_under_their_own_symbol() {
+        let mut engine = engine();
+        engine.create_symbol(['C', 'O', 'I', 'N'], dec(1), dec(1));
+        let (id, _) = limit(&mut engine, "bidder", Side::Bid, 100, 10);
+
+        assert!(engine
+            .replace_order("bidder", &['C', 'O', 'I', 'N'], id, dec(100), dec(5))
+            .is_err());
+        let e = engine
+            .cancel_order("bidder", &['C', 'O', 'I', 'N'], id)
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("order '{}' is for symbol 'ADBE', not 'COIN'", id)
+        );
+        assert!(engine
+            .cancel_order("bidder", &['A', 'D', 'B', 'E'], id)
+            .is_ok());
+    }
+
+    #[test]
+    fn cant_cancel_a_filled_or_unknown_order() {
+        let mut engine = engine();
+        let (id, _) = limit(&mut engine, "bidder", Side::Bid, 100, 10);
+        limit(&mut engine, "asker", Side::Ask, 100, 10);
+
+        assert!(engine
+            .cancel_order("bidder", &['A', 'D', 'B', 'E'], id)
+            .is_err());
+        assert!(engine
+            .cancel_order("bidder", &['A', 'D', 'B', 'E'], 1000)
+            .is_err());
+    }
+
+    #[test]
+    fn decreasing_quantity_keeps_queue_priority() {
+        let mut engine = engine();
+        let (first, _) = limit(&mut engine, "first", Side::Bid, 100, 10);
+        limit(&mut engine, "second", Side::Bid, 100, 10);
+
+        let replacement = engine
+            .replace_order("first", &['A', 'D', 'B', 'E'], first, dec(100), dec(5))
+            .unwrap();
+        assert!(replacement.executions.is_empty());
+        assert_eq!(replacement.order.remaining(), dec(5));
+
+        let (_, fills) = limit(&mut engine, "asker", Side::Ask, 100, 5);
+        assert_eq!(fills[0].bidder_order_id, first);
+    }
+
+    #[test]
+    fn changing_price_or_increasing_quantity_loses_queue_priority() {
+        for &(price, quantity) in &[(101, 10), (100, 20)] {
+            let mut engine = engine();
+            let (first, _) = limit(&mut engine, "first", Side::Ask, 100, 10);
+            let (second, _) = limit(&mut engine, "second", Side::Ask, 101, 10);
+            engine
+                .replace_order("second", &['A', 'D', 'B', 'E'], second, dec(100), dec(10))
+                .unwrap();
+
+            engine
+                .replace_order(
+                    "first",
+                    &['A', 'D', 'B', 'E'],
+                    first,
+                    dec(price),
+                    dec(quantity),
+                )
+                .unwrap();
+
+            let (_, fills) = limit(&mut engine, "bidder", Side::Bid, 101, 10);
+            assert_eq!(fills[0].asker_order_id, second);
+        }
+    }
+
+    #[test]
+    fn replacing_to_a_crossing_price_trades() {
+        let mut engine = engine();
+        let (ask, _) = limit(&mut engine, "asker", Side::Ask, 100, 10);
+        let (bid, _) = limit(&mut engine, "bidder", Side::Bid, 90, 10);
+
+        let fills = engine
+            .replace_order("bidder", &['A', 'D', 'B', 'E'], bid, dec(100), dec(10))
+            .unwrap()
+            .executions;
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].asker_order_id, ask);
+        assert_eq!(fills[0].price, dec(100));
+        assert!(engine
+            .cancel_order("bidder", &['A', 'D', 'B', 'E'], bid)
+            .is_err());
+    }
+
+    #[test]
+    fn replacements_that_trade_report_what_is_left_after_trading() {
+        let mut engine = engine();
+        limit(&mut engine, "asker", Side::Ask, 100, 4);
+        let (bid, _) = limit(&mut engine, "bidder", Side::Bid, 90, 10);
+
+        let replacement = engine
+            .replace_order("bidder", &['A', 'D', 'B', 'E'], bid, dec(100), dec(10))
+            .unwrap();
+        assert_eq!(replacement.executions[0].quantity, dec(4));
+        assert_eq!(replacement.order.quantity(), dec(10));
+        assert_eq!(replacement.order.remaining(), dec(6));
+    }
+
+    #[test]
+    fn cant_replace_below_the_filled_quantity_or_someone_elses_order() {
+        let mut engine = engine();
+        let (id, _) = limit(&mut engine, "bidder", Side::Bid, 100, 10);
+        limit(&mut engine, "asker", Side::Ask, 100, 4);
+
+        assert!(engine
+            .replace_order("bidder", &['A', 'D', 'B', 'E'], id, dec(100), dec(4))
+            .is_err());
+        assert!(engine
+            .replace_order("asker", &['A', 'D', 'B', 'E'], id, dec(100), dec(8))
+            .is_err());
+
+        let order = engine
+            .replace_order("bidder", &['A', 'D', 'B', 'E'], id, dec(100), dec(8))
+            .unwrap()
+            .order;
+        assert_eq!(order.quantity(), dec(8));
+        assert_eq!(order.remaining(), dec(4));
+    }
+
+    #[test]
+    fn market_orders_sweep_the_book_at_resting_prices() {
+        let mut engine = engine();
+        let symbol = ['A', 'D', 'B', 'E'];
+        limit(&mut engine, "asker", Side::Ask, 101, 5);
+        limit(&mut engine, "asker", Side::Ask, 100, 5);
+        limit(&mut engine, "asker", Side::Ask, 102, 5);
+
+        let (_, fills, canceled) = engine
+            .submit_market_order("bidder".to_string(), Side::Bid, &symbol, dec(12))
+            .unwrap();
+
+        assert_eq!(canceled, dec(0));
+        assert_eq!(
+            fills
+                .iter()
+                .map(|x| (x.price, x.quantity))
+                .collect::<Vec<_>>(),
+            vec![(dec(100), dec(5)), (dec(101), dec(5)), (dec(102), dec(2))]
+        );
+    }
+
+    #[test]
+    fn market_orders_cancel_whatever_cant_be_filled() {
+        let mut engine = engine();
+        let symbol = ['A', 'D', 'B', 'E'];
+        limit(&mut engine, "bidder", Side::Bid, 100, 5);
+
+        let (_, fills, canceled) = engine
+            .submit_market_order("asker".to_string(), Side::Ask, &symbol, dec(8))
+            .unwrap();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(canceled, dec(3));
+
+        // the unfilled remainder must not rest on the book
+        let (_, fills) = limit(&mut engine, "bidder", Side::Bid, 100, 3);
+        assert!(fills.is_empty());
+    }
+
+    #[test]
+    fn cant_submit_a_market_order_for_an_unknown_symbol() {
+        let mut engine = engine();
+        assert!(engine
+            .submit_market_order(
+                "asker".to_string(),
+                Side::Ask,
+                &['C', 'O', 'I', 'N'],
+                dec(8)
+            )
+            .is_err());
+    }
+
+    /// Submit a stop order for `ADBE`
+    fn stop(
+        engine: &mut MatchingEngine,
+        owner: &str,
+        side: Side,
+        stop_price: i32,
+        limit_price: Option<i32>,
+        quantity: i32,
+    ) -> OrderId {
+        engine
+            .submit_stop_order(
+                owner.to_string(),
+                side,
+                &['A', 'D', 'B', 'E'],
+                dec(stop_price),
+                limit_price.map(dec),
+                dec(quantity),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn stop_orders_wait_for_a_trade_at_their_stop_price() {
+        let mut engine = engine();
+        limit(&mut engine, "asker", Side::Ask, 101, 5);
+        limit(&mut engine, "asker", Side::Ask, 102, 5);
+        let id = stop(&mut engine, "stopper", Side::Bid, 101, None, 5);
+
+        limit(&mut engine, "bidder", Side::Bid, 100, 5);
+        limit(&mut engine, "asker", Side::Ask, 100, 5);
+        assert!(engine.take_triggered_orders().is_empty());
+
+        limit(&mut engine, "bidder", Side::Bid, 101, 2);
+        let triggered = engine.take_triggered_orders();
+        assert_eq!(triggered.len(), 1);
+
+        let (_, triggered) = &triggered[0];
+        assert_eq!(triggered.stop.id(), id);
+        assert_eq!(
+            triggered
+                .executions
+                .iter()
+                .map(|x| (x.price, x.quantity))
+                .collect::<Vec<_>>(),
+            vec![(dec(101), dec(3)), (dec(102), dec(2))]
+        );
+        assert_eq!(triggered.canceled_quantity, dec(0));
+    }
+
+    #[test]
+    fn stops_triggered_by_the_same_trade_fill_in_submission_order() {
+        let mut engine = engine();
+        let first = stop(&mut engine, "first", Side::Ask, 98, None, 5);
+        let second = stop(&mut engine, "second", Side::Ask, 99, None, 5);
+        limit(&mut engine, "bidder", Side::Bid, 97, 20);
+        limit(&mut engine, "bidder", Side::Bid, 98, 1);
+
+        limit(&mut engine, "asker", Side::Ask, 98, 1);
+
+        let triggered: Vec<_> = engine
+            .take_triggered_orders()
+            .into_iter()
+            .map(|(_, x)| x.stop.id())
+            .collect();
+        assert_eq!(triggered, vec![first, second]);
+    }
+
+    #[test]
+    fn trades_made_by_triggered_stops_can_trigger_more_stops() {
+        let mut engine = engine();
+        limit(&mut engine, "asker", Side::Ask, 100, 1);
+        limit(&mut engine, "asker", Side::Ask, 105, 5);
+        limit(&mut engine, "asker", Side::Ask, 110, 5);
+        let first = stop(&mut engine, "first", Side::Bid, 100, Some(105), 5);
+        let second = stop(&mut engine, "second", Side::Bid, 105, None, 5);
+
+        limit(&mut engine, "bidder", Side::Bid, 100, 1);
+
+        let triggered = engine.take_triggered_orders();
+        assert_eq!(triggered.len(), 2);
+        assert_eq!(triggered[0].1.stop.id(), first);
+        assert_eq!(triggered[0].1.executions[0].price, dec(105));
+        assert_eq!(triggered[1].1.stop.id(), second);
+        assert_eq!(triggered[1].1.executions[0].price, dec(110));
+    }
+
+    #[test]
+    fn triggered_stop_limit_orders_rest_whatever_isnt_filled() {
+        let mut engine = engine();
+        limit(&mut engine, "asker", Side::Ask, 100, 5);
+        let id = stop(&mut engine, "stopper", Side::Bid, 100, Some(100), 10);
+
+        limit(&mut engine, "bidder", Side::Bid, 100, 1);
+
+        let triggered = engine.take_triggered_orders();
+        assert_eq!(triggered[0].1.executions[0].quantity, dec(4));
+        let (_, _, order) = engine
+            .cancel_order("stopper", &['A', 'D', 'B', 'E'], id)
+            .unwrap();
+        assert_eq!(order.remaining(), dec(6));
+        assert_eq!(order.price(), dec(100));
+    }
+
+    #[test]
+    fn stops_passed_part_way_through_a_sweep_are_triggered() {
+        let mut engine = engine();
+        limit(&mut engine, "asker", Side::Ask, 100, 1);
+        limit(&mut engine, "asker", Side::Ask, 101, 1);
+        limit(&mut engine, "asker", Side::Ask, 102, 1);
+        let id = stop(&mut engine, "stopper", Side::Ask, 101, None, 5);
+
+        // trades at 100, 101 and then 102, only the last of which is above the sell stop
+        limit(&mut engine, "bidder", Side::Bid, 102, 3);
+
+        let triggered = engine.take_triggered_orders();
+        assert_eq!(triggered.len(), 1);
+        assert_eq!(triggered[0].1.stop.id(), id);
+    }
+
+    #[test]
+    fn stops_trigger_right_away_if_the_last_trade_already_reached_them() {
+        let mut engine = engine();
+        limit(&mut engine, "asker", Side::Ask, 100, 1);
+        limit(&mut engine, "bidder", Side::Bid, 100, 1);
+
+        let id = stop(&mut engine, "stopper", Side::Ask, 101, None, 5);
+
+        let triggered = engine.take_triggered_orders();
+        assert_eq!(triggered[0].1.stop.id(), id);
+        assert_eq!(triggered[0].1.canceled_quantity, dec(5));
+    }
+
+    #[test]
+    fn untriggered_stops_can_be_canceled_by_their_owner() {
+        let mut engine = engine();
+        let id = stop(&mut engine, "stopper", Side::Bid, 101, None, 5);
+
+        assert!(engine
+            .cancel_order("bidder", &['A', 'D', 'B', 'E'], id)
+            .is_err());
+        assert!(engine
+            .replace_order("stopper", &['A', 'D', 'B', 'E'], id, dec(100), dec(5))
+            .is_err());
+        engine
+            .cancel_order("stopper", &['A', 'D', 'B', 'E'], id)
+            .unwrap();
+
+        limit(&mut engine, "asker", Side::Ask, 101, 1);
+        limit(&mut engine, "bidder", Side::Bid, 101, 1);
+        assert!(engine.take_triggered_orders().is_empty());
+    }
+
+    /// Submit a good till cancel iceberg order for `ADBE`
+    fn iceberg(
+        engine: &mut MatchingEngine,
+        owner: &str,
+        side: Side,
+        price: i32,
+        quantity: i32,
+        display_quantity: i32,
+    ) -> Result<OrderId, Box<dyn std::error::Error>> {
+        let options = LimitOrderOptions {
+            time_in_force: TimeInForce::GoodTillCancel,
+            display_quantity: Some(dec(display_quantity)),
+            ..LimitOrderOptions::default()
+        };
+
+        engine
+            .submit_limit_order(
+                owner.to_string(),
+                side,
+                &['A', 'D', 'B', 'E'],
+                dec(price),
+                dec(quantity),
+                options,
+            )
+            .map(|(id, _, _)| id)
+    }
+
+    #[test]
+    fn only_the_displayed_slice_of_an_iceberg_order_shows_in_the_book() {
+        let mut engine = engine();
+        iceberg(&mut engine, "iceberg", Side::Ask, 101, 100, 10).unwrap();
+        limit(&mut engine, "asker", Side::Ask, 101, 5);
+        limit(&mut engine, "asker", Side::Ask, 102, 5);
+        iceberg(&mut engine, "iceberg", Side::Bid, 100, 50, 5).unwrap();
+
+        let symbol = ['A', 'D', 'B', 'E'];
+        assert_eq!(
+            engine.depth(&symbol, Side::Ask).unwrap(),
+            vec![(dec(101), dec(15)), (dec(102), dec(5))]
+        );
+        assert_eq!(
+            engine.depth(&symbol, Side::Bid).unwrap(),
+            vec![(dec(100), dec(5))]
+        );
+
+        // the first slice and part of the plain order fill, the iceberg shows its next slice
+        limit(&mut engine, "bidder", Side::Bid, 101, 12);
+        assert_eq!(
+            engine.depth(&symbol, Side::Ask).unwrap(),
+            vec![(dec(101), dec(13)), (dec(102), dec(5))]
+        );
+    }
+
+    #[test]
+    fn refreshed_iceberg_slices_go_to_the_back_of_the_queue() {
+        let mut engine = engine();
+        let iceberg_id = iceberg(&mut engine, "iceberg", Side::Ask, 100, 10, 2).unwrap();
+        let (resting, _) = limit(&mut engine, "asker", Side::Ask, 100, 5);
+
+        let (_, fills) = limit(&mut engine, "bidder", Side::Bid, 100, 3);
+        assert_eq!(
+            fills
+                .iter()
+                .map(|x| (x.asker_order_id, x.quantity))
+                .collect::<Vec<_>>(),
+            vec![(iceberg_id, dec(2)), (resting, dec(1))]
+        );
+    }
+
+    #[test]
+    fn the_reserve_of_an_iceberg_order_can_be_filled() {
+        let mut engine = engine();
+        let iceberg_id = iceberg(&mut engine, "iceberg", Side::Ask, 100, 10, 2).unwrap();
+        let (resting, _) = limit(&mut engine, "asker", Side::Ask, 100, 5);
+
+        let (_, fills) = limit(&mut engine, "bidder", Side::Bid, 100, 15);
+        assert_eq!(
+            fills
+                .iter()
+                .map(|x| (x.asker_order_id, x.quantity))
+                .collect::<Vec<_>>(),
+            vec![
+                (iceberg_id, dec(2)),
+                (resting, dec(5)),
+                (iceberg_id, dec(2)),
+                (iceberg_id, dec(2)),
+                (iceberg_id, dec(2)),
+                (iceberg_id, dec(2))
+            ]
+        );
+        assert!(engine
+            .depth(&['A', 'D', 'B', 'E'], Side::Ask)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn display_quantity_must_be_within_the_order_quantity() {
+        let mut engine = engine();
+        assert!(iceberg(&mut engine, "iceberg", Side::Ask, 100, 10, 0).is_err());
+        assert!(iceberg(&mut engine, "iceberg", Side::Ask, 100, 10, 11).is_err());
+        assert!(iceberg(&mut engine, "iceberg", Side::Ask, 100, 10, 10).is_ok());
+    }
+
+    #[test]
+    fn post_only_orders_are_rejected_instead_of_taking_liquidity() {
+        let mut engine = engine();
+        limit(&mut engine, "asker", Side::Ask, 100, 5);
+        let options = LimitOrderOptions {
+            post_only: true,
+            ..LimitOrderOptions::default()
+        };
+
+        let symbol = ['A', 'D', 'B', 'E'];
+        assert!(engine
+            .submit_limit_order(
+                "bidder".to_string(),
+                Side::Bid,
+                &symbol,
+                dec(100),
+                dec(5),
+                options
+            )
+            .is_err());
+        assert!(engine
+            .submit_limit_order(
+                "bidder".to_string(),
+                Side::Bid,
+                &symbol,
+                dec(99),
+                dec(5),
+                options
+            )
+            .is_ok());
+        assert_eq!(
+            engine.depth(&symbol, Side::Ask).unwrap(),
+            vec![(dec(100), dec(5))]
+        );
+    }
+
+    #[test]
+    fn post_only_orders_can_cross_their_owners_orders_with_self_trade_prevention() {
+        let mut engine = engine();
+        engine.set_self_trade_prevention(
+            "participant".to_string(),
+            Some(SelfTradePrevention::CancelOldest),
+        );
+        limit(&mut engine, "participant", Side::Ask, 100, 5);
+        let options = LimitOrderOptions {
+            post_only: true,
+            ..LimitOrderOptions::default()
+        };
+
+        let symbol = ['A', 'D', 'B', 'E'];
+        let (_, fills, _) = engine
+            .submit_limit_order(
+                "participant".to_string(),
+                Side::Bid,
+                &symbol,
+                dec(100),
+                dec(5),
+                options,
+            )
+            .unwrap();
+        assert!(fills.is_empty());
+        assert_eq!(
+            engine.depth(&symbol, Side::Bid).unwrap(),
+            vec![(dec(100), dec(5))]
+        );
+    }
+
+    /// Rest a bid from `participant` and send a crossing ask from them, returning what was prevented
+    fn self_trade(
+        mode: SelfTradePrevention,
+        resting_quantity: i32,
+        incoming_quantity: i32,
+    ) -> (
+        MatchingEngine,
+        Vec<Execution>,
+        Vec<(OrderId, Quantity, Quantity)>,
+    ) {
+        let mut engine = engine();
+        engine.set_self_trade_prevention("participant".to_string(), Some(mode));
+        limit(&mut engine, "participant", Side::Bid, 100, resting_quantity);
+        let (_, fills) = limit(
+            &mut engine,
+            "participant",
+            Side::Ask,
+            100,
+            incoming_quantity,
+        );
+
+        let prevented = engine
+            .take_prevented_self_trades()
+            .into_iter()
+            .map(|(_, x)| (x.order.id(), x.canceled_quantity, x.order.remaining()))
+            .collect();
+        (engine, fills, prevented)
+    }
+
+    #[test]
+    fn self_trade_prevention_modes_cancel_the_right_orders() {
+        let (_, fills, prevented) = self_trade(SelfTradePrevention::CancelNewest, 5, 3);
+        assert!(fills.is_empty());
+        assert_eq!(prevented, vec![(1, dec(3), dec(0))]);
+
+        let (mut engine, fills, prevented) = self_trade(SelfTradePrevention::CancelOldest, 5, 3);
+        assert!(fills.is_empty());
+        assert_eq!(prevented, vec![(0, dec(5), dec(0))]);
+        assert!(engine
+            .cancel_order("participant", &['A', 'D', 'B', 'E'], 1)
+            .is_ok());
+
+        let (engine, fills, prevented) = self_trade(SelfTradePrevention::CancelBoth, 5, 3);
+        assert!(fills.is_empty());
+        assert_eq!(prevented, vec![(0, dec(5), dec(0)), (1, dec(3), dec(0))]);
+        assert!(engine
+            .depth(&['A', 'D', 'B', 'E'], Side::Bid)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn decrementing_cancels_the_smaller_order_and_restates_the_larger_one() {
+        let (mut engine, fills, prevented) = self_trade(SelfTradePrevention::Decrement, 5, 3);
+        assert!(fills.is_empty());
+        assert_eq!(prevented, vec![(0, dec(3), dec(2)), (1, dec(3), dec(0))]);
+
+        let (_, _, order) = engine
+            .cancel_order("participant", &['A', 'D', 'B', 'E'], 0)
+            .unwrap();
+        assert_eq!(order.quantity(), dec(2));
+    }
+
+    #[test]
+    fn self_trade_prevention_only_applies_to_the_same_owner() {
+        let mut engine = engine();
+        engine.set_self_trade_prevention(
+            "participant".to_string(),
+            Some(SelfTradePrevention::CancelNewest),
+        );
+        limit(&mut engine, "participant", Side::Bid, 101, 5);
+        limit(&mut engine, "other", Side::Bid, 100, 5);
+
+        let (_, fills) = limit(&mut engine, "participant", Side::Ask, 100, 10);
+        assert_eq!(fills.len(), 0);
+        assert_eq!(engine.take_prevented_self_trades().len(), 1);
+
+        let (_, fills) = limit(&mut engine, "third", Side::Ask, 100, 10);
+        assert_eq!(fills.len(), 2);
+    }
+
+    #[test]
+    fn orders_can_override_their_owners_self_trade_prevention() {
+        let mut engine = engine();
+        engine.set_self_trade_prevention(
+            "participant".to_string(),
+            Some(SelfTradePrevention::CancelNewest),
+        );
+        limit(&mut engine, "participant", Side::Bid, 100, 5);
+
+        let options = LimitOrderOptions {
+            self_trade_prevention: Some(SelfTradePrevention::CancelOldest),
+            ..LimitOrderOptions::default()
+        };
+        let (id, _, _) = engine
+            .submit_limit_order(
+                "participant".to_string(),
+                Side::Ask,
+                &['A', 'D', 'B', 'E'],
+                dec(100),
+                dec(5),
+                options,
+            )
+            .unwrap();
+
+        let prevented = engine.take_prevented_self_trades();
+        assert_eq!(prevented.len(), 1);
+        assert_eq!(prevented[0].1.order.id(), 0);
+        assert!(engine
+            .cancel_order("participant", &['A', 'D', 'B', 'E'], id)
+            .is_ok());
+    }
+
+    #[test]
+    fn prices_and_quantities_must_be_whole_ticks_and_lots() {
+        let mut engine = MatchingEngine::default();
+        let symbol = ['C', 'O', 'I', 'N'];
+        engine.create_symbol(symbol, "0.01".parse().unwrap(), dec(10));
+
+        let mut submit = |price: &str, quantity: i32| {
+            engine.submit_limit_order(
+                "bidder".to_string(),
+                Side::Bid,
+                &symbol,
+                price.parse().unwrap(),
+                dec(quantity),
+                LimitOrderOptions::default(),
+            )
+        };
+        assert!(submit("12.755", 10).is_err());
+        assert!(submit("0", 10).is_err());
+        assert!(submit("12.75", 15).is_err());
+        assert!(submit("12.75", 0).is_err());
+        assert!(submit("12.75", 20).is_ok());
+
+        assert_eq!(
+            engine.depth(&symbol, Side::Bid).unwrap(),
+            vec![("12.75".parse().unwrap(), dec(20))]
+        );
+    }
+
+    #[test]
+    fn orders_too_large_to_value_are_rejected() {
+        let mut engine = MatchingEngine::default();
+        let symbol = ['C', 'O', 'I', 'N'];
+        engine.create_symbol(symbol, "0.01".parse().unwrap(), dec(10));
+
+        let mut submit = |price: &str, quantity: &str| {
+            engine.submit_limit_order(
+                "bidder".to_string(),
+                Side::Bid,
+                &symbol,
+                price.parse().unwrap(),
+                quantity.parse().unwrap(),
+                LimitOrderOptions::default(),
+            )
+        };
+        assert!(submit("1000000", "1000000").is_err());
+        assert!(submit("0.01", "90000000000").is_ok());
+        assert!(submit("0.01", "90000000000").is_ok());
+
+        // together they're more than a quantity can hold
+        assert_eq!(
+            engine.depth(&symbol, Side::Bid).unwrap(),
+            vec![("0.01".parse().unwrap(), Quantity::MAX)]
+        );
+    }
+
+    quickcheck! {
+        fn canceled_orders_are_removed_from_the_book(generated: Vec<(bool, u8, u8)>, to_cancel: Vec<usize>) -> bool {
+            let orders = orders(generated);
+            let mut book = Book::default();
+            for (side, order) in orders.iter().cloned() {
+                submit(&mut book, side, order);
+            }
+
+            for id in to_cancel.into_iter().filter(|_| !orders.is_empty()).map(|x| x % orders.len()) {
+                let was_resting = book.order(id).is_some();
+                if book.cancel(id).is_some() != was_resting || book.order(id).is_some() {
+                    return false;
+                }
+            }
+
+            let all_indexed = book.bids().chain(book.asks()).all(|x| book.order(x.id()).is_some());
+            all_indexed
+        }
+
+        fn book_matches_reference_model(generated: Vec<(bool, u8, u8)>) -> bool {
+            let mut book = Book::default();
+            let mut reference = ReferenceBook::default();
+
+            orders(generated).into_iter().all(|(side, order)| {
+                submit(&mut book, side, order.clone()) == reference.submit(side, order)
+            })
+        }
+
+        fn never_trades_at_a_non_crossing_price(generated: Vec<(bool, u8, u8)>) -> bool {
+            let mut book = Book::default();
+            let mut limits = HashMap::new();
+
+            orders(generated).into_iter().all(|(side, order)| {
+                limits.insert(order.id, order.price());
+                let fills = submit(&mut book, side, order);
+
+                let fills_cross = fills.iter().all(|fill| {
+                    limits[&fill.bidder_order_id] >= fill.price && fill.price >= limits[&fill.asker_order_id]
+                });
+
+                let best_bid = book.bids().map(LimitOrder::price).max();
+                let best_ask = book.asks().map(LimitOrder::price).min();
+                let book_uncrossed = match (best_bid, best_ask) {
+                    (Some(bid), Some(ask)) => bid < ask,
+                    _ => true,
+                };
+
+                fills_cross && book_uncrossed
+            })
+        }
+
+        fn trades_execute_at_the_resting_orders_price(resting_price: u16, incoming_price: u16, is_bid: bool) -> bool {
+            let (bid_price, ask_price) = (positive_price(resting_price.max(incoming_price)), positive_price(resting_price.min(incoming_price)));
+            let mut book = Book::default();
+
+            let fills = if is_bid {
+                book.submit_limit_bid(LimitOrder::new(0, "maker".to_string(), dec(1), bid_price)).unwrap();
+                book.submit_limit_ask(LimitOrder::new(1, "taker".to_string(), dec(1), ask_price)).unwrap()
+            } else {
+                book.submit_limit_ask(LimitOrder::new(0, "maker".to_string(), dec(1), ask_price)).unwrap();
+                book.submit_limit_bid(LimitOrder::new(1, "taker".to_string(), dec(1), bid_price)).unwrap()
+            };
+
+            let expected_price = if is_bid { bid_price } else { ask_price };
+            fills.len() == 1 && fills[0].price == expected_price
+        }
+
+        fn bids_at_the_same_price_fill_in_arrival_order(quantities: Vec<u8>, price: u16) -> bool {
+            let quantities = non_empty(quantities);
+            let price = positive_price(price);
+            let mut book = Book::default();
+
+            for (id, &quantity) in quantities.iter().enumerate() {
+                let fills = book
+                    .submit_limit_bid(LimitOrder::new(id, format!("bidder{}", id), quantity, price))
+                    .unwrap();
+                assert!(fills.is_empty());
+            }
+
+            let ask = LimitOrder::new(quantities.len(), "asker".to_string(), quantities.iter().sum(), price);
+            let fills = book.submit_limit_ask(ask).unwrap();
+
+            fills.iter().map(|x| x.bidder_order_id).eq(0..quantities.len())
+                && fills.iter().map(|x| x.quantity).eq(quantities.iter().copied())
+        }
+
+        fn asks_at_the_same_price_fill_in_arrival_order(quantities: Vec<u8>, price: u16) -> bool {
+            let quantities = non_empty(quantities);
+            let price = positive_price(price);
+            let mut book = Book::default();
+
+            for (id, &quantity) in quantities.iter().enumerate() {
+                let fills = book
+                    .submit_limit_ask(LimitOrder::new(id, format!("asker{}", id), quantity, price))
+                    .unwrap();
+                assert!(fills.is_empty());
+            }
+
+            let bid = LimitOrder::new(quantities.len(), "bidder".to_string(), quantities.iter().sum(), price);
+            let fills = book.submit_limit_bid(bid).unwrap();
+
+            fills.iter().map(|x| x.asker_order_id).eq(0..quantities.len())
+                && fills.iter().map(|x| x.quantity).eq(quantities.iter().copied())
+        }
+
+        fn partially_filled_orders_keep_their_place_in_the_queue(resting: Vec<u8>, incoming: Vec<u8>, price: u16) -> bool {
+            let resting = non_empty(resting);
+            let incoming = non_empty(incoming);
+            let price = positive_price(price);
+            let mut book = Book::default();
+
+            for (id, &quantity) in resting.iter().enumerate() {
+                book.submit_limit_bid(LimitOrder::new(id, format!("bidder{}", id), quantity, price))
+                    .unwrap();
+            }
+
+            let mut filled_ids = vec![];
+            for (id, &quantity) in incoming.iter().enumerate() {
+                let ask = LimitOrder::new(resting.len() + id, format!("asker{}", id), quantity, price);
+                filled_ids.extend(book.submit_limit_ask(ask).unwrap().into_iter().map(|x| x.bidder_order_id));
+            }
+
+            filled_ids.windows(2).all(|x| x[0] <= x[1])
+        }
     }
 }
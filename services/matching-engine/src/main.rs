@@ -1,120 +1,521 @@
 #![deny(clippy::all)]
 mod config;
 
-use futures::sink::SinkExt;
+use futures::sink::{Sink, SinkExt};
 use futures::stream::StreamExt;
 use matchbook_types::*;
 use matchbook_util::*;
 use matching_engine::*;
 use tracing::*;
 
+/// Number of requests remembered so that a request arriving again isn't acted on twice
+const DEDUPE_CAPACITY: usize = 100_000;
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
     let config = config::source_config_from_env()?;
 
-    let (mut sink, mut stream) = make_matchbook_streams(config.multicast_addr)?;
+    let (sink, stream) = make_matchbook_streams(config.multicast_addr)?;
+    let (retransmit_tx, mut retransmit_rx) = futures::channel::mpsc::unbounded();
+    let mut stream = GapDetector::new(stream, retransmit_tx);
+    let mut publisher = Publisher {
+        sink,
+        sequencer: Sequencer::open(&config.sequence_journal)?,
+        service_id: config.service_id,
+    };
 
     let mut engine = MatchingEngine::default();
+    let mut dedupe = Dedupe::new(DEDUPE_CAPACITY);
 
-    engine.create_symbol(['A', 'D', 'B', 'E']);
-    engine.create_symbol(['C', 'O', 'I', 'N']);
+    // prices in cents, quantities in whole shares
+    engine.create_symbol(['A', 'D', 'B', 'E'], "0.01".parse()?, Quantity::from(1));
+    engine.create_symbol(['C', 'O', 'I', 'N'], "0.01".parse()?, Quantity::from(1));
     debug!("loaded symbols");
 
+    for (participant, mode) in config.self_trade_prevention.iter().cloned() {
+        engine.set_self_trade_prevention(participant, Some(mode));
+    }
+
     loop {
-        match stream.next().await {
-            Some(Ok(message)) => {
-                #[allow(clippy::single_match)]
-                match message.kind {
-                    MessageKind::LimitOrderSubmitRequest {
+        let message = tokio::select! {
+            message = stream.next() => message,
+            Some(request) = retransmit_rx.next() => {
+                publisher.sink.send(request).await?;
+                continue;
+            }
+            _ = tokio::time::sleep(until_day_end(config.day_end)) => {
+                info!("end of day, expiring day orders");
+                for (symbol, side, order) in engine.expire_day_orders() {
+                    let kind = MessageKind::OrderExpired {
+                        id: order.id(),
+                        side,
+                        quantity: order.quantity(),
+                        expired_quantity: order.remaining(),
+                        symbol,
+                    };
+
+                    publisher.publish(order.owner().to_string(), kind).await?;
+                }
+                continue;
+            }
+        };
+
+        if let Some(Ok(message)) = &message {
+            match dedupe.check(message) {
+                Check::Handle => {}
+                Check::Ignore => continue,
+                Check::Respond(response) => {
+                    let response = Message {
+                        replay: true,
+                        ..response
+                    };
+                    publisher.sink.send(response).await?;
+                    continue;
+                }
+            }
+        }
+
+        match message {
+            Some(Ok(message)) => match message.kind {
+                MessageKind::LimitOrderSubmitRequest {
+                    quantity,
+                    price,
+                    symbol,
+                    side,
+                    time_in_force,
+                    display_quantity,
+                    post_only,
+                    self_trade_prevention,
+                } => {
+                    info!(
+                        ?message.id,
+                        ?side,
+                        %quantity,
+                        ?symbol,
+                        %price,
+                        ?time_in_force,
+                        ?display_quantity,
+                        post_only,
+                        ?self_trade_prevention,
+                        "received limit order open request"
+                    );
+                    let owner = message.id.topic_id.clone();
+                    let (id, executions, expired_quantity) = match engine.submit_limit_order(
+                        owner.clone(),
+                        side,
+                        &symbol,
+                        price,
+                        quantity,
+                        LimitOrderOptions {
+                            time_in_force,
+                            display_quantity,
+                            post_only,
+                            self_trade_prevention,
+                        },
+                    ) {
+                        Ok(result) => result,
+                        Err(e) => {
+                            warn!("failed to submit order {}", e);
+                            let kind = MessageKind::LimitOrderSubmitReject {
+                                side,
+                                price,
+                                quantity,
+                                symbol,
+                                reason: e.to_string(),
+                            };
+
+                            let response = publisher.publish(owner, kind).await?;
+                            dedupe.respond(&message.id, response);
+                            continue;
+                        }
+                    };
+
+                    let kind = MessageKind::LimitOrderSubmitRequestAcknowledge {
+                        id,
                         quantity,
                         price,
                         symbol,
                         side,
-                    } => {
+                    };
+
+                    let response = publisher.publish(owner.clone(), kind).await?;
+                    dedupe.respond(&message.id, response);
+
+                    for execution in executions {
+                        info!(?execution, "reporting execution");
+                        for (topic_id, kind) in execution_messages(symbol, execution) {
+                            publisher.publish(topic_id, kind).await?;
+                        }
+                    }
+
+                    if expired_quantity > Quantity::ZERO {
                         info!(
-                            ?message.id,
-                            ?side,
-                            quantity,
-                            ?symbol,
-                            price,
-                            "received limit order open request"
+                            id,
+                            %expired_quantity,
+                            ?time_in_force,
+                            "expiring unfilled order quantity"
                         );
-                        let (id, executions) = match engine.submit_limit_order(
-                            message.id.topic_id.clone(),
+                        let kind = MessageKind::OrderExpired {
+                            id,
                             side,
-                            &symbol,
-                            price,
                             quantity,
-                        ) {
-                            Ok(fills) => fills,
+                            expired_quantity,
+                            symbol,
+                        };
+
+                        publisher.publish(owner, kind).await?;
+                    }
+                }
+                MessageKind::MarketOrderSubmitRequest {
+                    quantity,
+                    symbol,
+                    side,
+                } => {
+                    info!(
+                        ?message.id,
+                        ?side,
+                        %quantity,
+                        ?symbol,
+                        "received market order request"
+                    );
+                    let owner = message.id.topic_id.clone();
+                    let (id, executions, canceled_quantity) =
+                        match engine.submit_market_order(owner.clone(), side, &symbol, quantity) {
+                            Ok(result) => result,
                             Err(e) => {
                                 warn!("failed to submit order {}", e);
+                                let kind = MessageKind::MarketOrderSubmitReject {
+                                    side,
+                                    quantity,
+                                    symbol,
+                                    reason: e.to_string(),
+                                };
+
+                                let response = publisher.publish(owner, kind).await?;
+                                dedupe.respond(&message.id, response);
                                 continue;
                             }
                         };
 
-                        let acknowledge_message = Message {
-                            kind: MessageKind::LimitOrderSubmitRequestAcknowledge {
-                                id,
+                    let kind = MessageKind::MarketOrderSubmitRequestAcknowledge {
+                        id,
+                        quantity,
+                        symbol,
+                        side,
+                    };
+
+                    let response = publisher.publish(owner.clone(), kind).await?;
+                    dedupe.respond(&message.id, response);
+
+                    for execution in executions {
+                        info!(?execution, "reporting execution");
+                        for (topic_id, kind) in execution_messages(symbol, execution) {
+                            publisher.publish(topic_id, kind).await?;
+                        }
+                    }
+
+                    if canceled_quantity > Quantity::ZERO {
+                        info!(
+                            id,
+                            %canceled_quantity, "canceling unfilled market order quantity"
+                        );
+                        let kind = MessageKind::OrderCanceled {
+                            id,
+                            side,
+                            quantity,
+                            canceled_quantity,
+                            symbol,
+                        };
+
+                        publisher.publish(owner, kind).await?;
+                    }
+                }
+                MessageKind::StopOrderSubmitRequest {
+                    side,
+                    stop_price,
+                    limit_price,
+                    quantity,
+                    symbol,
+                } => {
+                    info!(
+                        ?message.id,
+                        ?side,
+                        %stop_price,
+                        ?limit_price,
+                        %quantity,
+                        ?symbol,
+                        "received stop order request"
+                    );
+                    let id = match engine.submit_stop_order(
+                        message.id.topic_id.clone(),
+                        side,
+                        &symbol,
+                        stop_price,
+                        limit_price,
+                        quantity,
+                    ) {
+                        Ok(id) => id,
+                        Err(e) => {
+                            warn!("failed to submit order {}", e);
+                            let kind = MessageKind::StopOrderSubmitReject {
+                                side,
+                                stop_price,
+                                limit_price,
                                 quantity,
-                                price,
                                 symbol,
-                                side,
-                            },
-                            ..message
-                        };
+                                reason: e.to_string(),
+                            };
 
-                        sink.send(acknowledge_message).await?;
-
-                        for execution in executions {
-                            info!(?execution, "reporting execution");
-                            let bidder_message = Message {
-                                id: MessageId {
-                                    publisher_id: config.service_id,
-                                    topic_id: execution.bidder_id,
-                                    // TODO(will): the matching engine needs to keep track internally what the current
-                                    //              sequence number for each topic is. There's currently no way to know
-                                    //              what this sequence number should be
-                                    topic_sequence_n: 0,
-                                },
-                                kind: MessageKind::Execution {
-                                    id: execution.bidder_order_id,
-                                    price: execution.price,
-                                    quantity: execution.quantity,
-                                    side: Side::Bid,
-                                    symbol,
-                                },
+                            let response =
+                                publisher.publish(message.id.topic_id.clone(), kind).await?;
+                            dedupe.respond(&message.id, response);
+                            continue;
+                        }
+                    };
+
+                    let kind = MessageKind::StopOrderSubmitRequestAcknowledge {
+                        id,
+                        side,
+                        stop_price,
+                        limit_price,
+                        quantity,
+                        symbol,
+                    };
+
+                    let response = publisher.publish(message.id.topic_id.clone(), kind).await?;
+                    dedupe.respond(&message.id, response);
+                }
+                MessageKind::CancelRequest { id, symbol } => {
+                    info!(?message.id, id, ?symbol, "received cancel request");
+                    let kind = match engine.cancel_order(&message.id.topic_id, &symbol, id) {
+                        Ok((symbol, side, order)) => MessageKind::CancelAcknowledge {
+                            id,
+                            side,
+                            price: order.price(),
+                            quantity: order.quantity(),
+                            canceled_quantity: order.remaining(),
+                            symbol,
+                        },
+                        Err(e) => {
+                            warn!("failed to cancel order {}", e);
+                            MessageKind::CancelReject {
+                                id,
+                                reason: e.to_string(),
+                            }
+                        }
+                    };
+
+                    let response = publisher.publish(message.id.topic_id.clone(), kind).await?;
+                    dedupe.respond(&message.id, response);
+                }
+                MessageKind::ReplaceRequest {
+                    id,
+                    price,
+                    quantity,
+                    symbol,
+                } => {
+                    info!(?message.id, id, %price, %quantity, ?symbol, "received replace request");
+                    match engine.replace_order(&message.id.topic_id, &symbol, id, price, quantity) {
+                        Ok(Replacement {
+                            symbol,
+                            side,
+                            order,
+                            executions,
+                        }) => {
+                            let kind = MessageKind::ReplaceAcknowledge {
+                                id,
+                                side,
+                                price,
+                                quantity,
+                                leaves_quantity: order.remaining(),
+                                symbol,
                             };
-                            let asker_message = Message {
-                                id: MessageId {
-                                    publisher_id: config.service_id,
-                                    topic_id: execution.asker_id,
-                                    // TODO(will): the matching engine needs to keep track internally what the current
-                                    //              sequence number for each topic is. There's currently no way to know
-                                    //              what this sequence number should be
-                                    topic_sequence_n: 0,
-                                },
-                                kind: MessageKind::Execution {
-                                    id: execution.asker_order_id,
-                                    price: execution.price,
-                                    side: Side::Ask,
-                                    quantity: execution.quantity,
-                                    symbol,
-                                },
+
+                            let response =
+                                publisher.publish(message.id.topic_id.clone(), kind).await?;
+                            dedupe.respond(&message.id, response);
+
+                            for execution in executions {
+                                info!(?execution, "reporting execution");
+                                for (topic_id, kind) in execution_messages(symbol, execution) {
+                                    publisher.publish(topic_id, kind).await?;
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            warn!("failed to replace order {}", e);
+                            let kind = MessageKind::ReplaceReject {
+                                id,
+                                reason: e.to_string(),
                             };
 
-                            sink.send(bidder_message).await.unwrap();
-                            sink.send(asker_message).await.unwrap();
+                            let response =
+                                publisher.publish(message.id.topic_id.clone(), kind).await?;
+                            dedupe.respond(&message.id, response);
                         }
                     }
-                    _ => {}
                 }
-            }
+                _ => {}
+            },
             Some(Err(e)) => warn!("{}", e),
             None => break,
         }
+
+        for (symbol, prevented) in engine.take_prevented_self_trades() {
+            info!(id = prevented.order.id(), "prevented self trade");
+            let (topic_id, kind) = prevented_self_trade_message(symbol, prevented);
+            publisher.publish(topic_id, kind).await?;
+        }
+
+        // stop orders triggered by whatever traded are reported after the message that triggered them
+        for (symbol, triggered) in engine.take_triggered_orders() {
+            info!(id = triggered.stop.id(), "stop order triggered");
+            for (topic_id, kind) in triggered_messages(symbol, triggered) {
+                publisher.publish(topic_id, kind).await?;
+            }
+        }
     }
 
     Ok(())
 }
+
+/// Sends the engine's messages, numbering each one on its topic
+struct Publisher<S> {
+    sink: S,
+    sequencer: Sequencer,
+    service_id: ServiceId,
+}
+
+impl<S> Publisher<S>
+where
+    S: Sink<Message, Error = std::io::Error> + Unpin,
+{
+    async fn publish(
+        &mut self,
+        topic_id: ParticipantId,
+        kind: MessageKind,
+    ) -> Result<Message, Box<dyn std::error::Error>> {
+        let message = Message {
+            id: self.sequencer.next_id(self.service_id, topic_id)?,
+            kind,
+            replay: false,
+        };
+        self.sink.send(message.clone()).await?;
+        Ok(message)
+    }
+}
+
+/// Build the messages reporting an execution to both of the participants involved
+fn execution_messages(
+    symbol: SymbolOwned,
+    execution: Execution,
+) -> Vec<(ParticipantId, MessageKind)> {
+    let bidder_message = MessageKind::Execution {
+        id: execution.bidder_order_id,
+        price: execution.price,
+        quantity: execution.quantity,
+        side: Side::Bid,
+        symbol,
+    };
+    let asker_message = MessageKind::Execution {
+        id: execution.asker_order_id,
+        price: execution.price,
+        side: Side::Ask,
+        quantity: execution.quantity,
+        symbol,
+    };
+
+    vec![
+        (execution.bidder_id, bidder_message),
+        (execution.asker_id, asker_message),
+    ]
+}
+
+/// Build the message reporting that an order was canceled or restated to prevent a self trade
+fn prevented_self_trade_message(
+    symbol: SymbolOwned,
+    prevented: PreventedSelfTrade,
+) -> (ParticipantId, MessageKind) {
+    let PreventedSelfTrade {
+        side,
+        order,
+        canceled_quantity,
+    } = prevented;
+
+    let kind = if order.is_filled() {
+        MessageKind::OrderCanceled {
+            id: order.id(),
+            side,
+            quantity: order.quantity(),
+            canceled_quantity,
+            symbol,
+        }
+    } else {
+        MessageKind::OrderRestated {
+            id: order.id(),
+            side,
+            quantity: order.quantity(),
+            leaves_quantity: order.remaining(),
+            symbol,
+        }
+    };
+
+    (order.owner().to_string(), kind)
+}
+
+/// Build the messages reporting that a stop order was triggered, followed by its executions and whatever was canceled
+/// because it ran out of liquidity
+fn triggered_messages(
+    symbol: SymbolOwned,
+    triggered: TriggeredOrder,
+) -> Vec<(ParticipantId, MessageKind)> {
+    let TriggeredOrder {
+        stop,
+        executions,
+        canceled_quantity,
+    } = triggered;
+
+    let owner = stop.owner().to_string();
+    let mut messages = vec![(
+        owner.clone(),
+        MessageKind::StopOrderTriggered {
+            id: stop.id(),
+            side: stop.side(),
+            stop_price: stop.stop_price(),
+            limit_price: stop.limit_price(),
+            quantity: stop.quantity(),
+            symbol,
+        },
+    )];
+
+    for execution in executions {
+        messages.extend(execution_messages(symbol, execution));
+    }
+
+    if canceled_quantity > Quantity::ZERO {
+        messages.push((
+            owner,
+            MessageKind::OrderCanceled {
+                id: stop.id(),
+                side: stop.side(),
+                quantity: stop.quantity(),
+                canceled_quantity,
+                symbol,
+            },
+        ));
+    }
+
+    messages
+}
+
+/// Time left until the trading day ends at `day_end` (UTC)
+fn until_day_end(day_end: chrono::NaiveTime) -> std::time::Duration {
+    let now = chrono::Utc::now().naive_utc();
+    let mut end = now.date().and_time(day_end);
+    if end <= now {
+        end += chrono::Duration::days(1);
+    }
+
+    (end - now).to_std().unwrap_or_default()
+}
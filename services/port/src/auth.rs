@@ -0,0 +1,240 @@
+use fixer_upper::Message as FixMessage;
+use ring::{digest, pbkdf2, rand::SecureRandom};
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::path::Path;
+
+/// Scheme at the start of every password hash in a credentials file
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+/// PBKDF2 iterations used for new password hashes
+const HASH_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = digest::SHA256_OUTPUT_LEN;
+
+/// Decides whether a client logging on is who its `SenderCompID` says it is
+pub trait Authenticator: std::fmt::Debug + Send + Sync {
+    /// Check the credentials on `logon`, the error is sent back to the client as the reason it was turned away
+    fn authenticate(&self, logon: &FixMessage) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+#[derive(Debug)]
+struct Credential {
+    username: String,
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+/// Checks the `Username` and `Password` on a logon against a file of salted password hashes. Every line of the file
+/// is a participant's `SenderCompID`, their username and the hash of their password, separated by whitespace. Blank
+/// lines and lines starting with `#` are ignored
+#[derive(Debug)]
+pub struct CredentialFile {
+    credentials: HashMap<String, Credential>,
+    /// Checked against when the participant is unknown, so it takes as long as it would for a known one
+    unknown: Credential,
+}
+
+impl CredentialFile {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read credentials file {:?}: {}", path, e))?;
+        contents.parse()
+    }
+}
+
+impl std::str::FromStr for CredentialFile {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut credentials = HashMap::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let (participant_id, username, hash) =
+                match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                    (Some(a), Some(b), Some(c), None) => (a, b, c),
+                    _ => {
+                        return Err(
+                            format!("incorrectly formatted credential on line {}", i + 1).into(),
+                        )
+                    }
+                };
+            let credential = parse_hash(username, hash)
+                .map_err(|e| format!("invalid password hash on line {}: {}", i + 1, e))?;
+
+            if credentials
+                .insert(participant_id.to_string(), credential)
+                .is_some()
+            {
+                return Err(format!("duplicate credential for '{}'", participant_id).into());
+            }
+        }
+
+        Ok(Self {
+            credentials,
+            unknown: Credential {
+                username: String::new(),
+                iterations: NonZeroU32::new(HASH_ITERATIONS).expect("iterations isn't zero"),
+                salt: vec![0; SALT_LEN],
+                hash: vec![0; HASH_LEN],
+            },
+        })
+    }
+}
+
+impl Authenticator for CredentialFile {
+    fn authenticate(&self, logon: &FixMessage) -> Result<(), Box<dyn std::error::Error>> {
+        let (username, password) = match (&logon.body.username, &logon.body.password) {
+            (Some(username), Some(password)) => (username, password),
+            _ => return Err("missing required fields 'Username' and 'Password'".into()),
+        };
+
+        // don't give away which participants exist, every failure costs a hash so they can't be told apart by timing
+        let (credential, known) = match self.credentials.get(&logon.header.sender_comp_id) {
+            Some(credential) => (credential, &credential.username == username),
+            None => (&self.unknown, false),
+        };
+        let verified = pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            credential.iterations,
+            &credential.salt,
+            password.as_bytes(),
+            &credential.hash,
+        );
+
+        match verified {
+            Ok(()) if known => Ok(()),
+            _ => Err("invalid username or password".into()),
+        }
+    }
+}
+
+/// Hash `password` with a fresh salt, in the format a credentials file expects
+pub fn hash_password(password: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut salt = [0u8; SALT_LEN];
+    ring::rand::SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| "failed to generate a salt")?;
+
+    let iterations = NonZeroU32::new(HASH_ITERATIONS).expect("iterations isn't zero");
+    let mut hash = [0u8; HASH_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+
+    Ok(format!(
+        "{}${}${}${}",
+        HASH_SCHEME,
+        iterations,
+        base64::encode(salt),
+        base64::encode(hash)
+    ))
+}
+
+/// Parse a `pbkdf2-sha256$<iterations>$<salt>$<hash>` password hash, with the salt and hash base64 encoded
+fn parse_hash(username: &str, s: &str) -> Result<Credential, Box<dyn std::error::Error>> {
+    let mut parts = s.split('$');
+    match (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) {
+        (Some(HASH_SCHEME), Some(iterations), Some(salt), Some(hash), None) => Ok(Credential {
+            username: username.to_string(),
+            iterations: NonZeroU32::new(iterations.parse()?).ok_or("iterations can't be zero")?,
+            salt: base64::decode(salt)?,
+            hash: base64::decode(hash)?,
+        }),
+        _ => Err(format!("expected {}$<iterations>$<salt>$<hash>", HASH_SCHEME).into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fixer_upper::{BeginString, Body, Header, MessageType, Trailer};
+
+    fn logon(sender_comp_id: &str, username: Option<&str>, password: Option<&str>) -> FixMessage {
+        FixMessage {
+            header: Header {
+                begin_string: BeginString::Fix_4_4,
+                body_length: None,
+                msg_type: MessageType::Logon,
+                sender_comp_id: sender_comp_id.to_string(),
+                target_comp_id: "matchbook".to_string(),
+                msg_seq_num: 1,
+                poss_dup_flag: None,
+                sending_time: chrono::Utc::now(),
+                orig_sending_time: None,
+            },
+            body: Body {
+                heart_bt_int: Some(30),
+                username: username.map(str::to_string),
+                password: password.map(str::to_string),
+                ..Body::default()
+            },
+            trailer: Trailer::default(),
+        }
+    }
+
+    #[test]
+    fn checks_usernames_and_passwords() {
+        let file = format!(
+            "# participant username hash\n\nseller alice {}\nbuyer bob {}\n",
+            hash_password("hunter2").unwrap(),
+            hash_password("correct horse").unwrap()
+        );
+        let credentials: CredentialFile = file.parse().unwrap();
+
+        assert!(credentials
+            .authenticate(&logon("seller", Some("alice"), Some("hunter2")))
+            .is_ok());
+        assert!(credentials
+            .authenticate(&logon("buyer", Some("bob"), Some("correct horse")))
+            .is_ok());
+
+        for logon in &[
+            logon("seller", Some("alice"), Some("correct horse")),
+            logon("buyer", Some("alice"), Some("hunter2")),
+            logon("seller", Some("bob"), Some("correct horse")),
+            logon("nobody", Some("alice"), Some("hunter2")),
+            logon("seller", Some("alice"), None),
+        ] {
+            assert!(credentials.authenticate(logon).is_err());
+        }
+    }
+
+    #[test]
+    fn salts_every_hash() {
+        assert_ne!(
+            hash_password("hunter2").unwrap(),
+            hash_password("hunter2").unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let hash = hash_password("hunter2").unwrap();
+        for file in &[
+            "seller alice".to_string(),
+            format!("seller alice {} extra", hash),
+            "seller alice sha1$1$AAAA$AAAA".to_string(),
+            "seller alice pbkdf2-sha256$0$AAAA$AAAA".to_string(),
+            format!("seller alice {}\nseller bob {}", hash, hash),
+        ] {
+            assert!(file.parse::<CredentialFile>().is_err(), "{}", file);
+        }
+    }
+}
@@ -1,21 +1,90 @@
 use std::str::FromStr;
-use std::{env, net::SocketAddr};
+use std::{env, net::SocketAddr, path::PathBuf};
 
 use matchbook_types::ServiceId;
 
+use crate::tls::{Pem, TlsConfig};
+
 const MULTICAST_ADDR_ENV_VAR_NAME: &str = "MULTICAST_ADDR";
 const SERVICE_ID_ENV_VAR_NAME: &str = "SERVICE_ID";
 const EXCHANGE_ID_ENV_VAR_NAME: &str = "EXCHANGE_ID";
+const TLS_MODE_ENV_VAR_NAME: &str = "TLS_MODE";
+const DEFAULT_TLS_MODE: &str = "tls";
 const TLS_CERT_ENV_VAR_NAME: &str = "TLS_CERT";
+const TLS_CERT_FILE_ENV_VAR_NAME: &str = "TLS_CERT_FILE";
 const TLS_CERT_KEY_ENV_VAR_NAME: &str = "TLS_CERT_KEY";
+const TLS_CERT_KEY_FILE_ENV_VAR_NAME: &str = "TLS_CERT_KEY_FILE";
+const TLS_CLIENT_CA_ENV_VAR_NAME: &str = "TLS_CLIENT_CA";
+const TLS_CLIENT_CA_FILE_ENV_VAR_NAME: &str = "TLS_CLIENT_CA_FILE";
+const LISTENERS_ENV_VAR_NAME: &str = "LISTENERS";
+const DEFAULT_LISTENERS: &str = "0.0.0.0:8080=json";
+const SEQUENCE_JOURNAL_ENV_VAR_NAME: &str = "SEQUENCE_JOURNAL";
+const DEFAULT_SEQUENCE_JOURNAL: &str = "sequence-journal.jsonl";
+const CREDENTIALS_FILE_ENV_VAR_NAME: &str = "CREDENTIALS_FILE";
+const SESSION_STORE_DIR_ENV_VAR_NAME: &str = "SESSION_STORE_DIR";
+const DEFAULT_SESSION_STORE_DIR: &str = "sessions";
+const DUPLICATE_LOGON_ENV_VAR_NAME: &str = "DUPLICATE_LOGON";
+const DEFAULT_DUPLICATE_LOGON: &str = "reject";
+
+/// How FIX messages are written on the wire
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WireFormat {
+    /// FIX messages serialized as JSON
+    Json,
+    /// Classic SOH delimited `tag=value` FIX
+    TagValue,
+}
+
+impl FromStr for WireFormat {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(WireFormat::Json),
+            "tagvalue" => Ok(WireFormat::TagValue),
+            x => Err(format!("unknown wire format '{}'", x).into()),
+        }
+    }
+}
+
+/// What happens when a participant logs on while they already have a session on another connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicateLogon {
+    /// Turn the new logon away, the existing session carries on
+    Reject,
+    /// Log the existing session out and hand the participant over to the new one
+    Replace,
+}
+
+impl FromStr for DuplicateLogon {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(DuplicateLogon::Reject),
+            "replace" => Ok(DuplicateLogon::Replace),
+            x => Err(format!("unknown duplicate logon policy '{}'", x).into()),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub service_id: ServiceId,
     pub multicast_addr: SocketAddr,
     pub exchange_id: String,
-    pub tls_cert: String,
-    pub tls_cert_key: String,
+    pub tls: TlsConfig,
+    /// Addresses to accept clients on and the wire format each of them speaks, configured as `addr=format,...`
+    pub listeners: Vec<(SocketAddr, WireFormat)>,
+    /// File the sequence number of every message published to the backbone is recorded in, so numbering survives a
+    /// restart
+    pub sequence_journal: PathBuf,
+    /// Directory every participant's FIX session is kept in, so a client can pick up where it left off when it
+    /// reconnects
+    pub session_store_dir: PathBuf,
+    /// File of the credentials every participant has to log on with
+    pub credentials_file: PathBuf,
+    pub duplicate_logon: DuplicateLogon,
 }
 
 pub fn source_config_from_env() -> Result<Config, Box<dyn std::error::Error>> {
@@ -24,7 +93,47 @@ pub fn source_config_from_env() -> Result<Config, Box<dyn std::error::Error>> {
             .map(|x| ServiceId::from_str(x.as_str()))??,
         multicast_addr: env::var(MULTICAST_ADDR_ENV_VAR_NAME).map(|x| x.parse())??,
         exchange_id: env::var(EXCHANGE_ID_ENV_VAR_NAME)?,
-        tls_cert: env::var(TLS_CERT_ENV_VAR_NAME)?,
-        tls_cert_key: env::var(TLS_CERT_KEY_ENV_VAR_NAME)?,
+        tls: TlsConfig {
+            mode: env::var(TLS_MODE_ENV_VAR_NAME)
+                .unwrap_or_else(|_| DEFAULT_TLS_MODE.to_string())
+                .parse()?,
+            cert: pem_from_env(TLS_CERT_ENV_VAR_NAME, TLS_CERT_FILE_ENV_VAR_NAME),
+            key: pem_from_env(TLS_CERT_KEY_ENV_VAR_NAME, TLS_CERT_KEY_FILE_ENV_VAR_NAME),
+            client_ca: pem_from_env(TLS_CLIENT_CA_ENV_VAR_NAME, TLS_CLIENT_CA_FILE_ENV_VAR_NAME),
+        },
+        listeners: parse_listeners(
+            &env::var(LISTENERS_ENV_VAR_NAME).unwrap_or_else(|_| DEFAULT_LISTENERS.to_string()),
+        )?,
+        sequence_journal: env::var(SEQUENCE_JOURNAL_ENV_VAR_NAME)
+            .unwrap_or_else(|_| DEFAULT_SEQUENCE_JOURNAL.to_string())
+            .into(),
+        session_store_dir: env::var(SESSION_STORE_DIR_ENV_VAR_NAME)
+            .unwrap_or_else(|_| DEFAULT_SESSION_STORE_DIR.to_string())
+            .into(),
+        credentials_file: env::var(CREDENTIALS_FILE_ENV_VAR_NAME)?.into(),
+        duplicate_logon: env::var(DUPLICATE_LOGON_ENV_VAR_NAME)
+            .unwrap_or_else(|_| DEFAULT_DUPLICATE_LOGON.to_string())
+            .parse()?,
     })
 }
+
+/// A PEM document from the file named by `file_var`, or given inline in `inline_var`
+fn pem_from_env(inline_var: &str, file_var: &str) -> Option<Pem> {
+    env::var(file_var)
+        .map(|x| Pem::File(x.into()))
+        .or_else(|_| env::var(inline_var).map(Pem::Inline))
+        .ok()
+}
+
+fn parse_listeners(s: &str) -> Result<Vec<(SocketAddr, WireFormat)>, Box<dyn std::error::Error>> {
+    s.split(',')
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            let mut split = x.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(addr), Some(format)) => Ok((addr.parse()?, format.parse()?)),
+                _ => Err(format!("incorrectly formatted listener '{}'", x).into()),
+            }
+        })
+        .collect()
+}
@@ -1,33 +1,75 @@
-use tokio_rustls::TlsAcceptor;
 use tokio_util::codec::Framed;
 
 use {
-    crate::{message, Context, ParticipantChannelMap},
-    fixer_upper::{Header as FixHeader, Message as FixMessage, MessageType as FixMessageType},
+    crate::{
+        auth::Authenticator,
+        config::{DuplicateLogon, WireFormat},
+        message,
+        session::{self, Action, Session},
+        store::SessionStore,
+        tls::Acceptor,
+        Context, Participant, ParticipantChannelMap,
+    },
+    fixer_upper::{tagvalue::TagValueCodec, MessageType as FixMessageType},
     futures::{Sink, SinkExt, Stream, StreamExt},
     matchbook_types::*,
     matchbook_util::*,
-    std::net::SocketAddr,
+    std::{
+        collections::VecDeque,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Instant,
+    },
     tokio::{
         net::TcpListener,
-        sync::mpsc::{Receiver, Sender},
+        sync::{
+            mpsc::{Receiver, Sender},
+            oneshot,
+        },
     },
     tracing::*,
 };
 
-pub async fn spawn_listen_handler<'a>(
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// What a client handler needs to start a participant's session
+#[derive(Debug, Clone)]
+pub struct SessionContext {
+    pub store: SessionStore,
+    pub authenticator: Arc<dyn Authenticator>,
+    pub duplicate_logon: DuplicateLogon,
+}
+
+/// Who's on the other end of a client connection
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// The names on the client's certificate, when clients have to present one
+    pub certificate_names: Option<Vec<String>>,
+}
+
+pub async fn spawn_listen_handler(
     listener: TcpListener,
-    tls_acceptor: TlsAcceptor,
+    wire_format: WireFormat,
+    acceptor: Acceptor,
     udp_tx: Sender<Message>,
     state: ParticipantChannelMap,
+    sessions: SessionContext,
     context: Context,
 ) {
-    info!("started listening on {}", listener.local_addr().unwrap());
+    info!(
+        "started listening on {} for {:?}",
+        listener.local_addr().unwrap(),
+        wire_format
+    );
     while let Ok((stream, addr)) = listener.accept().await {
         let span = debug_span!("connection accepted");
         let _enter = span.enter();
-        let stream = match tls_acceptor.accept(stream).await {
-            Ok(stream) => stream,
+        let (stream, certificate_names) = match acceptor.accept(stream).await {
+            Ok(x) => x,
             Err(e) => {
                 error!("tls failed {:?}", e);
                 continue;
@@ -35,14 +77,28 @@ pub async fn spawn_listen_handler<'a>(
         };
 
         info!("accepted connection from {}", addr);
+        let peer = Peer {
+            addr,
+            certificate_names,
+        };
         let udp_tx = udp_tx.clone();
         let state = state.clone();
+        let sessions = sessions.clone();
         let context = context.clone();
-        let (sink, stream) = Framed::new(stream, FixJsonCodec::new()).split();
-
-        tokio::spawn(async move {
-            spawn_client_handler(stream, sink, udp_tx, addr, state, context).await
-        });
+        match wire_format {
+            WireFormat::Json => {
+                let (sink, stream) = Framed::new(stream, FixJsonCodec::new()).split();
+                tokio::spawn(async move {
+                    spawn_client_handler(stream, sink, udp_tx, peer, state, sessions, context).await
+                });
+            }
+            WireFormat::TagValue => {
+                let (sink, stream) = Framed::new(stream, TagValueCodec::new()).split();
+                tokio::spawn(async move {
+                    spawn_client_handler(stream, sink, udp_tx, peer, state, sessions, context).await
+                });
+            }
+        }
     }
 }
 
@@ -56,140 +112,152 @@ pub async fn spawn_client_handler(
         + std::marker::Send
         + 'static,
     udp_tx: Sender<Message>,
-    addr: SocketAddr,
+    peer: Peer,
     state: ParticipantChannelMap,
+    sessions: SessionContext,
     context: Context,
 ) {
-    let (participant_tx, mut participant_rx): (Sender<Message>, _) = tokio::sync::mpsc::channel(32);
+    // a session has to start with a logon
+    let logon = match stream.next().await {
+        Some(Ok(message)) if message.header.msg_type == FixMessageType::Logon => message,
+        Some(Ok(message)) => {
+            warn!(?message.header.msg_type, "received non-logon message before logging on, terminating connection");
+            return;
+        }
+        Some(Err(e)) => {
+            warn!("{}", e);
+            return;
+        }
+        None => {
+            warn!("user terminated connection before logging on");
+            return;
+        }
+    };
+    let participant_id = logon.header.sender_comp_id.clone();
 
-    // FIX session sequence numbers start at 1
-    let mut inbound_sequence_n = 1;
-    let mut outbound_sequence_n = 1;
+    if let Some(names) = &peer.certificate_names {
+        if !names.contains(&participant_id) {
+            warn!(%participant_id, ?peer, "client certificate wasn't issued for participant");
+            let text = format!(
+                "client certificate isn't valid for SenderCompID '{}'",
+                participant_id
+            );
+            let logout = session::reject_logon(&logon, context.exchange_id.clone(), text);
+            let _ = sink.send(logout).await;
+            return;
+        }
+    }
 
-    // create a channel that will be used to start listening for UDP messages after the user has
-    let (sender_tx, mut sender_rx) = tokio::sync::mpsc::channel(32);
-    let (logon_tx, logon_rx) = tokio::sync::oneshot::channel();
-    let listen_handle = {
-        let context = context.clone();
-        tokio::spawn(async move {
-            let udp_tx = udp_tx.clone();
-            let participant_id = loop {
-                let span = debug_span!("client message received", ?addr);
-                let _enter = span.enter();
-
-                let message = stream.next().await;
-
-                if let Some(Ok(ref message)) = message {
-                    let sequence_n = message.header.msg_seq_num;
-                    if sequence_n != inbound_sequence_n {
-                        error!(
-                        "received unexpected sequence number {}, was expecting {}. terminating connection",
-                        sequence_n, inbound_sequence_n
-                    );
-                        sender_tx
-                            .send(fixer_upper::Message {
-                                header: fixer_upper::Header {
-                                    begin_string: fixer_upper::BeginString::Fix_4_4,
-                                    body_length: None,
-                                    msg_type: fixer_upper::MessageType::Logout,
-                                    sender_comp_id: message.header.target_comp_id.clone(),
-                                    target_comp_id: context.exchange_id.clone(),
-                                    msg_seq_num: outbound_sequence_n,
-                                    sending_time: chrono::Utc::now(),
-                                },
-                                body: fixer_upper::Body {
-                                    ..fixer_upper::Body::default()
-                                },
-                                trailer: fixer_upper::Trailer {
-                                    signature: None,
-                                    signature_length: None,
-                                },
-                            })
-                            .await
-                            .unwrap();
+    // hashing passwords takes long enough that it'd hold up every other session on this worker. The error isn't Send,
+    // it can't be held on to across sending the logout
+    let authenticator = sessions.authenticator.clone();
+    let (logon, rejection) = match tokio::task::spawn_blocking(move || {
+        let rejection = authenticator
+            .authenticate(&logon)
+            .map_err(|e| e.to_string());
+        (logon, rejection)
+    })
+    .await
+    {
+        Ok(x) => x,
+        Err(e) => {
+            error!(%participant_id, "authentication failed to run: {}", e);
+            return;
+        }
+    };
+    if let Err(text) = rejection {
+        warn!(%participant_id, addr = ?peer.addr, "authentication failed: {}", text);
+        let logout = session::reject_logon(&logon, context.exchange_id.clone(), text);
+        let _ = sink.send(logout).await;
+        return;
+    }
 
-                        return;
-                    }
-                }
+    let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let (participant_tx, mut participant_rx): (Sender<Message>, _) = tokio::sync::mpsc::channel(32);
+    let (kick_tx, mut kick_rx) = oneshot::channel();
+    // dropped once the session is over, letting a connection that took over know it can carry on
+    let (_ended_tx, ended_rx) = oneshot::channel::<()>();
+    let participant = Participant {
+        connection_id,
+        tx: participant_tx,
+        kick: Some(kick_tx),
+        ended: ended_rx,
+    };
 
-                inbound_sequence_n += 1;
-
-                match message {
-                    Some(Ok(FixMessage {
-                        header:
-                            FixHeader {
-                                ref sender_comp_id,
-                                msg_type: FixMessageType::Logon,
-                                ..
-                            },
-                        ..
-                    })) => {
-                        let id = sender_comp_id.clone();
-                        let mut message = message.unwrap().unwrap();
-                        message.header.sender_comp_id = message.header.target_comp_id;
-                        message.header.target_comp_id = id.clone();
-                        sender_tx.send(message).await.unwrap();
-                        outbound_sequence_n += 1;
-                        break id;
-                    }
-                    None => return,
-                    x => {
-                        warn!(?x, "received non-logon message");
-                        continue;
-                    }
-                }
-            };
+    // checked and claimed in one go, so two connections can't both think they're the only one
+    let previous = {
+        let mut state = state.write().await;
+        if state.contains_key(&participant_id) && sessions.duplicate_logon == DuplicateLogon::Reject
+        {
+            None
+        } else {
+            Some(state.insert(participant_id.clone(), participant))
+        }
+    };
+    let previous = match previous {
+        Some(x) => x,
+        None => {
+            warn!(%participant_id, addr = ?peer.addr, "participant is already logged on, rejecting logon");
+            let text = format!("'{}' is already logged on", participant_id);
+            let logout = session::reject_logon(&logon, context.exchange_id.clone(), text);
+            let _ = sink.send(logout).await;
+            return;
+        }
+    };
+    // the other session has to be done with the journal before this one opens it
+    if let Some(mut previous) = previous {
+        info!(%participant_id, addr = ?peer.addr, "participant logged on again, logging out their other session");
+        if let Some(kick) = previous.kick.take() {
+            let _ = kick.send(());
+        }
+        let _ = previous.ended.await;
+    }
 
-            logon_tx.send(participant_id).unwrap();
-
-            // normal flow
-            loop {
-                let message = stream.next().await;
-                if let Some(Ok(ref message)) = message {
-                    let sequence_n = message.header.msg_seq_num;
-                    if sequence_n != inbound_sequence_n {
-                        error!(
-                        "received unexected sequence number {}, was expecting {}. terminating connection",
-                        sequence_n, inbound_sequence_n
-                    );
-
-                        sender_tx
-                            .send(fixer_upper::Message {
-                                header: fixer_upper::Header {
-                                    begin_string: fixer_upper::BeginString::Fix_4_4,
-                                    body_length: None,
-                                    msg_type: fixer_upper::MessageType::Logout,
-                                    sender_comp_id: message.header.target_comp_id.clone(),
-                                    target_comp_id: "matchbook".to_string(),
-                                    msg_seq_num: outbound_sequence_n,
-                                    sending_time: chrono::Utc::now(),
-                                },
-                                body: fixer_upper::Body {
-                                    ..fixer_upper::Body::default()
-                                },
-                                trailer: fixer_upper::Trailer {
-                                    signature: None,
-                                    signature_length: None,
-                                },
-                            })
-                            .await
-                            .unwrap();
+    async {
+        let (journal, restored) = match sessions.store.open(&participant_id) {
+            Ok(x) => x,
+            Err(e) => {
+                error!(%participant_id, "failed to open session journal: {}", e);
+                return;
+            }
+        };
+        let (mut session, actions) = match Session::logon(
+            logon,
+            context.exchange_id.clone(),
+            restored,
+            Some(journal),
+            Instant::now(),
+        ) {
+            Ok(x) => x,
+            Err(logout) => {
+                let _ = sink.send(*logout).await;
+                return;
+            }
+        };
+        info!(%participant_id, addr = ?peer.addr, "user authenticated and ready to receive messages");
 
-                        return;
+        let mut kicked = false;
+        let mut actions: VecDeque<Action> = actions.into();
+        'session: loop {
+            while let Some(action) = actions.pop_front() {
+                match action {
+                    Action::Send(message) => {
+                        if let Err(e) = sink.send(message).await {
+                            warn!("failed to send to client: {}", e);
+                            break 'session;
+                        }
                     }
-                }
-
-                inbound_sequence_n += 1;
-
-                match message {
-                    Some(Ok(message)) => {
+                    Action::Deliver(message) => {
+                        let header = message.header.clone();
                         let message = match message::fix_message_into_matchbook_message(
                             message,
                             context.service_id,
                         ) {
                             Ok(message) => message,
-                            Err(e) => {
-                                warn!("{}", e);
+                            Err(rejection) => {
+                                warn!(?header.msg_type, header.msg_seq_num, "rejecting message: {}", rejection);
+                                let reject = session.reject(&header, rejection, Instant::now());
+                                actions.push_front(Action::Send(reject));
                                 continue;
                             }
                         };
@@ -199,73 +267,106 @@ pub async fn spawn_client_handler(
                             .await
                             .expect("failed the send message to backbone transmitter");
                     }
-                    None => break,
-                    x => warn!("{:?}", x),
+                    Action::Disconnect => break 'session,
                 }
             }
-        })
-    };
 
-    let sender_handle = {
-        let context = context.clone();
-        tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    Some(message) = participant_rx.recv()=> {
-                        let mut message = message::matchbook_message_into_fix_message(message, context.exchange_id.clone());
-                        message.header.msg_seq_num = outbound_sequence_n;
-                        match sink.send(message).await {
-                            Ok(_) => {}
-                            // TODO(will): figure out what needs to be done to be able to debug print the error
-                            Err(_) => {warn!("failed to send to sender")}
-                        }
-                        outbound_sequence_n += 1;
+            let timer = session.next_timer();
+            let timer = async move {
+                match timer {
+                    Some(x) => tokio::time::sleep_until(x.into()).await,
+                    None => futures::future::pending().await,
+                }
+            };
+
+            let new_actions = tokio::select! {
+                message = stream.next() => match message {
+                    Some(Ok(message)) => session.receive(message, Instant::now()),
+                    Some(Err(e)) => {
+                        warn!("{}", e);
+                        continue;
                     }
-                    Some(message) = sender_rx.recv() => {
-                        match sink.send(message).await {
-                            Ok(_) => {}
-                            // TODO(will): figure out what needs to be done to be able to debug print the error
-                            Err(_) => {warn!("failed to send to sender")}
+                    None => break,
+                },
+                Some(message) = participant_rx.recv() => {
+                    match message::matchbook_message_into_fix_message(message, context.exchange_id.clone()) {
+                        Ok(message) => vec![Action::Send(session.send(message, Instant::now()))],
+                        Err(e) => {
+                            warn!("not sending engine message to client: {}", e);
+                            continue;
                         }
                     }
-                    else => {
-                        break
-                    }
                 }
-            }
-        })
-    };
-
-    let participant_id = match logon_rx.await {
-        Ok(id) => id,
-        Err(_) => {
-            warn!("user terminated connection before logging on");
-            return;
+                _ = &mut kick_rx, if !kicked => {
+                    kicked = true;
+                    session.end("logged on from another connection".to_string(), Instant::now())
+                }
+                _ = timer => session.poll_timers(Instant::now()),
+            };
+            actions.extend(new_actions);
         }
-    };
-
-    state
-        .write()
-        .await
-        .insert(participant_id.clone(), participant_tx);
-    info!("user authenticated and ready to receive messages");
+    }
+    .await;
 
-    tokio::select!(_ = listen_handle => { }, _ = sender_handle => { });
+    // a connection that took over owns the entry now
+    let mut state = state.write().await;
+    if state.get(&participant_id).map(|x| x.connection_id) == Some(connection_id) {
+        state.remove(&participant_id);
+    }
+    drop(state);
+    // only once nothing can find the session any more, so senders see it's gone rather than blocking
+    drop(participant_rx);
     info!("ending session for participant {}", participant_id);
 }
 
 pub async fn spawn_multicast_rx_handler<S>(
-    mut stream: S,
+    stream: S,
+    udp_tx: Sender<Message>,
     state: ParticipantChannelMap,
     _context: Context,
 ) where
     S: Stream<Item = Result<Message, std::io::Error>> + Unpin,
 {
-    while let Some(Ok(message)) = stream.next().await {
-        if let Some(tx) = state.read().await.get(&message.id.topic_id).cloned() {
+    let (retransmit_tx, mut retransmit_rx) = futures::channel::mpsc::unbounded();
+    let mut stream = GapDetector::new(stream, retransmit_tx);
+
+    loop {
+        let message = tokio::select! {
+            message = stream.next() => message,
+            Some(request) = retransmit_rx.next() => {
+                udp_tx.send(request).await.expect("failed to send to backbone");
+                continue;
+            }
+        };
+
+        let message = match message {
+            Some(Ok(message)) => message,
+            _ => break,
+        };
+
+        // other services use the same topics, clients are only interested in what the engine has to say. Retransmit
+        // requests carry the id of the engine message they're asking for, so they can look like they're from it
+        if message.id.publisher_id.kind != ServiceKind::MatchingEngine
+            || message.kind.is_retransmit_request()
+            || matches!(message.kind, MessageKind::GapFill)
+        {
+            continue;
+        }
+
+        // the lock mustn't be held while waiting on a busy session, it'd keep the session from ending
+        let tx = state
+            .read()
+            .await
+            .get(&message.id.topic_id)
+            .map(|x| x.tx.clone());
+        if let Some(tx) = tx {
             debug!("received message",);
-            tx.send(message).await.expect("failed to send to backbone");
-            trace!("message forwarded to client connection handler");
+            let participant_id = message.id.topic_id.clone();
+            if tx.send(message).await.is_err() {
+                warn!(%participant_id, "participant's session ended, dropping message");
+            } else {
+                trace!("message forwarded to client connection handler");
+            }
         } else {
             warn!("message meant for invalid participant");
         }
@@ -275,9 +376,22 @@ pub async fn spawn_multicast_rx_handler<S>(
 pub async fn spawn_multicast_tx_handler(
     mut sink: impl Sink<Message, Error = std::io::Error> + Unpin,
     mut rx: Receiver<Message>,
-    _context: Context,
+    mut sequencer: Sequencer,
+    context: Context,
 ) {
-    while let Some(message) = rx.recv().await {
+    while let Some(mut message) = rx.recv().await {
+        // a client's own sequence numbers start over whenever it reconnects, so the backbone gets numbers of our own.
+        // Retransmit requests keep the id of the message they're asking for
+        if !message.kind.is_retransmit_request() {
+            message.id = match sequencer.next_id(context.service_id, message.id.topic_id) {
+                Ok(id) => id,
+                Err(e) => {
+                    error!("failed to assign a sequence number: {}", e);
+                    continue;
+                }
+            };
+        }
+
         debug!(?message.id, "received message");
         match sink.send(message).await {
             Ok(_) => {}
@@ -289,3 +403,235 @@ pub async fn spawn_multicast_tx_handler(
         trace!("message sent to client");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fixer_upper::{BeginString, Body, Header, Message as FixMessage, Trailer};
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+    use std::collections::HashMap;
+    use tokio::{sync::RwLock, task::JoinHandle};
+
+    #[derive(Debug)]
+    struct AllowAll;
+
+    impl Authenticator for AllowAll {
+        fn authenticate(&self, _: &FixMessage) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    /// The far end of a client connection
+    struct Client {
+        tx: UnboundedSender<Result<FixMessage, std::io::Error>>,
+        rx: UnboundedReceiver<FixMessage>,
+        handle: JoinHandle<()>,
+    }
+
+    impl Client {
+        async fn logon(&mut self) -> FixMessage {
+            let logon = FixMessage {
+                header: Header {
+                    begin_string: BeginString::Fix_4_4,
+                    body_length: None,
+                    msg_type: FixMessageType::Logon,
+                    sender_comp_id: "seller".to_string(),
+                    target_comp_id: "matchbook".to_string(),
+                    msg_seq_num: 1,
+                    poss_dup_flag: None,
+                    sending_time: chrono::Utc::now(),
+                    orig_sending_time: None,
+                },
+                body: Body {
+                    heart_bt_int: Some(0),
+                    reset_seq_num_flag: Some(true),
+                    ..Body::default()
+                },
+                trailer: Trailer::default(),
+            };
+            self.tx.unbounded_send(Ok(logon)).unwrap();
+            self.rx.next().await.unwrap()
+        }
+    }
+
+    fn connect(
+        state: &ParticipantChannelMap,
+        sessions: &SessionContext,
+        udp_tx: &Sender<Message>,
+    ) -> Client {
+        let (tx, stream) = unbounded();
+        let (sink, rx) = unbounded();
+        let sink = sink.sink_map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e));
+        let peer = Peer {
+            addr: "127.0.0.1:9000".parse().unwrap(),
+            certificate_names: None,
+        };
+        let context = Context {
+            service_id: "port:1".parse().unwrap(),
+            exchange_id: "matchbook".to_string(),
+            multicast_addr: "239.255.0.1:9001".parse().unwrap(),
+        };
+        let handle = tokio::spawn(spawn_client_handler(
+            stream,
+            sink,
+            udp_tx.clone(),
+            peer,
+            state.clone(),
+            sessions.clone(),
+            context,
+        ));
+        Client { tx, rx, handle }
+    }
+
+    fn sessions(name: &str, duplicate_logon: DuplicateLogon) -> SessionContext {
+        let dir = std::env::temp_dir().join(format!("port-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        SessionContext {
+            store: SessionStore::new(dir).unwrap(),
+            authenticator: Arc::new(AllowAll),
+            duplicate_logon,
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_a_second_logon() {
+        let state: ParticipantChannelMap = Arc::new(RwLock::new(HashMap::new()));
+        let sessions = sessions("duplicate-reject", DuplicateLogon::Reject);
+        let (udp_tx, _udp_rx) = tokio::sync::mpsc::channel(32);
+
+        let mut first = connect(&state, &sessions, &udp_tx);
+        assert_eq!(first.logon().await.header.msg_type, FixMessageType::Logon);
+
+        let mut second = connect(&state, &sessions, &udp_tx);
+        let logout = second.logon().await;
+        assert_eq!(logout.header.msg_type, FixMessageType::Logout);
+        assert_eq!(
+            logout.body.text.as_deref(),
+            Some("'seller' is already logged on")
+        );
+        second.handle.await.unwrap();
+        assert!(state.read().await.contains_key("seller"));
+
+        // the first session carries on until it ends, then makes way
+        drop(first.tx);
+        first.handle.await.unwrap();
+        assert!(state.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_message_types_it_doesnt_know() {
+        let state: ParticipantChannelMap = Arc::new(RwLock::new(HashMap::new()));
+        let sessions = sessions("unsupported", DuplicateLogon::Reject);
+        let (udp_tx, _udp_rx) = tokio::sync::mpsc::channel(32);
+
+        let mut client = connect(&state, &sessions, &udp_tx);
+        let logon = client.logon().await;
+        let unsupported = FixMessage {
+            header: Header {
+                msg_type: FixMessageType::Unsupported,
+                msg_seq_num: 2,
+                ..logon.header
+            },
+            body: Body::default(),
+            trailer: Trailer::default(),
+        };
+        client.tx.unbounded_send(Ok(unsupported)).unwrap();
+
+        let reject = client.rx.next().await.unwrap();
+        assert_eq!(
+            reject.header.msg_type,
+            FixMessageType::BusinessMessageReject
+        );
+        assert_eq!(reject.body.ref_seq_num, Some(2));
+        assert_eq!(reject.body.ref_msg_type, None);
+        assert_eq!(
+            reject.body.text.as_deref(),
+            Some("unsupported message type")
+        );
+
+        drop(client.tx);
+        client.handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_forwards_what_the_engine_says() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+        let (_ended, ended) = oneshot::channel();
+        let participant = Participant {
+            connection_id: 0,
+            tx,
+            kick: None,
+            ended,
+        };
+        let state: ParticipantChannelMap = Arc::new(RwLock::new(
+            vec![("seller".to_string(), participant)]
+                .into_iter()
+                .collect(),
+        ));
+        let (udp_tx, _udp_rx) = tokio::sync::mpsc::channel(32);
+        let context = Context {
+            service_id: "port:1".parse().unwrap(),
+            exchange_id: "matchbook".to_string(),
+            multicast_addr: "239.255.0.1:9001".parse().unwrap(),
+        };
+
+        let message = |topic_sequence_n, kind| Message {
+            id: MessageId {
+                publisher_id: "matching-engine:1".parse().unwrap(),
+                topic_id: "seller".to_string(),
+                topic_sequence_n,
+            },
+            kind,
+            replay: false,
+        };
+        let reject = MessageKind::CancelReject {
+            id: 1,
+            reason: "unknown order".to_string(),
+        };
+        let messages = vec![
+            message(1, reject.clone()),
+            message(1, MessageKind::RetransmitRequest),
+            message(
+                1,
+                MessageKind::RetransmitRangeRequest {
+                    last_topic_sequence_n: 1,
+                },
+            ),
+            message(2, MessageKind::GapFill),
+            message(3, reject.clone()),
+        ];
+        let stream = futures::stream::iter(messages.into_iter().map(Ok).collect::<Vec<_>>());
+        spawn_multicast_rx_handler(stream, udp_tx, state, context).await;
+
+        let forwarded = |x: Option<Message>| x.map(|x| (x.id.topic_sequence_n, x.kind));
+        assert_eq!(forwarded(rx.recv().await), Some((1, reject.clone())));
+        assert_eq!(forwarded(rx.recv().await), Some((3, reject)));
+        // the handler has finished with the session's channel
+        assert_eq!(forwarded(rx.recv().await), None);
+    }
+
+    #[tokio::test]
+    async fn replaces_the_existing_session() {
+        let state: ParticipantChannelMap = Arc::new(RwLock::new(HashMap::new()));
+        let sessions = sessions("duplicate-replace", DuplicateLogon::Replace);
+        let (udp_tx, _udp_rx) = tokio::sync::mpsc::channel(32);
+
+        let mut first = connect(&state, &sessions, &udp_tx);
+        assert_eq!(first.logon().await.header.msg_type, FixMessageType::Logon);
+
+        let mut second = connect(&state, &sessions, &udp_tx);
+        assert_eq!(second.logon().await.header.msg_type, FixMessageType::Logon);
+        let logout = first.rx.next().await.unwrap();
+        assert_eq!(logout.header.msg_type, FixMessageType::Logout);
+        assert_eq!(
+            logout.body.text.as_deref(),
+            Some("logged on from another connection")
+        );
+        first.handle.await.unwrap();
+        assert!(state.read().await.contains_key("seller"));
+
+        drop(second.tx);
+        second.handle.await.unwrap();
+        assert!(state.read().await.is_empty());
+    }
+}
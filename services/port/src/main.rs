@@ -1,17 +1,12 @@
 #![deny(clippy::all)]
 
-use std::io::BufReader;
-
-use tokio_rustls::{
-    rustls::{
-        internal::pemfile::{certs, pkcs8_private_keys},
-        NoClientAuth, ServerConfig,
-    },
-    TlsAcceptor,
-};
+mod auth;
 mod config;
 mod handler;
 mod message;
+mod session;
+mod store;
+mod tls;
 
 use {
     handler::*,
@@ -20,14 +15,35 @@ use {
     std::{collections::HashMap, error::Error, net::SocketAddr, sync::Arc},
     tokio::{
         net::TcpListener,
-        sync::{mpsc::Sender, RwLock},
+        sync::{mpsc::Sender, oneshot, RwLock},
     },
 };
 
-pub type ParticipantChannelMap = Arc<RwLock<HashMap<ParticipantId, Sender<Message>>>>;
+pub type ParticipantChannelMap = Arc<RwLock<HashMap<ParticipantId, Participant>>>;
+
+/// The session a participant is logged on with
+#[derive(Debug)]
+pub struct Participant {
+    /// Tells apart the connections a participant has logged on from
+    pub connection_id: u64,
+    pub tx: Sender<Message>,
+    /// Asks the session to log out, another connection is taking over
+    pub kick: Option<oneshot::Sender<()>>,
+    /// Closes once the session has ended
+    pub ended: oneshot::Receiver<()>,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    // `port hash-password <password>` prints the hash to put in the credentials file
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, command, password] = &args[..] {
+        if command == "hash-password" {
+            println!("{}", auth::hash_password(password)?);
+            return Ok(());
+        }
+    }
+
     let config = config::source_config_from_env()?;
     tracing_subscriber::fmt::init();
     let context = Context {
@@ -42,28 +58,55 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let (udp_tx, udp_rx) = tokio::sync::mpsc::channel(32);
 
-    let listener = TcpListener::bind("0.0.0.0:8080").await?;
-    let tls_acceptor = setup_tls_acceptor(config.tls_cert, config.tls_cert_key)?;
+    let acceptor = tls::Acceptor::new(config.tls)?;
+    tokio::spawn(tls::spawn_reload_handler(acceptor.clone()));
+
+    let sessions = SessionContext {
+        store: store::SessionStore::new(&config.session_store_dir)?,
+        authenticator: Arc::new(auth::CredentialFile::open(&config.credentials_file)?),
+        duplicate_logon: config.duplicate_logon,
+    };
 
-    // A task responsible for handling incoming client connections
-    let client_listener_handle = {
+    // A task per listener responsible for handling incoming client connections
+    let mut client_listener_handles = vec![];
+    for (addr, wire_format) in config.listeners {
+        let listener = TcpListener::bind(addr).await?;
+        let acceptor = acceptor.clone();
+        let sessions = sessions.clone();
+        let udp_tx = udp_tx.clone();
         let state = state.clone();
         let context = context.clone();
-        tokio::spawn(async move {
-            spawn_listen_handler(listener, tls_acceptor, udp_tx, state.clone(), context).await
-        })
-    };
+        client_listener_handles.push(tokio::spawn(async move {
+            spawn_listen_handler(
+                listener,
+                wire_format,
+                acceptor,
+                udp_tx,
+                state,
+                sessions,
+                context,
+            )
+            .await
+        }));
+    }
+    let client_listener_handle = futures::future::join_all(client_listener_handles);
 
     //A task that listens for inbound multicast packets and forwards them to the applicable client handler
     let multicast_rx_handle = {
         let state = state.clone();
         let context = context.clone();
-        tokio::spawn(async move { spawn_multicast_rx_handler(stream, state, context).await })
+        let udp_tx = udp_tx.clone();
+        tokio::spawn(
+            async move { spawn_multicast_rx_handler(stream, udp_tx, state, context).await },
+        )
     };
 
     let multicast_tx_handle = {
         let context = context.clone();
-        tokio::spawn(async move { spawn_multicast_tx_handler(sink, udp_rx, context).await })
+        let sequencer = Sequencer::open(&config.sequence_journal)?;
+        tokio::spawn(
+            async move { spawn_multicast_tx_handler(sink, udp_rx, sequencer, context).await },
+        )
     };
 
     let _ = tokio::join!(
@@ -75,19 +118,6 @@ async fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn setup_tls_acceptor(
-    cert: String,
-    key: String,
-) -> Result<TlsAcceptor, Box<dyn std::error::Error>> {
-    let mut tls_config = ServerConfig::new(NoClientAuth::new());
-    let certs = certs(&mut BufReader::new(std::io::Cursor::new(cert))).unwrap();
-    let keys = pkcs8_private_keys(&mut BufReader::new(std::io::Cursor::new(key))).unwrap();
-
-    tls_config.set_single_cert(certs, keys[0].clone())?;
-
-    Ok(TlsAcceptor::from(Arc::new(tls_config)))
-}
-
 #[derive(Debug, Clone)]
 pub struct Context {
     pub service_id: ServiceId,
@@ -1,83 +1,225 @@
-use fixer_upper::{Message as FixMessage, MessageType as FixMessageType};
-use itertools::Itertools;
+use fixer_upper::{
+    BusinessRejectReason, Message as FixMessage, MessageType as FixMessageType, SessionRejectReason,
+};
 use matchbook_types::*;
 
+/// Why a message from a client wasn't passed on to the engine
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejection {
+    /// The message breaks the rules for its type, answered with a `Reject (3)`
+    Session {
+        reason: SessionRejectReason,
+        ref_tag_id: Option<u64>,
+        text: String,
+    },
+    /// The exchange doesn't handle this kind of message, answered with a `BusinessMessageReject (j)`
+    Business {
+        reason: BusinessRejectReason,
+        /// The `ClOrdID` of the rejected message, if it had one
+        ref_id: Option<String>,
+        text: String,
+    },
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::Session { text, .. } | Rejection::Business { text, .. } => {
+                write!(f, "{}", text)
+            }
+        }
+    }
+}
+
 pub fn fix_message_into_matchbook_message(
     msg: FixMessage,
     service_id: ServiceId,
-) -> Result<Message, Box<dyn std::error::Error>> {
+) -> Result<Message, Rejection> {
     use FixMessageType::*;
     use MessageKind::*;
 
-    let symbol = msg.body.symbol.ok_or("missing required field 'Symbol'")?;
+    let symbol = match msg.header.msg_type {
+        NewOrderSingle | OrderCancelRequest | OrderCancelReplaceRequest => {
+            required(msg.body.symbol, 55, "Symbol")?
+        }
+        x => {
+            return Err(Rejection::Business {
+                reason: fixer_upper::BusinessRejectReason::UnsupportedMessageType,
+                ref_id: msg.body.cl_ord_id,
+                text: match x {
+                    Unsupported => "unsupported message type".to_string(),
+                    x => format!("unsupported message type '{:?}'", x),
+                },
+            })
+        }
+    };
 
     Ok(match msg.header.msg_type {
         NewOrderSingle => Message {
-            kind: LimitOrderSubmitRequest {
-                price: msg.body.price.ok_or("missing required field 'Price'")? as Price,
-                quantity: msg
-                    .body
-                    .order_qty
-                    .ok_or("missing required field 'OrderQty'")?
-                    as Quantity,
-                side: match msg.body.side.ok_or("missing required field 'Side'")? {
-                    fixer_upper::Side::Buy => Side::Bid,
-                    fixer_upper::Side::Sell => Side::Ask,
+            kind: match required(msg.body.ord_type, 40, "OrdType")?
+            {
+                fixer_upper::OrderType::Limit => LimitOrderSubmitRequest {
+                    price: required(msg.body.price, 44, "Price")?,
+                    quantity: required(msg.body.order_qty, 38, "OrderQty")?,
+                    side: matchbook_side(required(msg.body.side, 54, "Side")?),
+                    symbol: matchbook_symbol(&symbol)?,
+                    time_in_force: match msg.body.time_in_force {
+                        // FIX orders without a time in force are day orders
+                        None | Some(fixer_upper::TimeInForce::Day) => TimeInForce::Day,
+                        Some(fixer_upper::TimeInForce::GoodTillCancel) => {
+                            TimeInForce::GoodTillCancel
+                        }
+                        Some(fixer_upper::TimeInForce::ImmediateOrCancel) => {
+                            TimeInForce::ImmediateOrCancel
+                        }
+                        Some(fixer_upper::TimeInForce::FillOrKill) => TimeInForce::FillOrKill,
+                        Some(x) => {
+                            return Err(incorrect(59, format!("unsupported time in force '{:?}'", x)))
+                        }
+                    },
+                    display_quantity: msg.body.max_floor,
+                    // ExecInst can hold several space separated instructions
+                    post_only: msg.body.exec_inst.iter().any(|x| {
+                        x.split_whitespace()
+                            .any(|x| x == fixer_upper::EXEC_INST_PARTICIPATE_DONT_INITIATE)
+                    }),
+                    self_trade_prevention: msg.body.self_match_prevention_instruction.map(
+                        |x| match x {
+                            fixer_upper::SelfMatchPreventionInstruction::CancelAggressive => {
+                                SelfTradePrevention::CancelNewest
+                            }
+                            fixer_upper::SelfMatchPreventionInstruction::CancelPassive => {
+                                SelfTradePrevention::CancelOldest
+                            }
+                            fixer_upper::SelfMatchPreventionInstruction::CancelAggressiveAndPassive => {
+                                SelfTradePrevention::CancelBoth
+                            }
+                        },
+                    ),
                 },
-                symbol: {
-                    let (first, second, third, fourth) = symbol
-                        .chars()
-                        .next_tuple()
-                        .ok_or("incorrect length for field 'Symbol'")?;
-                    [first, second, third, fourth]
+                fixer_upper::OrderType::Market => MarketOrderSubmitRequest {
+                    quantity: required(msg.body.order_qty, 38, "OrderQty")?,
+                    side: matchbook_side(required(msg.body.side, 54, "Side")?),
+                    symbol: matchbook_symbol(&symbol)?,
                 },
+                ord_type @ fixer_upper::OrderType::Stop
+                | ord_type @ fixer_upper::OrderType::StopLimit => {
+                    // stop orders are day orders
+                    match msg.body.time_in_force {
+                        None | Some(fixer_upper::TimeInForce::Day) => {}
+                        Some(x) => {
+                            return Err(incorrect(
+                                59,
+                                format!("unsupported time in force '{:?}' for a stop order", x),
+                            ))
+                        }
+                    }
+
+                    StopOrderSubmitRequest {
+                        stop_price: required(msg.body.stop_px, 99, "StopPx")?,
+                        limit_price: match ord_type {
+                            fixer_upper::OrderType::StopLimit => {
+                                Some(required(msg.body.price, 44, "Price")?)
+                            }
+                            _ => None,
+                        },
+                        quantity: required(msg.body.order_qty, 38, "OrderQty")?,
+                        side: matchbook_side(required(msg.body.side, 54, "Side")?),
+                        symbol: matchbook_symbol(&symbol)?,
+                    }
+                }
+                x => return Err(incorrect(40, format!("unsupported order type '{:?}'", x))),
             },
             id: MessageId {
                 publisher_id: service_id,
                 topic_id: msg.header.sender_comp_id,
                 topic_sequence_n: msg.header.msg_seq_num,
             },
+            replay: false,
         },
-        x => unimplemented!("{:?}", x),
+        OrderCancelRequest => Message {
+            kind: CancelRequest {
+                id: parse_order_id(&required(msg.body.order_id, 37, "OrderID")?)?,
+                symbol: matchbook_symbol(&symbol)?,
+            },
+            id: MessageId {
+                publisher_id: service_id,
+                topic_id: msg.header.sender_comp_id,
+                topic_sequence_n: msg.header.msg_seq_num,
+            },
+            replay: false,
+        },
+        OrderCancelReplaceRequest => Message {
+            kind: ReplaceRequest {
+                id: parse_order_id(&required(msg.body.order_id, 37, "OrderID")?)?,
+                price: required(msg.body.price, 44, "Price")?,
+                quantity: required(msg.body.order_qty, 38, "OrderQty")?,
+                symbol: matchbook_symbol(&symbol)?,
+            },
+            id: MessageId {
+                publisher_id: service_id,
+                topic_id: msg.header.sender_comp_id,
+                topic_sequence_n: msg.header.msg_seq_num,
+            },
+            replay: false,
+        },
+        _ => unreachable!("message type was checked above"),
     })
 }
 
-pub fn matchbook_message_into_fix_message(msg: Message, exchange_id: String) -> FixMessage {
-    match msg.kind {
+pub fn matchbook_message_into_fix_message(
+    msg: Message,
+    exchange_id: String,
+) -> Result<FixMessage, Box<dyn std::error::Error>> {
+    Ok(match msg.kind {
         MessageKind::LimitOrderSubmitRequest {
             price,
             side,
             symbol,
             quantity,
-            ..
+            time_in_force,
+            display_quantity,
+            post_only,
+            self_trade_prevention,
         } => FixMessage {
-            header: fixer_upper::Header {
-                begin_string: fixer_upper::BeginString::Fix_4_4,
-                body_length: None,
-                msg_type: FixMessageType::NewOrderSingle,
-                sender_comp_id: exchange_id,
-                target_comp_id: msg.id.topic_id.to_string(),
-                msg_seq_num: msg.id.topic_sequence_n,
-                sending_time: chrono::Utc::now(),
-            },
+            header: header(FixMessageType::NewOrderSingle, exchange_id, &msg.id),
             body: fixer_upper::Body {
                 cl_ord_id: Some(msg.id.topic_id),
                 handl_inst: Some(fixer_upper::HandlInst::ManualOrderBestExecution),
                 symbol: Some(symbol.iter().collect()),
-                side: Some(match side {
-                    Side::Ask => fixer_upper::Side::Sell,
-                    Side::Bid => fixer_upper::Side::Buy,
-                }),
+                side: Some(fix_side(side)),
                 transact_time: Some(chrono::Utc::now()),
                 ord_type: Some(fixer_upper::OrderType::Limit),
-                order_qty: Some(quantity as fixer_upper::Price),
-                price: Some(price as fixer_upper::Price),
+                order_qty: Some(quantity),
+                price: Some(price),
+                max_floor: display_quantity,
+                exec_inst: if post_only {
+                    Some(fixer_upper::EXEC_INST_PARTICIPATE_DONT_INITIATE.to_string())
+                } else {
+                    None
+                },
+                self_match_prevention_instruction: match self_trade_prevention {
+                    Some(SelfTradePrevention::CancelNewest) => {
+                        Some(fixer_upper::SelfMatchPreventionInstruction::CancelAggressive)
+                    }
+                    Some(SelfTradePrevention::CancelOldest) => {
+                        Some(fixer_upper::SelfMatchPreventionInstruction::CancelPassive)
+                    }
+                    Some(SelfTradePrevention::CancelBoth) => Some(
+                        fixer_upper::SelfMatchPreventionInstruction::CancelAggressiveAndPassive,
+                    ),
+                    // FIX doesn't have an instruction for decrementing, it's only available as a participant's mode
+                    Some(SelfTradePrevention::Decrement) | None => None,
+                },
+                time_in_force: Some(match time_in_force {
+                    TimeInForce::Day => fixer_upper::TimeInForce::Day,
+                    TimeInForce::GoodTillCancel => fixer_upper::TimeInForce::GoodTillCancel,
+                    TimeInForce::ImmediateOrCancel => fixer_upper::TimeInForce::ImmediateOrCancel,
+                    TimeInForce::FillOrKill => fixer_upper::TimeInForce::FillOrKill,
+                }),
                 ..fixer_upper::Body::default()
             },
-            trailer: fixer_upper::Trailer {
-                signature_length: None,
-                signature: None,
-            },
+            trailer: fixer_upper::Trailer::default(),
         },
         MessageKind::LimitOrderSubmitRequestAcknowledge {
             side,
@@ -86,15 +228,7 @@ pub fn matchbook_message_into_fix_message(msg: Message, exchange_id: String) ->
             id,
             ..
         } => FixMessage {
-            header: fixer_upper::Header {
-                begin_string: fixer_upper::BeginString::Fix_4_4,
-                body_length: None,
-                msg_type: fixer_upper::MessageType::ExecutionReport,
-                sender_comp_id: exchange_id,
-                target_comp_id: msg.id.topic_id.to_string(),
-                msg_seq_num: msg.id.topic_sequence_n,
-                sending_time: chrono::Utc::now(),
-            },
+            header: header(FixMessageType::ExecutionReport, exchange_id, &msg.id),
             body: fixer_upper::Body {
                 order_id: Some(format!("{}", id)), // TODO(will): where does this come from
                 ord_status: Some(fixer_upper::OrderStatus::New),
@@ -102,21 +236,15 @@ pub fn matchbook_message_into_fix_message(msg: Message, exchange_id: String) ->
                 symbol: Some(symbol.iter().collect()),
                 exec_trans_type: Some(fixer_upper::ExecTransType::New),
                 exec_type: Some(fixer_upper::ExecType::New),
-                order_qty: Some(quantity as fixer_upper::Quantity),
-                leaves_qty: Some(quantity as fixer_upper::Quantity),
-                cum_qty: Some(0.0),
-                side: Some(match side {
-                    Side::Ask => fixer_upper::Side::Sell,
-                    Side::Bid => fixer_upper::Side::Buy,
-                }),
-                avg_px: Some(0.0),
+                order_qty: Some(quantity),
+                leaves_qty: Some(quantity),
+                cum_qty: Some(Decimal::ZERO),
+                side: Some(fix_side(side)),
+                avg_px: Some(Decimal::ZERO),
                 ..fixer_upper::Body::default()
             },
 
-            trailer: fixer_upper::Trailer {
-                signature: None,
-                signature_length: None,
-            },
+            trailer: fixer_upper::Trailer::default(),
         },
         MessageKind::Execution {
             id,
@@ -126,15 +254,7 @@ pub fn matchbook_message_into_fix_message(msg: Message, exchange_id: String) ->
             price,
             ..
         } => FixMessage {
-            header: fixer_upper::Header {
-                begin_string: fixer_upper::BeginString::Fix_4_4,
-                body_length: None,
-                msg_type: fixer_upper::MessageType::ExecutionReport,
-                sender_comp_id: exchange_id,
-                target_comp_id: msg.id.topic_id.to_string(),
-                msg_seq_num: msg.id.topic_sequence_n,
-                sending_time: chrono::Utc::now(),
-            },
+            header: header(FixMessageType::ExecutionReport, exchange_id, &msg.id),
             body: fixer_upper::Body {
                 order_id: Some(format!("{}", id)), // TODO(will): where does this come from
                 ord_status: Some(fixer_upper::OrderStatus::New),
@@ -142,21 +262,545 @@ pub fn matchbook_message_into_fix_message(msg: Message, exchange_id: String) ->
                 symbol: Some(symbol.iter().collect()),
                 exec_trans_type: Some(fixer_upper::ExecTransType::New),
                 exec_type: Some(fixer_upper::ExecType::New),
-                order_qty: Some(quantity as fixer_upper::Quantity),
-                leaves_qty: Some(0.0),
-                cum_qty: Some(quantity as fixer_upper::Quantity),
-                side: Some(match side {
-                    Side::Ask => fixer_upper::Side::Sell,
-                    Side::Bid => fixer_upper::Side::Buy,
+                order_qty: Some(quantity),
+                leaves_qty: Some(Decimal::ZERO),
+                cum_qty: Some(quantity),
+                side: Some(fix_side(side)),
+                avg_px: Some(price),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::MarketOrderSubmitRequest {
+            side,
+            symbol,
+            quantity,
+        } => FixMessage {
+            header: header(FixMessageType::NewOrderSingle, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                cl_ord_id: Some(msg.id.topic_id),
+                handl_inst: Some(fixer_upper::HandlInst::ManualOrderBestExecution),
+                symbol: Some(symbol.iter().collect()),
+                side: Some(fix_side(side)),
+                transact_time: Some(chrono::Utc::now()),
+                ord_type: Some(fixer_upper::OrderType::Market),
+                order_qty: Some(quantity),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::MarketOrderSubmitRequestAcknowledge {
+            side,
+            symbol,
+            quantity,
+            id,
+        } => FixMessage {
+            header: header(FixMessageType::ExecutionReport, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                order_id: Some(format!("{}", id)),
+                ord_status: Some(fixer_upper::OrderStatus::New),
+                symbol: Some(symbol.iter().collect()),
+                exec_trans_type: Some(fixer_upper::ExecTransType::New),
+                exec_type: Some(fixer_upper::ExecType::New),
+                ord_type: Some(fixer_upper::OrderType::Market),
+                order_qty: Some(quantity),
+                leaves_qty: Some(quantity),
+                cum_qty: Some(Decimal::ZERO),
+                side: Some(fix_side(side)),
+                avg_px: Some(Decimal::ZERO),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::StopOrderSubmitRequest {
+            side,
+            stop_price,
+            limit_price,
+            quantity,
+            symbol,
+        } => FixMessage {
+            header: header(FixMessageType::NewOrderSingle, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                cl_ord_id: Some(msg.id.topic_id),
+                handl_inst: Some(fixer_upper::HandlInst::ManualOrderBestExecution),
+                symbol: Some(symbol.iter().collect()),
+                side: Some(fix_side(side)),
+                transact_time: Some(chrono::Utc::now()),
+                ord_type: Some(fix_stop_order_type(limit_price)),
+                order_qty: Some(quantity),
+                price: limit_price,
+                stop_px: Some(stop_price),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::StopOrderSubmitRequestAcknowledge {
+            id,
+            side,
+            stop_price,
+            limit_price,
+            quantity,
+            symbol,
+        } => FixMessage {
+            header: header(FixMessageType::ExecutionReport, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                order_id: Some(format!("{}", id)),
+                ord_status: Some(fixer_upper::OrderStatus::New),
+                symbol: Some(symbol.iter().collect()),
+                exec_trans_type: Some(fixer_upper::ExecTransType::New),
+                exec_type: Some(fixer_upper::ExecType::New),
+                ord_type: Some(fix_stop_order_type(limit_price)),
+                order_qty: Some(quantity),
+                price: limit_price,
+                stop_px: Some(stop_price),
+                leaves_qty: Some(quantity),
+                cum_qty: Some(Decimal::ZERO),
+                side: Some(fix_side(side)),
+                avg_px: Some(Decimal::ZERO),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::StopOrderTriggered {
+            id,
+            side,
+            stop_price,
+            limit_price,
+            quantity,
+            symbol,
+        } => FixMessage {
+            header: header(FixMessageType::ExecutionReport, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                order_id: Some(format!("{}", id)),
+                ord_status: Some(fixer_upper::OrderStatus::New),
+                symbol: Some(symbol.iter().collect()),
+                exec_trans_type: Some(fixer_upper::ExecTransType::New),
+                exec_type: Some(fixer_upper::ExecType::TriggeredOrActivatedBySystem),
+                ord_type: Some(fix_stop_order_type(limit_price)),
+                order_qty: Some(quantity),
+                price: limit_price,
+                stop_px: Some(stop_price),
+                leaves_qty: Some(quantity),
+                cum_qty: Some(Decimal::ZERO),
+                side: Some(fix_side(side)),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::OrderCanceled {
+            id,
+            side,
+            quantity,
+            canceled_quantity,
+            symbol,
+        } => FixMessage {
+            header: header(FixMessageType::ExecutionReport, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                order_id: Some(format!("{}", id)),
+                ord_status: Some(fixer_upper::OrderStatus::Canceled),
+                symbol: Some(symbol.iter().collect()),
+                exec_trans_type: Some(fixer_upper::ExecTransType::New),
+                exec_type: Some(fixer_upper::ExecType::Canceled),
+                order_qty: Some(quantity),
+                leaves_qty: Some(Decimal::ZERO),
+                cum_qty: Some(quantity - canceled_quantity),
+                side: Some(fix_side(side)),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::LimitOrderSubmitReject {
+            side,
+            price,
+            quantity,
+            symbol,
+            reason,
+        } => FixMessage {
+            header: header(FixMessageType::ExecutionReport, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                ord_status: Some(fixer_upper::OrderStatus::Rejected),
+                symbol: Some(symbol.iter().collect()),
+                exec_trans_type: Some(fixer_upper::ExecTransType::New),
+                exec_type: Some(fixer_upper::ExecType::Rejected),
+                ord_type: Some(fixer_upper::OrderType::Limit),
+                order_qty: Some(quantity),
+                price: Some(price),
+                leaves_qty: Some(Decimal::ZERO),
+                cum_qty: Some(Decimal::ZERO),
+                side: Some(fix_side(side)),
+                text: Some(reason),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::MarketOrderSubmitReject {
+            side,
+            quantity,
+            symbol,
+            reason,
+        } => FixMessage {
+            header: header(FixMessageType::ExecutionReport, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                ord_status: Some(fixer_upper::OrderStatus::Rejected),
+                symbol: Some(symbol.iter().collect()),
+                exec_trans_type: Some(fixer_upper::ExecTransType::New),
+                exec_type: Some(fixer_upper::ExecType::Rejected),
+                ord_type: Some(fixer_upper::OrderType::Market),
+                order_qty: Some(quantity),
+                leaves_qty: Some(Decimal::ZERO),
+                cum_qty: Some(Decimal::ZERO),
+                side: Some(fix_side(side)),
+                text: Some(reason),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::StopOrderSubmitReject {
+            side,
+            stop_price,
+            limit_price,
+            quantity,
+            symbol,
+            reason,
+        } => FixMessage {
+            header: header(FixMessageType::ExecutionReport, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                ord_status: Some(fixer_upper::OrderStatus::Rejected),
+                symbol: Some(symbol.iter().collect()),
+                exec_trans_type: Some(fixer_upper::ExecTransType::New),
+                exec_type: Some(fixer_upper::ExecType::Rejected),
+                ord_type: Some(fix_stop_order_type(limit_price)),
+                order_qty: Some(quantity),
+                price: limit_price,
+                stop_px: Some(stop_price),
+                leaves_qty: Some(Decimal::ZERO),
+                cum_qty: Some(Decimal::ZERO),
+                side: Some(fix_side(side)),
+                text: Some(reason),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::OrderRestated {
+            id,
+            side,
+            quantity,
+            leaves_quantity,
+            symbol,
+        } => FixMessage {
+            header: header(FixMessageType::ExecutionReport, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                order_id: Some(format!("{}", id)),
+                ord_status: Some(if leaves_quantity < quantity {
+                    fixer_upper::OrderStatus::PartiallyFilled
+                } else {
+                    fixer_upper::OrderStatus::New
                 }),
-                avg_px: Some(price as fixer_upper::Price),
+                symbol: Some(symbol.iter().collect()),
+                exec_trans_type: Some(fixer_upper::ExecTransType::New),
+                exec_type: Some(fixer_upper::ExecType::Restated),
+                order_qty: Some(quantity),
+                leaves_qty: Some(leaves_quantity),
+                cum_qty: Some(quantity - leaves_quantity),
+                side: Some(fix_side(side)),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::OrderExpired {
+            id,
+            side,
+            quantity,
+            expired_quantity,
+            symbol,
+        } => FixMessage {
+            header: header(FixMessageType::ExecutionReport, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                order_id: Some(format!("{}", id)),
+                ord_status: Some(fixer_upper::OrderStatus::Expired),
+                symbol: Some(symbol.iter().collect()),
+                exec_trans_type: Some(fixer_upper::ExecTransType::New),
+                exec_type: Some(fixer_upper::ExecType::Expired),
+                order_qty: Some(quantity),
+                leaves_qty: Some(Decimal::ZERO),
+                cum_qty: Some(quantity - expired_quantity),
+                side: Some(fix_side(side)),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::CancelRequest { id, symbol } => FixMessage {
+            header: header(FixMessageType::OrderCancelRequest, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                order_id: Some(format!("{}", id)),
+                symbol: Some(symbol.iter().collect()),
+                transact_time: Some(chrono::Utc::now()),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::CancelAcknowledge {
+            id,
+            side,
+            price,
+            quantity,
+            canceled_quantity,
+            symbol,
+        } => FixMessage {
+            header: header(FixMessageType::ExecutionReport, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                order_id: Some(format!("{}", id)),
+                ord_status: Some(fixer_upper::OrderStatus::Canceled),
+                symbol: Some(symbol.iter().collect()),
+                exec_trans_type: Some(fixer_upper::ExecTransType::New),
+                exec_type: Some(fixer_upper::ExecType::Canceled),
+                order_qty: Some(quantity),
+                leaves_qty: Some(Decimal::ZERO),
+                cum_qty: Some(quantity - canceled_quantity),
+                side: Some(fix_side(side)),
+                price: Some(price),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::CancelReject { id, reason } => FixMessage {
+            header: header(FixMessageType::OrderCancelReject, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                order_id: Some(format!("{}", id)),
+                ord_status: Some(fixer_upper::OrderStatus::Rejected),
+                cxl_rej_response_to: Some(fixer_upper::CxlRejResponseTo::OrderCancelRequest),
+                text: Some(reason),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::ReplaceRequest {
+            id,
+            price,
+            quantity,
+            symbol,
+        } => FixMessage {
+            header: header(
+                FixMessageType::OrderCancelReplaceRequest,
+                exchange_id,
+                &msg.id,
+            ),
+            body: fixer_upper::Body {
+                order_id: Some(format!("{}", id)),
+                transact_time: Some(chrono::Utc::now()),
+                ord_type: Some(fixer_upper::OrderType::Limit),
+                order_qty: Some(quantity),
+                price: Some(price),
+                symbol: Some(symbol.iter().collect()),
+                ..fixer_upper::Body::default()
+            },
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::ReplaceAcknowledge {
+            id,
+            side,
+            price,
+            quantity,
+            leaves_quantity,
+            symbol,
+        } => FixMessage {
+            header: header(FixMessageType::ExecutionReport, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                order_id: Some(format!("{}", id)),
+                ord_status: Some(fixer_upper::OrderStatus::Replaced),
+                symbol: Some(symbol.iter().collect()),
+                exec_trans_type: Some(fixer_upper::ExecTransType::New),
+                exec_type: Some(fixer_upper::ExecType::Replaced),
+                order_qty: Some(quantity),
+                leaves_qty: Some(leaves_quantity),
+                cum_qty: Some(quantity - leaves_quantity),
+                side: Some(fix_side(side)),
+                price: Some(price),
                 ..fixer_upper::Body::default()
             },
-            trailer: fixer_upper::Trailer {
-                signature: None,
-                signature_length: None,
+            trailer: fixer_upper::Trailer::default(),
+        },
+        MessageKind::ReplaceReject { id, reason } => FixMessage {
+            header: header(FixMessageType::OrderCancelReject, exchange_id, &msg.id),
+            body: fixer_upper::Body {
+                order_id: Some(format!("{}", id)),
+                ord_status: Some(fixer_upper::OrderStatus::Rejected),
+                cxl_rej_response_to: Some(fixer_upper::CxlRejResponseTo::OrderCancelReplaceRequest),
+                text: Some(reason),
+                ..fixer_upper::Body::default()
             },
+            trailer: fixer_upper::Trailer::default(),
         },
-        x => unimplemented!("{:?}", x),
+        x => return Err(format!("no FIX message for {:?}", x).into()),
+    })
+}
+
+fn header(msg_type: FixMessageType, exchange_id: String, id: &MessageId) -> fixer_upper::Header {
+    fixer_upper::Header {
+        begin_string: fixer_upper::BeginString::Fix_4_4,
+        body_length: None,
+        msg_type,
+        sender_comp_id: exchange_id,
+        target_comp_id: id.topic_id.to_string(),
+        msg_seq_num: id.topic_sequence_n,
+        poss_dup_flag: None,
+        sending_time: chrono::Utc::now(),
+        orig_sending_time: None,
+    }
+}
+
+fn matchbook_side(side: fixer_upper::Side) -> Side {
+    match side {
+        fixer_upper::Side::Buy => Side::Bid,
+        fixer_upper::Side::Sell => Side::Ask,
+    }
+}
+
+/// `field`, or a rejection for leaving it out
+fn required<T>(field: Option<T>, tag: u64, name: &str) -> Result<T, Rejection> {
+    field.ok_or_else(|| Rejection::Session {
+        reason: SessionRejectReason::RequiredTagMissing,
+        ref_tag_id: Some(tag),
+        text: format!("missing required field '{}'", name),
+    })
+}
+
+/// A rejection for a value of field `tag` that isn't allowed
+fn incorrect(tag: u64, text: String) -> Rejection {
+    Rejection::Session {
+        reason: SessionRejectReason::ValueIsIncorrect,
+        ref_tag_id: Some(tag),
+        text,
+    }
+}
+
+fn parse_order_id(order_id: &str) -> Result<OrderId, Rejection> {
+    order_id
+        .parse()
+        .map_err(|e| incorrect(37, format!("invalid value for field 'OrderID': {}", e)))
+}
+
+/// Symbols are exactly 4 ASCII characters, anything else is rejected rather than cut short to fit
+fn matchbook_symbol(symbol: &str) -> Result<SymbolOwned, Rejection> {
+    if !symbol.is_ascii() {
+        return Err(incorrect(55, "field 'Symbol' must be ASCII".to_string()));
+    }
+    match symbol.chars().collect::<Vec<_>>()[..] {
+        [first, second, third, fourth] => Ok([first, second, third, fourth]),
+        _ => Err(incorrect(
+            55,
+            "incorrect length for field 'Symbol'".to_string(),
+        )),
+    }
+}
+
+fn fix_side(side: Side) -> fixer_upper::Side {
+    match side {
+        Side::Ask => fixer_upper::Side::Sell,
+        Side::Bid => fixer_upper::Side::Buy,
+    }
+}
+
+/// FIX order type of a stop order, which is a stop limit order if it has a limit price
+fn fix_stop_order_type(limit_price: Option<Price>) -> fixer_upper::OrderType {
+    match limit_price {
+        Some(_) => fixer_upper::OrderType::StopLimit,
+        None => fixer_upper::OrderType::Stop,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fixer_upper::{BeginString, Body, Header, Trailer};
+    use std::str::FromStr;
+
+    fn client(msg_type: FixMessageType, body: Body) -> FixMessage {
+        FixMessage {
+            header: Header {
+                begin_string: BeginString::Fix_4_4,
+                body_length: None,
+                msg_type,
+                sender_comp_id: "buyer".to_string(),
+                target_comp_id: "matchbook".to_string(),
+                msg_seq_num: 2,
+                poss_dup_flag: None,
+                sending_time: chrono::Utc::now(),
+                orig_sending_time: None,
+            },
+            body,
+            trailer: Trailer::default(),
+        }
+    }
+
+    fn limit_order(symbol: &str, price: Option<Price>) -> FixMessage {
+        client(
+            FixMessageType::NewOrderSingle,
+            Body {
+                cl_ord_id: Some("1".to_string()),
+                symbol: Some(symbol.to_string()),
+                side: Some(fixer_upper::Side::Buy),
+                ord_type: Some(fixer_upper::OrderType::Limit),
+                order_qty: Some(Decimal::from_str("10").unwrap()),
+                price,
+                ..Body::default()
+            },
+        )
+    }
+
+    fn convert(message: FixMessage) -> Result<Message, Rejection> {
+        fix_message_into_matchbook_message(message, ServiceId::from_str("port:1").unwrap())
+    }
+
+    #[test]
+    fn rejects_messages_that_break_the_rules() {
+        let price = Some(Decimal::from_str("100").unwrap());
+        assert!(convert(limit_order("ABCD", price)).is_ok());
+
+        assert_eq!(
+            convert(limit_order("ABCD", None)).unwrap_err(),
+            Rejection::Session {
+                reason: SessionRejectReason::RequiredTagMissing,
+                ref_tag_id: Some(44),
+                text: "missing required field 'Price'".to_string(),
+            }
+        );
+        for symbol in &["ABC", "ADBEX"] {
+            assert_eq!(
+                convert(limit_order(symbol, price)).unwrap_err(),
+                Rejection::Session {
+                    reason: SessionRejectReason::ValueIsIncorrect,
+                    ref_tag_id: Some(55),
+                    text: "incorrect length for field 'Symbol'".to_string(),
+                }
+            );
+        }
+        assert_eq!(
+            convert(limit_order("ADBÉ", price)).unwrap_err(),
+            Rejection::Session {
+                reason: SessionRejectReason::ValueIsIncorrect,
+                ref_tag_id: Some(55),
+                text: "field 'Symbol' must be ASCII".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_message_types() {
+        let status_request = client(
+            FixMessageType::OrderStatusRequest,
+            Body {
+                cl_ord_id: Some("1".to_string()),
+                ..Body::default()
+            },
+        );
+        assert_eq!(
+            convert(status_request).unwrap_err(),
+            Rejection::Business {
+                reason: BusinessRejectReason::UnsupportedMessageType,
+                ref_id: Some("1".to_string()),
+                text: "unsupported message type 'OrderStatusRequest'".to_string(),
+            }
+        );
     }
 }
@@ -0,0 +1,998 @@
+//! The FIX session layer between a client and the port: sequence numbers, heartbeats and recovering from gaps in
+//! either direction. The only IO in here is writing the session's progress to its journal, the client handler feeds in
+//! messages and the time and carries out the actions that come back
+use crate::message::Rejection;
+use crate::store::{SessionJournal, SessionState, MAX_RETAINED};
+use fixer_upper::{
+    BeginString, Body, Header, Message as FixMessage, MessageType as FixMessageType,
+    SessionRejectReason, Trailer,
+};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// Longest heartbeat interval a client can ask for, in seconds
+pub const MAX_HEART_BT_INT: u64 = 300;
+/// Most messages held back waiting for a gap to be filled before giving up on the client
+pub const MAX_QUEUED: usize = 1000;
+
+/// What the client handler should do after a message or a timer has been handled, in order
+#[derive(Debug)]
+pub enum Action {
+    /// Write a message to the client
+    Send(FixMessage),
+    /// Pass an application message from the client on to the backbone
+    Deliver(FixMessage),
+    /// Close the connection
+    Disconnect,
+}
+
+/// A logged on FIX session with a single client
+#[derive(Debug)]
+pub struct Session {
+    exchange_id: String,
+    participant_id: String,
+    /// Zero turns heartbeats and test requests off
+    heart_bt_int: Duration,
+    /// Sequence number of the client's logon
+    logon_seq_num: u64,
+    /// Sequence number the next message from the client should have
+    next_inbound: u64,
+    /// Sequence number of the next message to the client
+    next_outbound: u64,
+    /// Application messages sent to the client, so resend requests can be answered
+    sent: BTreeMap<u64, FixMessage>,
+    /// Messages that arrived ahead of a gap, waiting for the client to resend what's missing. `None` is a message that
+    /// was acted on as soon as it arrived, it only takes up its sequence number
+    queued: BTreeMap<u64, Option<FixMessage>>,
+    /// The last missing sequence number while a resend request is outstanding
+    resend_until: Option<u64>,
+    last_received: Instant,
+    last_sent: Instant,
+    /// The test request the client hasn't answered yet and when it was sent
+    test_request: Option<(String, Instant)>,
+    test_request_count: u64,
+    /// Where the session's progress is kept between connections, if anywhere
+    journal: Option<SessionJournal>,
+}
+
+impl Session {
+    /// Start a session from the client's `Logon`, carrying on from `restored` unless the client asks to reset sequence
+    /// numbers. Returns the session and what to send back. The heartbeat interval is whatever the client asked for. A
+    /// logon that can't be accepted gets the `Logout` to send back instead
+    pub fn logon(
+        logon: FixMessage,
+        exchange_id: String,
+        restored: SessionState,
+        journal: Option<SessionJournal>,
+        now: Instant,
+    ) -> Result<(Self, Vec<Action>), Box<FixMessage>> {
+        let n = logon.header.msg_seq_num;
+        let reset = logon.body.reset_seq_num_flag == Some(true);
+        let restored = if reset {
+            SessionState::default()
+        } else {
+            restored
+        };
+
+        let rejection = match logon.body.heart_bt_int {
+            None => Some("missing required field 'HeartBtInt'".to_string()),
+            Some(x) if x > MAX_HEART_BT_INT => Some(format!(
+                "HeartBtInt {} is longer than the maximum of {}",
+                x, MAX_HEART_BT_INT
+            )),
+            _ if reset && n != 1 => {
+                Some("MsgSeqNum has to be 1 when ResetSeqNumFlag is set".to_string())
+            }
+            _ if n < restored.next_inbound => Some(format!(
+                "MsgSeqNum too low, expecting {} but received {}",
+                restored.next_inbound, n
+            )),
+            _ => None,
+        };
+
+        let mut session = Self {
+            exchange_id,
+            participant_id: logon.header.sender_comp_id.clone(),
+            heart_bt_int: Duration::from_secs(logon.body.heart_bt_int.unwrap_or(0)),
+            logon_seq_num: n,
+            next_inbound: restored.next_inbound,
+            next_outbound: restored.next_outbound,
+            sent: restored.sent,
+            queued: BTreeMap::new(),
+            resend_until: None,
+            last_received: now,
+            last_sent: now,
+            test_request: None,
+            test_request_count: 0,
+            journal,
+        };
+
+        if let Some(text) = rejection {
+            warn!(%session.participant_id, "rejecting logon: {}", text);
+            let logout = session.send_admin(
+                FixMessageType::Logout,
+                Body {
+                    text: Some(text),
+                    ..Body::default()
+                },
+                now,
+            );
+            return Err(Box::new(logout));
+        }
+
+        if reset {
+            info!(%session.participant_id, "client reset sequence numbers");
+            if let Some(Err(e)) = session.journal.as_mut().map(SessionJournal::reset) {
+                error!("failed to reset session journal: {}", e);
+            }
+        }
+
+        let reply = session.send_admin(
+            FixMessageType::Logon,
+            Body {
+                encrypt_method: Some(0),
+                heart_bt_int: logon.body.heart_bt_int,
+                reset_seq_num_flag: if reset { Some(true) } else { None },
+                ..Body::default()
+            },
+            now,
+        );
+        let mut actions = vec![Action::Send(reply)];
+        // the logon itself takes up a sequence number, a logon that's ahead asks for what came before it
+        actions.extend(session.receive(logon, now));
+        Ok((session, actions))
+    }
+
+    /// Handle a message from the client
+    pub fn receive(&mut self, message: FixMessage, now: Instant) -> Vec<Action> {
+        self.last_received = now;
+        self.test_request = None;
+
+        let mut actions = vec![];
+        let next_inbound = self.next_inbound;
+        let n = message.header.msg_seq_num;
+        let msg_type = message.header.msg_type;
+        let gap_fill = message.body.gap_fill_flag == Some(true);
+
+        if msg_type == FixMessageType::SequenceReset && !gap_fill {
+            // a reset ignores sequence numbers altogether
+            self.reset(&message, now, &mut actions);
+        } else if n < self.next_inbound {
+            if message.header.poss_dup_flag == Some(true) {
+                trace!(n, "ignoring possible duplicate we've already seen");
+                return actions;
+            }
+
+            let text = format!(
+                "MsgSeqNum too low, expecting {} but received {}",
+                self.next_inbound, n
+            );
+            error!("{}, terminating connection", text);
+            self.logout(text, now, &mut actions);
+            return actions;
+        } else if n > self.next_inbound && msg_type != FixMessageType::Logout {
+            if self.queued.len() >= MAX_QUEUED && !self.queued.contains_key(&n) {
+                let text = format!(
+                    "more than {} messages received ahead of MsgSeqNum {}",
+                    MAX_QUEUED, self.next_inbound
+                );
+                error!("{}, terminating connection", text);
+                self.logout(text, now, &mut actions);
+                return actions;
+            }
+
+            if msg_type == FixMessageType::ResendRequest {
+                // answered straight away, otherwise both sides could end up waiting on each other's resend
+                self.process_resend_request(&message, now, &mut actions);
+                self.queued.insert(n, None);
+            } else {
+                self.queued.insert(n, Some(message));
+            }
+        } else {
+            self.process(message, now, &mut actions);
+        }
+
+        // catch up on whatever was waiting behind a gap that's now been filled
+        loop {
+            if matches!(actions.last(), Some(Action::Disconnect)) {
+                break;
+            }
+            self.queued = self.queued.split_off(&self.next_inbound);
+            match self.queued.remove(&self.next_inbound) {
+                Some(Some(message)) => self.process(message, now, &mut actions),
+                Some(None) => self.next_inbound += 1,
+                None => break,
+            }
+        }
+
+        if self.next_inbound != next_inbound {
+            let next_inbound = self.next_inbound;
+            if let Some(Err(e)) = self.journal.as_mut().map(|x| x.received(next_inbound)) {
+                error!("failed to journal received message: {}", e);
+            }
+            self.compact_journal();
+        }
+        if matches!(actions.last(), Some(Action::Disconnect)) {
+            return actions;
+        }
+
+        if let Some(until) = self.resend_until {
+            if self.next_inbound > until {
+                self.resend_until = None;
+            }
+        }
+        if self.resend_until.is_none() {
+            if let Some(&first) = self.queued.keys().next() {
+                debug!(
+                    from = self.next_inbound,
+                    to = first - 1,
+                    "requesting resend"
+                );
+                let request = self.send_admin(
+                    FixMessageType::ResendRequest,
+                    Body {
+                        begin_seq_no: Some(self.next_inbound),
+                        // everything from there on
+                        end_seq_no: Some(0),
+                        ..Body::default()
+                    },
+                    now,
+                );
+                actions.push(Action::Send(request));
+                self.resend_until = Some(first - 1);
+            }
+        }
+        actions
+    }
+
+    /// Stamp an application message for the client with the next sequence number, keeping it in case it needs to be
+    /// resent
+    pub fn send(&mut self, mut message: FixMessage, now: Instant) -> FixMessage {
+        message.header.sender_comp_id = self.exchange_id.clone();
+        message.header.target_comp_id = self.participant_id.clone();
+        message.header.msg_seq_num = self.next_outbound;
+        message.header.poss_dup_flag = None;
+        message.header.orig_sending_time = None;
+        message.header.sending_time = chrono::Utc::now();
+
+        let resendable = !is_session_message(message.header.msg_type);
+        if let Some(Err(e)) = self.journal.as_mut().map(|x| x.sent(&message, resendable)) {
+            error!("failed to journal sent message: {}", e);
+        }
+        if resendable {
+            self.sent.insert(self.next_outbound, message.clone());
+            // anything older is gap filled if it's asked for again
+            if self.sent.len() > MAX_RETAINED {
+                let first = *self.sent.keys().next().expect("sent isn't empty");
+                self.sent.remove(&first);
+            }
+        }
+        self.next_outbound += 1;
+        self.last_sent = now;
+        self.compact_journal();
+        message
+    }
+
+    /// Rewrite the journal as where the session is up to once it's grown long enough
+    fn compact_journal(&mut self) {
+        if let Some(journal) = self.journal.as_mut() {
+            if journal.needs_compaction() {
+                if let Err(e) = journal.compact(self.next_inbound, self.next_outbound, &self.sent) {
+                    error!("failed to compact session journal: {}", e);
+                }
+            }
+        }
+    }
+
+    /// When `poll_timers` next has something to do, if ever
+    pub fn next_timer(&self) -> Option<Instant> {
+        if self.heart_bt_int == Duration::from_secs(0) {
+            return None;
+        }
+
+        let heartbeat = self.last_sent + self.heart_bt_int;
+        let test_request = match &self.test_request {
+            // give up on the client if it doesn't answer within another interval
+            Some((_, sent_at)) => *sent_at + self.heart_bt_int,
+            None => self.last_received + self.heart_bt_int + self.heart_bt_int / 5,
+        };
+        Some(heartbeat.min(test_request))
+    }
+
+    /// Send a heartbeat if we've been quiet, check on the client if it has been, and give up on it if it doesn't answer
+    pub fn poll_timers(&mut self, now: Instant) -> Vec<Action> {
+        let mut actions = vec![];
+        if self.heart_bt_int == Duration::from_secs(0) {
+            return actions;
+        }
+
+        if let Some((id, sent_at)) = &self.test_request {
+            if now >= *sent_at + self.heart_bt_int {
+                let text = format!("test request {} wasn't answered", id);
+                warn!("{}, terminating connection", text);
+                self.logout(text, now, &mut actions);
+                return actions;
+            }
+        } else if now >= self.last_received + self.heart_bt_int + self.heart_bt_int / 5 {
+            self.test_request_count += 1;
+            let id = self.test_request_count.to_string();
+            debug!(%id, "client has gone quiet, sending test request");
+            let request = self.send_admin(
+                FixMessageType::TestRequest,
+                Body {
+                    test_req_id: Some(id.clone()),
+                    ..Body::default()
+                },
+                now,
+            );
+            actions.push(Action::Send(request));
+            self.test_request = Some((id, now));
+        }
+
+        if now >= self.last_sent + self.heart_bt_int {
+            let heartbeat = self.send_admin(FixMessageType::HeartBeat, Body::default(), now);
+            actions.push(Action::Send(heartbeat));
+        }
+        actions
+    }
+
+    /// Log the client out, telling it why
+    pub fn end(&mut self, text: String, now: Instant) -> Vec<Action> {
+        let mut actions = vec![];
+        self.logout(text, now, &mut actions);
+        actions
+    }
+
+    /// Tell the client why `rejected` was turned away, with a `Reject (3)` or a `BusinessMessageReject (j)`
+    pub fn reject(&mut self, rejected: &Header, rejection: Rejection, now: Instant) -> FixMessage {
+        let mut body = Body {
+            ref_seq_num: Some(rejected.msg_seq_num),
+            // there's no MsgType to refer back to when it's one we don't know
+            ref_msg_type: Some(rejected.msg_type).filter(|&x| x != FixMessageType::Unsupported),
+            ..Body::default()
+        };
+        let msg_type = match rejection {
+            Rejection::Session {
+                reason,
+                ref_tag_id,
+                text,
+            } => {
+                body.session_reject_reason = Some(reason);
+                body.ref_tag_id = ref_tag_id;
+                body.text = Some(text);
+                FixMessageType::Reject
+            }
+            Rejection::Business {
+                reason,
+                ref_id,
+                text,
+            } => {
+                body.business_reject_reason = Some(reason);
+                body.business_reject_ref_id = ref_id;
+                body.text = Some(text);
+                FixMessageType::BusinessMessageReject
+            }
+        };
+        self.send_admin(msg_type, body, now)
+    }
+
+    /// Act on a message that's next in sequence
+    fn process(&mut self, message: FixMessage, now: Instant, actions: &mut Vec<Action>) {
+        let n = message.header.msg_seq_num;
+        self.next_inbound = n + 1;
+
+        match message.header.msg_type {
+            FixMessageType::HeartBeat => {}
+            FixMessageType::TestRequest => {
+                let heartbeat = self.send_admin(
+                    FixMessageType::HeartBeat,
+                    Body {
+                        test_req_id: message.body.test_req_id,
+                        ..Body::default()
+                    },
+                    now,
+                );
+                actions.push(Action::Send(heartbeat));
+            }
+            FixMessageType::ResendRequest => self.process_resend_request(&message, now, actions),
+            FixMessageType::SequenceReset => match message.body.new_seq_no {
+                Some(x) if x > n => self.next_inbound = x,
+                x => {
+                    warn!(n, new_seq_no = ?x, "rejecting gap fill that doesn't move forward");
+                    let text = format!("NewSeqNo must be higher than MsgSeqNum {}", n);
+                    let reject = self.reject(&message.header, new_seq_no_rejection(x, text), now);
+                    actions.push(Action::Send(reject));
+                }
+            },
+            FixMessageType::Logout => {
+                info!("client logged out");
+                let logout = self.send_admin(FixMessageType::Logout, Body::default(), now);
+                actions.push(Action::Send(logout));
+                actions.push(Action::Disconnect);
+            }
+            // the logon was handled when the session started, any other one is a mistake
+            FixMessageType::Logon if n == self.logon_seq_num => {}
+            FixMessageType::Logon => {
+                warn!(n, "ignoring logon for a session that's already logged on")
+            }
+            FixMessageType::Reject => warn!(?message, "client rejected a message"),
+            _ => actions.push(Action::Deliver(message)),
+        }
+    }
+
+    fn process_resend_request(
+        &mut self,
+        request: &FixMessage,
+        now: Instant,
+        actions: &mut Vec<Action>,
+    ) {
+        let begin = request.body.begin_seq_no.unwrap_or(1);
+        let end = request.body.end_seq_no.unwrap_or(0);
+        self.resend(begin, end, now, actions);
+    }
+
+    /// Move the next expected sequence number to wherever the client's `SequenceReset` says
+    fn reset(&mut self, reset: &FixMessage, now: Instant, actions: &mut Vec<Action>) {
+        match reset.body.new_seq_no {
+            Some(x) if x >= self.next_inbound => {
+                debug!(
+                    from = self.next_inbound,
+                    to = x,
+                    "client reset sequence numbers"
+                );
+                self.next_inbound = x;
+            }
+            x => {
+                warn!(
+                    next_inbound = self.next_inbound,
+                    new_seq_no = ?x,
+                    "rejecting sequence reset that would go backwards"
+                );
+                let text = format!("NewSeqNo can't be lower than {}", self.next_inbound);
+                let reject = self.reject(&reset.header, new_seq_no_rejection(x, text), now);
+                actions.push(Action::Send(reject));
+            }
+        }
+    }
+
+    /// Send everything from `begin` to `end` again. Application messages are resent as possible duplicates, session
+    /// messages would be stale and ones older than `MAX_RETAINED` are gone, so runs of them are skipped over with a gap
+    /// fill
+    fn resend(&mut self, begin: u64, end: u64, now: Instant, actions: &mut Vec<Action>) {
+        let last = self.next_outbound - 1;
+        let end = if end == 0 || end > last { last } else { end };
+        debug!(begin, end, "resending messages");
+
+        let mut gap_from = None;
+        for n in begin.max(1)..=end {
+            let message = match self.sent.get(&n) {
+                Some(x) if !is_session_message(x.header.msg_type) => x,
+                _ => {
+                    gap_from.get_or_insert(n);
+                    continue;
+                }
+            };
+
+            if let Some(from) = gap_from.take() {
+                actions.push(Action::Send(self.gap_fill(from, n)));
+            }
+            let mut message = message.clone();
+            message.header.poss_dup_flag = Some(true);
+            message.header.orig_sending_time = Some(message.header.sending_time);
+            message.header.sending_time = chrono::Utc::now();
+            actions.push(Action::Send(message));
+        }
+        if let Some(from) = gap_from {
+            actions.push(Action::Send(self.gap_fill(from, end + 1)));
+        }
+        self.last_sent = now;
+    }
+
+    fn gap_fill(&self, from: u64, to: u64) -> FixMessage {
+        let mut gap_fill = message(
+            FixMessageType::SequenceReset,
+            self.exchange_id.clone(),
+            self.participant_id.clone(),
+            from,
+            Body {
+                gap_fill_flag: Some(true),
+                new_seq_no: Some(to),
+                ..Body::default()
+            },
+        );
+        gap_fill.header.poss_dup_flag = Some(true);
+        gap_fill
+    }
+
+    fn logout(&mut self, text: String, now: Instant, actions: &mut Vec<Action>) {
+        let logout = self.send_admin(
+            FixMessageType::Logout,
+            Body {
+                text: Some(text),
+                ..Body::default()
+            },
+            now,
+        );
+        actions.push(Action::Send(logout));
+        actions.push(Action::Disconnect);
+    }
+
+    fn send_admin(&mut self, msg_type: FixMessageType, body: Body, now: Instant) -> FixMessage {
+        let message = message(
+            msg_type,
+            self.exchange_id.clone(),
+            self.participant_id.clone(),
+            0,
+            body,
+        );
+        self.send(message, now)
+    }
+}
+
+/// The `Logout` for a logon that's turned away before a session is started. It isn't part of any session, so it's
+/// numbered 1 and not journaled
+pub fn reject_logon(logon: &FixMessage, exchange_id: String, text: String) -> FixMessage {
+    message(
+        FixMessageType::Logout,
+        exchange_id,
+        logon.header.sender_comp_id.clone(),
+        1,
+        Body {
+            text: Some(text),
+            ..Body::default()
+        },
+    )
+}
+
+/// Rejection of a `SequenceReset` whose `NewSeqNo` is missing, or is there and `incorrect` says what's wrong with it
+fn new_seq_no_rejection(new_seq_no: Option<u64>, incorrect: String) -> Rejection {
+    let (reason, text) = match new_seq_no {
+        Some(_) => (SessionRejectReason::ValueIsIncorrect, incorrect),
+        None => (
+            SessionRejectReason::RequiredTagMissing,
+            "missing required field 'NewSeqNo'".to_string(),
+        ),
+    };
+    Rejection::Session {
+        reason,
+        ref_tag_id: Some(36),
+        text,
+    }
+}
+
+/// Session level messages that are skipped over rather than resent. A `Reject` is still worth resending
+fn is_session_message(msg_type: FixMessageType) -> bool {
+    matches!(
+        msg_type,
+        FixMessageType::HeartBeat
+            | FixMessageType::TestRequest
+            | FixMessageType::ResendRequest
+            | FixMessageType::SequenceReset
+            | FixMessageType::Logout
+            | FixMessageType::Logon
+    )
+}
+
+fn message(
+    msg_type: FixMessageType,
+    sender_comp_id: String,
+    target_comp_id: String,
+    msg_seq_num: u64,
+    body: Body,
+) -> FixMessage {
+    FixMessage {
+        header: Header {
+            begin_string: BeginString::Fix_4_4,
+            body_length: None,
+            msg_type,
+            sender_comp_id,
+            target_comp_id,
+            msg_seq_num,
+            poss_dup_flag: None,
+            sending_time: chrono::Utc::now(),
+            orig_sending_time: None,
+        },
+        body,
+        trailer: Trailer::default(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn client(msg_type: FixMessageType, msg_seq_num: u64, body: Body) -> FixMessage {
+        message(
+            msg_type,
+            "buyer".to_string(),
+            "matchbook".to_string(),
+            msg_seq_num,
+            body,
+        )
+    }
+
+    fn logon(msg_seq_num: u64, heart_bt_int: Option<u64>) -> FixMessage {
+        client(
+            FixMessageType::Logon,
+            msg_seq_num,
+            Body {
+                heart_bt_int,
+                ..Body::default()
+            },
+        )
+    }
+
+    /// Log on with nothing to carry on from
+    fn start(logon: FixMessage, now: Instant) -> Result<(Session, Vec<Action>), Box<FixMessage>> {
+        Session::logon(
+            logon,
+            "matchbook".to_string(),
+            SessionState::default(),
+            None,
+            now,
+        )
+    }
+
+    fn order(msg_seq_num: u64) -> FixMessage {
+        client(FixMessageType::NewOrderSingle, msg_seq_num, Body::default())
+    }
+
+    fn possible_duplicate(mut message: FixMessage) -> FixMessage {
+        message.header.poss_dup_flag = Some(true);
+        message
+    }
+
+    /// What each action was, as the message type and sequence number of anything sent or delivered
+    fn summary(actions: &[Action]) -> Vec<(&'static str, FixMessageType, u64)> {
+        actions
+            .iter()
+            .map(|x| match x {
+                Action::Send(x) => ("send", x.header.msg_type, x.header.msg_seq_num),
+                Action::Deliver(x) => ("deliver", x.header.msg_type, x.header.msg_seq_num),
+                Action::Disconnect => ("disconnect", FixMessageType::Logout, 0),
+            })
+            .collect()
+    }
+
+    fn sent(actions: &[Action]) -> Vec<&FixMessage> {
+        actions
+            .iter()
+            .filter_map(|x| match x {
+                Action::Send(x) => Some(x),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn negotiates_the_heartbeat_interval_on_logon() {
+        let (session, actions) = start(logon(1, Some(30)), Instant::now()).unwrap();
+        let reply = sent(&actions)[0];
+        assert_eq!(reply.header.msg_type, FixMessageType::Logon);
+        assert_eq!(reply.body.heart_bt_int, Some(30));
+        assert_eq!(session.heart_bt_int, Duration::from_secs(30));
+        assert_eq!(session.next_inbound, 2);
+
+        for heart_bt_int in &[None, Some(MAX_HEART_BT_INT + 1)] {
+            let logout = start(logon(1, *heart_bt_int), Instant::now()).unwrap_err();
+            assert_eq!(logout.header.msg_type, FixMessageType::Logout);
+            assert!(logout.body.text.is_some());
+        }
+    }
+
+    #[test]
+    fn asks_for_a_gap_to_be_resent_and_catches_up() {
+        let now = Instant::now();
+        let (mut session, _) = start(logon(1, Some(30)), now).unwrap();
+
+        let actions = session.receive(order(4), now);
+        let request = sent(&actions)[0];
+        assert_eq!(request.header.msg_type, FixMessageType::ResendRequest);
+        assert_eq!(request.body.begin_seq_no, Some(2));
+        assert_eq!(request.body.end_seq_no, Some(0));
+
+        // another message past the gap doesn't ask again
+        assert!(session.receive(order(5), now).is_empty());
+
+        let actions = session.receive(possible_duplicate(order(2)), now);
+        assert_eq!(
+            summary(&actions),
+            vec![("deliver", FixMessageType::NewOrderSingle, 2)]
+        );
+
+        let gap_fill = client(
+            FixMessageType::SequenceReset,
+            3,
+            Body {
+                gap_fill_flag: Some(true),
+                new_seq_no: Some(4),
+                ..Body::default()
+            },
+        );
+        let actions = session.receive(possible_duplicate(gap_fill), now);
+        assert_eq!(
+            summary(&actions),
+            vec![
+                ("deliver", FixMessageType::NewOrderSingle, 4),
+                ("deliver", FixMessageType::NewOrderSingle, 5),
+            ]
+        );
+
+        // the client resending everything from 2 again is ignored
+        assert!(session
+            .receive(possible_duplicate(order(4)), now)
+            .is_empty());
+        assert_eq!(session.next_inbound, 6);
+    }
+
+    #[test]
+    fn rejects_sequence_resets_that_dont_move_forward() {
+        let now = Instant::now();
+        let (mut session, _) = start(logon(1, Some(30)), now).unwrap();
+        session.receive(order(2), now);
+
+        let reset = client(
+            FixMessageType::SequenceReset,
+            3,
+            Body {
+                new_seq_no: Some(2),
+                ..Body::default()
+            },
+        );
+        let actions = session.receive(reset, now);
+        let reject = sent(&actions)[0];
+        assert_eq!(reject.header.msg_type, FixMessageType::Reject);
+        assert_eq!(reject.body.ref_seq_num, Some(3));
+        assert_eq!(reject.body.ref_tag_id, Some(36));
+        assert_eq!(
+            reject.body.ref_msg_type,
+            Some(FixMessageType::SequenceReset)
+        );
+        assert_eq!(
+            reject.body.session_reject_reason,
+            Some(SessionRejectReason::ValueIsIncorrect)
+        );
+        assert_eq!(session.next_inbound, 3);
+
+        // a gap fill still uses up its sequence number
+        let gap_fill = client(
+            FixMessageType::SequenceReset,
+            3,
+            Body {
+                gap_fill_flag: Some(true),
+                ..Body::default()
+            },
+        );
+        let actions = session.receive(gap_fill, now);
+        let reject = sent(&actions)[0];
+        assert_eq!(reject.header.msg_type, FixMessageType::Reject);
+        assert_eq!(
+            reject.body.session_reject_reason,
+            Some(SessionRejectReason::RequiredTagMissing)
+        );
+        assert_eq!(session.next_inbound, 4);
+    }
+
+    #[test]
+    fn logs_out_when_the_sequence_number_is_too_low() {
+        let now = Instant::now();
+        let (mut session, _) = start(logon(1, Some(30)), now).unwrap();
+        session.receive(order(2), now);
+
+        let actions = session.receive(order(2), now);
+        assert_eq!(
+            summary(&actions),
+            vec![
+                ("send", FixMessageType::Logout, 2),
+                ("disconnect", FixMessageType::Logout, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn resends_application_messages_and_gap_fills_the_rest() {
+        let now = Instant::now();
+        let (mut session, _) = start(logon(1, Some(30)), now).unwrap();
+        let report = client(FixMessageType::ExecutionReport, 0, Body::default());
+        session.send(report.clone(), now);
+        session.poll_timers(now + Duration::from_secs(30));
+        session.send(report, now);
+
+        let request = client(
+            FixMessageType::ResendRequest,
+            2,
+            Body {
+                begin_seq_no: Some(1),
+                end_seq_no: Some(0),
+                ..Body::default()
+            },
+        );
+        let actions = session.receive(request, now);
+        assert_eq!(
+            summary(&actions),
+            vec![
+                ("send", FixMessageType::SequenceReset, 1),
+                ("send", FixMessageType::ExecutionReport, 2),
+                ("send", FixMessageType::SequenceReset, 3),
+                ("send", FixMessageType::ExecutionReport, 4),
+            ]
+        );
+
+        let messages = sent(&actions);
+        assert!(messages
+            .iter()
+            .all(|x| x.header.poss_dup_flag == Some(true)));
+        assert_eq!(messages[0].body.new_seq_no, Some(2));
+        assert_eq!(messages[2].body.new_seq_no, Some(4));
+        assert!(messages[1].header.orig_sending_time.is_some());
+    }
+
+    #[test]
+    fn answers_resend_requests_that_arrive_ahead_of_a_gap() {
+        let now = Instant::now();
+        let (mut session, _) = start(logon(1, Some(30)), now).unwrap();
+        let report = client(FixMessageType::ExecutionReport, 0, Body::default());
+        session.send(report, now);
+
+        let request = client(
+            FixMessageType::ResendRequest,
+            3,
+            Body {
+                begin_seq_no: Some(2),
+                end_seq_no: Some(0),
+                ..Body::default()
+            },
+        );
+        let actions = session.receive(request, now);
+        assert_eq!(
+            summary(&actions),
+            vec![
+                ("send", FixMessageType::ExecutionReport, 2),
+                ("send", FixMessageType::ResendRequest, 3),
+            ]
+        );
+
+        // the resend request only takes up its sequence number once the gap is filled
+        let actions = session.receive(possible_duplicate(order(2)), now);
+        assert_eq!(
+            summary(&actions),
+            vec![("deliver", FixMessageType::NewOrderSingle, 2)]
+        );
+        assert_eq!(session.next_inbound, 4);
+    }
+
+    #[test]
+    fn logs_out_when_too_many_messages_are_held_back() {
+        let now = Instant::now();
+        let (mut session, _) = start(logon(1, Some(30)), now).unwrap();
+        for n in 3..3 + MAX_QUEUED as u64 {
+            session.receive(order(n), now);
+        }
+
+        let actions = session.receive(order(3 + MAX_QUEUED as u64), now);
+        assert_eq!(
+            summary(&actions),
+            vec![
+                ("send", FixMessageType::Logout, 3),
+                ("disconnect", FixMessageType::Logout, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn sends_heartbeats_and_test_requests_then_gives_up() {
+        let begin = Instant::now();
+        let (mut session, _) = start(logon(1, Some(10)), begin).unwrap();
+        assert_eq!(session.next_timer(), Some(begin + Duration::from_secs(10)));
+
+        let actions = session.poll_timers(begin + Duration::from_secs(10));
+        assert_eq!(
+            summary(&actions),
+            vec![("send", FixMessageType::HeartBeat, 2)]
+        );
+
+        let actions = session.poll_timers(begin + Duration::from_secs(12));
+        assert_eq!(
+            summary(&actions),
+            vec![("send", FixMessageType::TestRequest, 3)]
+        );
+        let test_req_id = sent(&actions)[0].body.test_req_id.clone();
+
+        // answering the test request clears it
+        let heartbeat = client(
+            FixMessageType::HeartBeat,
+            2,
+            Body {
+                test_req_id,
+                ..Body::default()
+            },
+        );
+        assert!(session
+            .receive(heartbeat, begin + Duration::from_secs(13))
+            .is_empty());
+
+        session.poll_timers(begin + Duration::from_secs(26));
+        let actions = session.poll_timers(begin + Duration::from_secs(36));
+        assert_eq!(
+            summary(&actions),
+            vec![
+                ("send", FixMessageType::Logout, 5),
+                ("disconnect", FixMessageType::Logout, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn answers_test_requests() {
+        let now = Instant::now();
+        let (mut session, _) = start(logon(1, Some(30)), now).unwrap();
+        let request = client(
+            FixMessageType::TestRequest,
+            2,
+            Body {
+                test_req_id: Some("ping".to_string()),
+                ..Body::default()
+            },
+        );
+
+        let actions = session.receive(request, now);
+        let heartbeat = sent(&actions)[0];
+        assert_eq!(heartbeat.header.msg_type, FixMessageType::HeartBeat);
+        assert_eq!(heartbeat.body.test_req_id.as_deref(), Some("ping"));
+    }
+
+    #[test]
+    fn carries_on_from_the_journal_after_reconnecting() {
+        let dir = std::env::temp_dir().join(format!("port-resume-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = crate::store::SessionStore::new(&dir).unwrap();
+        let now = Instant::now();
+        let connect = |logon: FixMessage| {
+            let (journal, restored) = store.open("buyer").unwrap();
+            Session::logon(logon, "matchbook".to_string(), restored, Some(journal), now)
+        };
+
+        {
+            let (mut session, _) = connect(logon(1, Some(30))).unwrap();
+            session.receive(order(2), now);
+            let report = client(FixMessageType::ExecutionReport, 0, Body::default());
+            session.send(report, now);
+        }
+
+        // the client can't go back to the start without resetting
+        let logout = connect(logon(1, Some(30))).unwrap_err();
+        assert_eq!(logout.header.msg_type, FixMessageType::Logout);
+
+        let (mut session, actions) = connect(logon(3, Some(30))).unwrap();
+        assert_eq!(summary(&actions), vec![("send", FixMessageType::Logon, 4)]);
+
+        // and can still ask for what it missed before it dropped
+        let request = client(
+            FixMessageType::ResendRequest,
+            4,
+            Body {
+                begin_seq_no: Some(2),
+                end_seq_no: Some(2),
+                ..Body::default()
+            },
+        );
+        let actions = session.receive(request, now);
+        assert_eq!(
+            summary(&actions),
+            vec![("send", FixMessageType::ExecutionReport, 2)]
+        );
+
+        let mut reset = logon(1, Some(30));
+        reset.body.reset_seq_num_flag = Some(true);
+        let (session, actions) = connect(reset).unwrap();
+        let reply = sent(&actions)[0];
+        assert_eq!(reply.header.msg_seq_num, 1);
+        assert_eq!(reply.body.reset_seq_num_flag, Some(true));
+        assert_eq!(session.next_inbound, 2);
+        drop(session);
+
+        assert_eq!(store.open("buyer").unwrap().1.next_outbound, 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
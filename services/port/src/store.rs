@@ -0,0 +1,359 @@
+use fixer_upper::Message as FixMessage;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Most sent messages kept for resending, anything older is gap filled instead
+pub const MAX_RETAINED: usize = 10_000;
+/// Records appended to a journal before it's compacted again
+const COMPACT_AFTER: usize = 2 * MAX_RETAINED;
+
+/// Where a FIX session was up to when its client last disconnected
+#[derive(Debug, Clone)]
+pub struct SessionState {
+    /// Sequence number the next message from the client should have
+    pub next_inbound: u64,
+    /// Sequence number of the next message to the client
+    pub next_outbound: u64,
+    /// Application messages sent to the client, which it can ask to have resent
+    pub sent: BTreeMap<u64, FixMessage>,
+}
+
+impl SessionState {
+    /// Forget all but the most recent `MAX_RETAINED` sent messages
+    fn retain(&mut self) {
+        while self.sent.len() > MAX_RETAINED {
+            let first = *self.sent.keys().next().expect("sent isn't empty");
+            self.sent.remove(&first);
+        }
+    }
+}
+
+impl Default for SessionState {
+    fn default() -> Self {
+        Self {
+            next_inbound: 1,
+            next_outbound: 1,
+            sent: BTreeMap::new(),
+        }
+    }
+}
+
+/// A line of a session journal
+#[derive(Debug, Serialize, Deserialize)]
+enum Record {
+    /// Everything before this sequence number has been received from the client
+    Received(u64),
+    /// An application message sent to the client
+    Sent(Box<FixMessage>),
+    /// A session message went to the client with this sequence number, it's gap filled rather than resent
+    Skipped(u64),
+    /// Where the session was up to when the journal was compacted, the sent messages still kept follow it
+    State {
+        next_inbound: u64,
+        next_outbound: u64,
+    },
+}
+
+/// A directory holding the journal of every participant's FIX session, one file each
+#[derive(Debug, Clone)]
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    /// Keep sessions in `dir`, creating it if it doesn't exist yet
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Open the journal of `participant_id`'s session, along with where the session was up to
+    pub fn open(
+        &self,
+        participant_id: &str,
+    ) -> Result<(SessionJournal, SessionState), Box<dyn std::error::Error>> {
+        let path = self
+            .dir
+            .join(format!("{}.jsonl", file_name(participant_id)));
+        let mut state = SessionState::default();
+        if path.exists() {
+            let file = File::open(&path)?;
+            let mut reader = BufReader::new(file);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    break;
+                }
+
+                // we stopped part way through writing the last line, it never happened
+                if !line.ends_with('\n') {
+                    break;
+                }
+                let record: Record = serde_json::from_str(&line)
+                    .map_err(|e| format!("corrupt session journal {:?}: {}", path, e))?;
+
+                match record {
+                    Record::Received(x) => state.next_inbound = x,
+                    Record::Sent(message) => {
+                        let n = message.header.msg_seq_num;
+                        state.next_outbound = state.next_outbound.max(n + 1);
+                        state.sent.insert(n, *message);
+                    }
+                    Record::Skipped(x) => state.next_outbound = state.next_outbound.max(x + 1),
+                    Record::State {
+                        next_inbound,
+                        next_outbound,
+                    } => {
+                        state.next_inbound = next_inbound;
+                        state.next_outbound = next_outbound;
+                    }
+                }
+            }
+        }
+        state.retain();
+
+        let file = compact(&path, state.next_inbound, state.next_outbound, &state.sent)?;
+        Ok((
+            SessionJournal {
+                path,
+                file,
+                records: 0,
+            },
+            state,
+        ))
+    }
+}
+
+/// Rewrite the journal at `path` as just where the session is up to and the sent messages it still keeps, so it
+/// doesn't grow forever. Returns the journal ready to append to
+fn compact(
+    path: &Path,
+    next_inbound: u64,
+    next_outbound: u64,
+    sent: &BTreeMap<u64, FixMessage>,
+) -> Result<File, Box<dyn std::error::Error>> {
+    let compacted = path.with_extension("compacting");
+    {
+        let mut file = File::create(&compacted)?;
+        let mut write = |record: &Record| -> Result<(), Box<dyn std::error::Error>> {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+            Ok(())
+        };
+
+        write(&Record::State {
+            next_inbound,
+            next_outbound,
+        })?;
+        for message in sent.values() {
+            write(&Record::Sent(Box::new(message.clone())))?;
+        }
+        file.sync_all()?;
+    }
+    std::fs::rename(&compacted, path)?;
+
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+/// Somewhere to write down a session's progress as it happens
+#[derive(Debug)]
+pub struct SessionJournal {
+    path: PathBuf,
+    file: File,
+    /// Records appended since the journal was last compacted
+    records: usize,
+}
+
+impl SessionJournal {
+    /// Everything before `next_inbound` has been received from the client
+    pub fn received(&mut self, next_inbound: u64) -> Result<(), Box<dyn std::error::Error>> {
+        self.write(&Record::Received(next_inbound))
+    }
+
+    /// `message` was sent to the client. Session messages only have their sequence number kept
+    pub fn sent(
+        &mut self,
+        message: &FixMessage,
+        resendable: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if resendable {
+            self.write(&Record::Sent(Box::new(message.clone())))
+        } else {
+            self.write(&Record::Skipped(message.header.msg_seq_num))
+        }
+    }
+
+    /// Forget everything, the session is starting over from 1
+    pub fn reset(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.file.set_len(0)?;
+        self.records = 0;
+        Ok(())
+    }
+
+    /// Whether enough has been appended that the journal should be compacted
+    pub fn needs_compaction(&self) -> bool {
+        self.records >= COMPACT_AFTER
+    }
+
+    /// Rewrite the journal as just where the session is up to and the sent messages it still keeps
+    pub fn compact(
+        &mut self,
+        next_inbound: u64,
+        next_outbound: u64,
+        sent: &BTreeMap<u64, FixMessage>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.file = compact(&self.path, next_inbound, next_outbound, sent)?;
+        self.records = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, record: &Record) -> Result<(), Box<dyn std::error::Error>> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.flush()?;
+        self.records += 1;
+        Ok(())
+    }
+}
+
+/// `participant_id` made safe to use as a file name. Anything but letters, digits, `-` and `_` is hex escaped, so
+/// two ids never share a file and an id can't point outside the store
+fn file_name(participant_id: &str) -> String {
+    participant_id
+        .bytes()
+        .map(|x| match x {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => (x as char).to_string(),
+            x => format!("%{:02X}", x),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fixer_upper::{BeginString, Body, Header, MessageType, Trailer};
+
+    fn message(msg_type: MessageType, msg_seq_num: u64) -> FixMessage {
+        FixMessage {
+            header: Header {
+                begin_string: BeginString::Fix_4_4,
+                body_length: None,
+                msg_type,
+                sender_comp_id: "matchbook".to_string(),
+                target_comp_id: "buyer".to_string(),
+                msg_seq_num,
+                poss_dup_flag: None,
+                sending_time: chrono::Utc::now(),
+                orig_sending_time: None,
+            },
+            body: Body::default(),
+            trailer: Trailer::default(),
+        }
+    }
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("port-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn restores_where_a_session_was_up_to() {
+        let dir = store_dir("restore");
+        let store = SessionStore::new(&dir).unwrap();
+        {
+            let (mut journal, state) = store.open("buyer").unwrap();
+            assert_eq!(state.next_inbound, 1);
+            assert_eq!(state.next_outbound, 1);
+
+            journal
+                .sent(&message(MessageType::Logon, 1), false)
+                .unwrap();
+            journal.received(2).unwrap();
+            journal
+                .sent(&message(MessageType::ExecutionReport, 2), true)
+                .unwrap();
+            journal
+                .sent(&message(MessageType::HeartBeat, 3), false)
+                .unwrap();
+            journal.received(3).unwrap();
+        }
+
+        // a record that was cut off half way through being written
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join("buyer.jsonl"))
+            .unwrap();
+        write!(file, "{{\"Received\":").unwrap();
+
+        let (mut journal, state) = store.open("buyer").unwrap();
+        assert_eq!(state.next_inbound, 3);
+        assert_eq!(state.next_outbound, 4);
+        assert_eq!(state.sent.keys().copied().collect::<Vec<_>>(), vec![2]);
+
+        journal.received(4).unwrap();
+        drop(journal);
+        let (mut journal, state) = store.open("buyer").unwrap();
+        assert_eq!(state.next_inbound, 4);
+        assert_eq!(state.next_outbound, 4);
+
+        journal.reset().unwrap();
+        let (_, state) = store.open("buyer").unwrap();
+        assert_eq!(state.next_inbound, 1);
+        assert!(state.sent.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compacts_journals_and_forgets_old_sent_messages() {
+        let dir = store_dir("compact");
+        let store = SessionStore::new(&dir).unwrap();
+        {
+            let (mut journal, _) = store.open("buyer").unwrap();
+            for n in 1..=MAX_RETAINED as u64 + 2 {
+                journal
+                    .sent(&message(MessageType::ExecutionReport, n), true)
+                    .unwrap();
+                journal.received(n + 1).unwrap();
+            }
+            journal
+                .sent(
+                    &message(MessageType::HeartBeat, MAX_RETAINED as u64 + 3),
+                    false,
+                )
+                .unwrap();
+        }
+
+        let (_, state) = store.open("buyer").unwrap();
+        assert_eq!(state.next_inbound, MAX_RETAINED as u64 + 3);
+        assert_eq!(state.next_outbound, MAX_RETAINED as u64 + 4);
+        assert_eq!(state.sent.len(), MAX_RETAINED);
+        assert_eq!(state.sent.keys().next(), Some(&3));
+
+        let lines = std::fs::read_to_string(dir.join("buyer.jsonl"))
+            .unwrap()
+            .lines()
+            .count();
+        assert_eq!(lines, MAX_RETAINED + 1);
+
+        let (_, reopened) = store.open("buyer").unwrap();
+        assert_eq!(reopened.next_inbound, state.next_inbound);
+        assert_eq!(reopened.next_outbound, state.next_outbound);
+        assert_eq!(reopened.sent.len(), MAX_RETAINED);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn escapes_participant_ids_in_file_names() {
+        assert_eq!(file_name("buyer-1_a"), "buyer-1_a");
+        assert_eq!(file_name("../etc/passwd"), "%2E%2E%2Fetc%2Fpasswd");
+        assert_ne!(file_name("a/b"), file_name("a%2Fb"));
+    }
+}
@@ -1,15 +1,32 @@
 use std::str::FromStr;
-use std::{env, net::SocketAddr};
+use std::{env, net::SocketAddr, path::PathBuf, time::Duration};
 
 use matchbook_types::ServiceId;
 
 const MULTICAST_ADDR_ENV_VAR_NAME: &str = "MULTICAST_ADDR";
 const SERVICE_ID_ENV_VAR_NAME: &str = "SERVICE_ID";
+const STORE_DIR_ENV_VAR_NAME: &str = "STORE_DIR";
+const RETENTION_MAX_AGE_ENV_VAR_NAME: &str = "RETENTION_MAX_AGE_SECS";
+const RETENTION_MAX_MESSAGES_ENV_VAR_NAME: &str = "RETENTION_MAX_MESSAGES";
+const RETRANSMIT_RATE_ENV_VAR_NAME: &str = "RETRANSMIT_RATE";
+const DEFAULT_STORE_DIR: &str = "message-log";
+/// A trading day
+const DEFAULT_RETENTION_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_RETENTION_MAX_MESSAGES: usize = 1_000_000;
+const DEFAULT_RETRANSMIT_RATE: u32 = 1_000;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub service_id: ServiceId,
     pub multicast_addr: SocketAddr,
+    /// Directory the log of messages that can be retransmitted is kept in
+    pub store_dir: PathBuf,
+    /// How long messages can be retransmitted for
+    pub retention_max_age: Duration,
+    /// How many messages are kept to be retransmitted
+    pub retention_max_messages: usize,
+    /// Most messages retransmitted per second
+    pub retransmit_rate: u32,
 }
 
 pub fn source_config_from_env() -> Result<Config, Box<dyn std::error::Error>> {
@@ -17,5 +34,20 @@ pub fn source_config_from_env() -> Result<Config, Box<dyn std::error::Error>> {
         service_id: env::var(SERVICE_ID_ENV_VAR_NAME)
             .map(|x| ServiceId::from_str(x.as_str()))??,
         multicast_addr: env::var(MULTICAST_ADDR_ENV_VAR_NAME).map(|x| x.parse())??,
+        store_dir: env::var(STORE_DIR_ENV_VAR_NAME)
+            .unwrap_or_else(|_| DEFAULT_STORE_DIR.to_string())
+            .into(),
+        retention_max_age: Duration::from_secs(match env::var(RETENTION_MAX_AGE_ENV_VAR_NAME) {
+            Ok(x) => x.parse()?,
+            Err(_) => DEFAULT_RETENTION_MAX_AGE_SECS,
+        }),
+        retention_max_messages: match env::var(RETENTION_MAX_MESSAGES_ENV_VAR_NAME) {
+            Ok(x) => x.parse()?,
+            Err(_) => DEFAULT_RETENTION_MAX_MESSAGES,
+        },
+        retransmit_rate: match env::var(RETRANSMIT_RATE_ENV_VAR_NAME) {
+            Ok(x) => x.parse()?,
+            Err(_) => DEFAULT_RETRANSMIT_RATE,
+        },
     })
 }
@@ -1,12 +1,15 @@
 #![deny(clippy::all)]
 
 mod config;
+mod store;
 
 use {
     futures::{Sink, SinkExt, Stream, StreamExt},
     matchbook_types::*,
     matchbook_util::*,
-    std::{collections::HashMap, marker::Unpin},
+    std::{collections::VecDeque, marker::Unpin, time::Duration},
+    store::{LogStore, MessageStore, Retention, TopicRange},
+    tokio::time::Instant,
     tracing::*,
 };
 
@@ -15,42 +18,126 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
     let config = config::source_config_from_env()?;
 
-    info!("started listening");
+    info!(%config.service_id, "started listening");
+
+    let store = LogStore::open(
+        &config.store_dir,
+        Retention {
+            max_age: Some(config.retention_max_age),
+            max_messages: Some(config.retention_max_messages),
+        },
+        store::DEFAULT_SEGMENT_LEN,
+    )?;
+    info!(messages = store.len(), "loaded message log");
 
     let (sink, stream) = make_matchbook_streams(config.multicast_addr)?;
 
-    spawn_main_loop(stream, sink).await;
+    spawn_main_loop(stream, sink, store, config.retransmit_rate).await;
     Ok(())
 }
 
-pub async fn spawn_main_loop<St, Si>(mut stream: St, mut sink: Si)
+/// Most replies waiting to be sent at once. Requests that don't fit are cut short, whoever asked will ask again for
+/// the rest
+const MAX_OUTBOX_LEN: usize = 10_000;
+
+pub async fn spawn_main_loop<St, Si, M>(mut stream: St, mut sink: Si, mut store: M, max_rate: u32)
 where
     St: Stream<Item = Result<Message, std::io::Error>> + Unpin,
     Si: Sink<Message> + Unpin,
+    M: MessageStore,
 {
-    let mut cache = HashMap::new();
+    // replies are spaced out so a big request can't flood the backbone
+    let interval = Duration::from_secs(1) / max_rate.max(1);
+    let mut next_send = Instant::now();
+    let mut outbox = VecDeque::new();
+
     loop {
-        let message = stream.next().await;
-
-        match message {
-            Some(Ok(message)) => {
-                debug!(?message.id, "received message");
-
-                match message.kind {
-                    MessageKind::RetransmitRequest => {
-                        if let Some(msg) = cache.get(&message.id).cloned() {
-                            let _ = sink.send(msg).await;
-                        }
-                    }
-                    // we don't want to cache any retransmit requests, or any message that we've already seen
-                    _ => {
-                        if !cache.contains_key(&message.id) {
-                            cache.insert(message.id.clone(), message.clone());
-                        }
-                    }
+        tokio::select! {
+            message = stream.next() => match message {
+                Some(Ok(message)) => {
+                    debug!(?message.id, "received message");
+                    receive(message, &mut store, &mut outbox);
+                }
+                Some(Err(_)) => continue,
+                None => break,
+            },
+            _ = tokio::time::sleep_until(next_send), if !outbox.is_empty() => {
+                let msg = outbox.pop_front().expect("outbox isn't empty");
+                // tagged so nobody mistakes it for a new message
+                let _ = sink.send(Message { replay: true, ..msg }).await;
+                next_send = Instant::now() + interval;
+            }
+        }
+    }
+}
+
+fn receive<M: MessageStore>(message: Message, store: &mut M, outbox: &mut VecDeque<Message>) {
+    let stored = |store: &M, id: &MessageId| {
+        store.get(id).unwrap_or_else(|e| {
+            error!(?id, "failed to read stored message: {}", e);
+            None
+        })
+    };
+
+    // a message past the retention limits is never coming back, so whoever asked for it can skip it. One we just
+    // don't have might only have been lost on its way here, whoever asked gives up on it by themselves if it never
+    // turns up
+    let reply = |store: &M, id: MessageId, topic: TopicRange| match stored(store, &id) {
+        Some(msg) => Some(msg),
+        None if id.topic_sequence_n <= topic.dropped => Some(Message {
+            id,
+            kind: MessageKind::GapFill,
+            replay: true,
+        }),
+        None => None,
+    };
+
+    match message.kind {
+        MessageKind::RetransmitRequest => {
+            let topic = store.topic(message.id.publisher_id, &message.id.topic_id);
+            if let Some(msg) = reply(store, message.id, topic) {
+                if outbox.len() < MAX_OUTBOX_LEN {
+                    outbox.push_back(msg);
+                }
+            }
+        }
+        MessageKind::RetransmitRangeRequest {
+            last_topic_sequence_n,
+        } => {
+            // nothing past the last message stored can be answered, and no more can be looked up than fit in the
+            // outbox, so a huge range doesn't hold up everything else
+            let topic = store.topic(message.id.publisher_id, &message.id.topic_id);
+            let first = message.id.topic_sequence_n;
+            let last = last_topic_sequence_n
+                .min(topic.last)
+                .min(first.saturating_add(MAX_OUTBOX_LEN as u64 - 1));
+
+            let mut missing = 0;
+            for topic_sequence_n in first..=last {
+                if outbox.len() >= MAX_OUTBOX_LEN {
+                    break;
+                }
+
+                let id = MessageId {
+                    topic_sequence_n,
+                    ..message.id.clone()
+                };
+                match reply(store, id, topic) {
+                    Some(msg) => outbox.push_back(msg),
+                    None => missing += 1,
                 }
             }
-            _ => continue,
+            if missing > 0 {
+                warn!(?message.id, last_topic_sequence_n, missing, "can't retransmit messages we don't have");
+            }
+        }
+        // someone else couldn't retransmit the message, there might still be a real one to store
+        MessageKind::GapFill => {}
+        // the store keeps the first message with an id
+        _ => {
+            if let Err(e) = store.insert(message) {
+                error!("failed to store message: {}", e);
+            }
         }
     }
 }
@@ -61,12 +148,32 @@ mod test {
     use futures::SinkExt;
     use futures_test::*;
 
+    const RATE: u32 = 1_000;
+
+    fn replay(message: Message) -> Message {
+        Message {
+            replay: true,
+            ..message
+        }
+    }
+
+    fn store(name: &str) -> LogStore {
+        let dir = std::env::temp_dir().join(format!(
+            "retransmitter-main-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        LogStore::open(dir, Retention::default(), store::DEFAULT_SEGMENT_LEN).unwrap()
+    }
+
     #[tokio::test]
     async fn retransmits_first_message_received_when_a_message_with_a_duplicate_id_is_received() {
         let (sink_tx, mut sink_rx) = futures::channel::mpsc::channel(1);
         let (mut stream_tx, stream_rx) = futures::channel::mpsc::channel(1);
 
-        tokio::spawn(async move { spawn_main_loop(stream_rx, sink_tx).await });
+        let store = store("first_received");
+        tokio::spawn(async move { spawn_main_loop(stream_rx, sink_tx, store, RATE).await });
 
         let id = MessageId {
             publisher_id: ServiceId {
@@ -81,25 +188,36 @@ mod test {
             id: id.clone(),
             kind: MessageKind::LimitOrderSubmitRequest {
                 side: Side::Bid,
-                price: 100,
-                quantity: 100,
+                price: Price::from(100),
+                quantity: Quantity::from(100),
                 symbol: ['A', 'D', 'B', 'E'],
+                time_in_force: TimeInForce::GoodTillCancel,
+                display_quantity: None,
+                post_only: false,
+                self_trade_prevention: None,
             },
+            replay: false,
         };
 
         let id_colliding_message = Message {
             id: id.clone(),
             kind: MessageKind::LimitOrderSubmitRequest {
                 side: Side::Bid,
-                price: 100,
-                quantity: 100,
+                price: Price::from(100),
+                quantity: Quantity::from(100),
                 symbol: ['A', 'D', 'B', 'E'],
+                time_in_force: TimeInForce::GoodTillCancel,
+                display_quantity: None,
+                post_only: false,
+                self_trade_prevention: None,
             },
+            replay: false,
         };
 
         let retransmit_req = Message {
             id: id.clone(),
             kind: MessageKind::RetransmitRequest,
+            replay: false,
         };
 
         stream_tx.send(Ok(first_received.clone())).await.unwrap();
@@ -114,7 +232,7 @@ mod test {
 
         assert_eq!(
             sink_rx.next().await.unwrap(),
-            first_received.clone(),
+            replay(first_received.clone()),
             "received different message than the first message the retransmitter received"
         );
         assert_stream_pending!(sink_rx);
@@ -123,7 +241,7 @@ mod test {
 
         assert_eq!(
             sink_rx.next().await.unwrap(),
-            first_received.clone(),
+            replay(first_received.clone()),
             "received different message after requesting another retranimst. the same message should always be retransmitted"
         );
         assert_stream_pending!(sink_rx);
@@ -134,7 +252,8 @@ mod test {
         let (sink_tx, mut sink_rx) = futures::channel::mpsc::channel(1);
         let (mut stream_tx, stream_rx) = futures::channel::mpsc::channel(1);
 
-        tokio::spawn(async move { spawn_main_loop(stream_rx, sink_tx).await });
+        let store = store("unknown");
+        tokio::spawn(async move { spawn_main_loop(stream_rx, sink_tx, store, RATE).await });
 
         stream_tx
             .send(Ok(Message {
@@ -147,6 +266,7 @@ mod test {
                     topic_sequence_n: 1000,
                 },
                 kind: MessageKind::RetransmitRequest,
+                replay: false,
             }))
             .await
             .unwrap();
@@ -169,10 +289,15 @@ mod test {
             id: to_retransmit_id_1.clone(),
             kind: MessageKind::LimitOrderSubmitRequest {
                 side: Side::Bid,
-                price: 100,
-                quantity: 100,
+                price: Price::from(100),
+                quantity: Quantity::from(100),
                 symbol: ['A', 'D', 'B', 'E'],
+                time_in_force: TimeInForce::GoodTillCancel,
+                display_quantity: None,
+                post_only: false,
+                self_trade_prevention: None,
             },
+            replay: false,
         };
 
         let to_retransmit_id_2 = MessageId {
@@ -188,26 +313,34 @@ mod test {
             id: to_retransmit_id_2.clone(),
             kind: MessageKind::LimitOrderSubmitRequest {
                 side: Side::Bid,
-                price: 100,
-                quantity: 100,
+                price: Price::from(100),
+                quantity: Quantity::from(100),
                 symbol: ['A', 'D', 'B', 'E'],
+                time_in_force: TimeInForce::GoodTillCancel,
+                display_quantity: None,
+                post_only: false,
+                self_trade_prevention: None,
             },
+            replay: false,
         };
 
         let retransmit_1_req = Message {
             id: to_retransmit_id_1.clone(),
             kind: MessageKind::RetransmitRequest,
+            replay: false,
         };
 
         let retransmit_2_req = Message {
             id: to_retransmit_id_2.clone(),
             kind: MessageKind::RetransmitRequest,
+            replay: false,
         };
 
         let (sink_tx, mut sink_rx) = futures::channel::mpsc::channel(1);
         let (mut stream_tx, stream_rx) = futures::channel::mpsc::channel(1);
 
-        tokio::spawn(async move { spawn_main_loop(stream_rx, sink_tx).await });
+        let store = store("in_order");
+        tokio::spawn(async move { spawn_main_loop(stream_rx, sink_tx, store, RATE).await });
 
         // send messages for retrieval
         stream_tx.send(Ok(to_retransmit_1.clone())).await.unwrap();
@@ -221,7 +354,113 @@ mod test {
 
         stream_tx.send(Ok(retransmit_1_req)).await.unwrap();
 
-        assert_eq!(sink_rx.next().await.unwrap(), to_retransmit_2);
-        assert_eq!(sink_rx.next().await.unwrap(), to_retransmit_1);
+        assert_eq!(sink_rx.next().await.unwrap(), replay(to_retransmit_2));
+        assert_eq!(sink_rx.next().await.unwrap(), replay(to_retransmit_1));
+    }
+
+    #[tokio::test]
+    async fn replays_a_range_in_order_skipping_what_it_doesnt_have() {
+        let message = |topic_sequence_n| Message {
+            id: MessageId {
+                publisher_id: ServiceId {
+                    kind: ServiceKind::MatchingEngine,
+                    number: 0,
+                },
+                topic_id: "client1".to_owned(),
+                topic_sequence_n,
+            },
+            kind: MessageKind::CancelRequest {
+                id: topic_sequence_n as usize,
+                symbol: ['A', 'D', 'B', 'E'],
+            },
+            replay: false,
+        };
+
+        let (sink_tx, mut sink_rx) = futures::channel::mpsc::channel(1);
+        let (mut stream_tx, stream_rx) = futures::channel::mpsc::channel(1);
+
+        // slow enough that replaying the range takes a noticeable amount of time
+        let store = store("range");
+        tokio::spawn(async move { spawn_main_loop(stream_rx, sink_tx, store, 50).await });
+
+        for n in &[1, 2, 4] {
+            stream_tx.send(Ok(message(*n))).await.unwrap();
+        }
+
+        let start = std::time::Instant::now();
+        stream_tx
+            .send(Ok(Message {
+                kind: MessageKind::RetransmitRangeRequest {
+                    last_topic_sequence_n: 6,
+                },
+                ..message(2)
+            }))
+            .await
+            .unwrap();
+
+        // nothing stands in for 3, or for 5 and 6 which haven't been seen yet
+        assert_eq!(sink_rx.next().await.unwrap(), replay(message(2)));
+        assert_eq!(sink_rx.next().await.unwrap(), replay(message(4)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_stream_pending!(sink_rx);
+    }
+
+    #[tokio::test]
+    async fn gap_fills_what_retention_dropped_and_stops_at_the_last_message_stored() {
+        let message = |topic_sequence_n| Message {
+            id: MessageId {
+                publisher_id: ServiceId {
+                    kind: ServiceKind::MatchingEngine,
+                    number: 0,
+                },
+                topic_id: "client1".to_owned(),
+                topic_sequence_n,
+            },
+            kind: MessageKind::CancelRequest {
+                id: topic_sequence_n as usize,
+                symbol: ['A', 'D', 'B', 'E'],
+            },
+            replay: false,
+        };
+        let gap_fill = |n| Message {
+            kind: MessageKind::GapFill,
+            ..replay(message(n))
+        };
+
+        let dir =
+            std::env::temp_dir().join(format!("retransmitter-main-dropped-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let retention = Retention {
+            max_age: None,
+            max_messages: Some(3),
+        };
+        let mut store = LogStore::open(&dir, retention, 2).unwrap();
+        let mut outbox = VecDeque::new();
+        // 1 and 2 are dropped once there are more than 3 others
+        for n in &[1, 2, 3, 4, 5, 7] {
+            receive(message(*n), &mut store, &mut outbox);
+        }
+
+        receive(
+            Message {
+                kind: MessageKind::RetransmitRangeRequest {
+                    last_topic_sequence_n: u64::MAX,
+                },
+                ..message(1)
+            },
+            &mut store,
+            &mut outbox,
+        );
+        let expected = vec![
+            gap_fill(1),
+            gap_fill(2),
+            message(3),
+            message(4),
+            message(5),
+            message(7),
+        ];
+        assert_eq!(outbox.into_iter().collect::<Vec<_>>(), expected);
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
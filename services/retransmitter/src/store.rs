@@ -0,0 +1,572 @@
+use matchbook_types::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Number of messages written to a segment before starting the next one. Retention drops whole segments at a time
+pub const DEFAULT_SEGMENT_LEN: usize = 10_000;
+
+/// Somewhere to keep the messages that might need to be retransmitted
+pub trait MessageStore {
+    /// Keep `message` unless a message with the same id is already stored, the first one seen is the one retransmitted
+    fn insert(&mut self, message: Message) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// The stored message with `id`, if it's still kept
+    fn get(&self, id: &MessageId) -> Result<Option<Message>, Box<dyn std::error::Error>>;
+
+    /// How far the messages stored on a topic go
+    fn topic(&self, publisher_id: ServiceId, topic_id: &str) -> TopicRange;
+}
+
+/// How far the messages stored on a topic go. Everything up to and including `dropped` is past the retention limits,
+/// nothing after `last` has been stored. Both are 0 if there's nothing
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TopicRange {
+    pub dropped: u64,
+    pub last: u64,
+}
+
+/// How long messages are kept for. Whatever limit is reached first wins, no limit keeps messages forever
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    pub max_messages: Option<usize>,
+}
+
+/// A line of a segment file
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    /// Milliseconds since the unix epoch
+    received_at: u64,
+    message: Message,
+}
+
+#[derive(Debug)]
+struct Segment {
+    number: u64,
+    path: PathBuf,
+    file: File,
+    ids: Vec<MessageId>,
+    /// When the last message in the segment was received, in milliseconds since the unix epoch
+    last_received_at: u64,
+    len: u64,
+}
+
+/// An append-only log of messages, split over numbered segment files in a directory, with an index of where each
+/// message is in memory. The index is rebuilt from the segments when the store is opened
+#[derive(Debug)]
+pub struct LogStore {
+    dir: PathBuf,
+    /// Oldest first, the last one is being appended to
+    segments: VecDeque<Segment>,
+    /// Segment number and byte offset of every stored message
+    index: HashMap<MessageId, (u64, u64)>,
+    /// How far each topic goes, `dropped` is kept in a file of its own since the segments it came from are gone
+    topics: HashMap<(ServiceId, String), TopicRange>,
+    segment_len: usize,
+    retention: Retention,
+}
+
+impl LogStore {
+    /// Open the log in `dir`, creating it if it doesn't exist yet
+    pub fn open(
+        dir: impl AsRef<Path>,
+        retention: Retention,
+        segment_len: usize,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut numbers = vec![];
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension() == Some("log".as_ref()) {
+                if let Some(number) = path
+                    .file_stem()
+                    .and_then(|x| x.to_str())
+                    .and_then(|x| x.parse::<u64>().ok())
+                {
+                    numbers.push(number);
+                }
+            }
+        }
+        numbers.sort_unstable();
+
+        let mut store = Self {
+            dir,
+            segments: VecDeque::new(),
+            index: HashMap::new(),
+            topics: HashMap::new(),
+            segment_len: segment_len.max(1),
+            retention,
+        };
+
+        let dropped_path = store.dropped_path();
+        if dropped_path.exists() {
+            let dropped: Vec<(ServiceId, String, u64)> =
+                serde_json::from_reader(BufReader::new(File::open(&dropped_path)?))
+                    .map_err(|e| format!("corrupt {:?}: {}", dropped_path, e))?;
+            for (publisher_id, topic_id, dropped) in dropped {
+                store.topics.insert(
+                    (publisher_id, topic_id),
+                    TopicRange {
+                        dropped,
+                        last: dropped,
+                    },
+                );
+            }
+        }
+
+        let last = numbers.last().copied();
+        for number in numbers {
+            let segment = store.load_segment(number, Some(number) == last)?;
+            store.segments.push_back(segment);
+        }
+
+        if store.segments.is_empty() {
+            store.start_segment(0)?;
+        }
+
+        store.enforce_retention(now());
+        Ok(store)
+    }
+
+    /// Number of messages stored
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// The segment numbered `number`. Segments are in order, but ones missing from the directory leave gaps
+    fn segment(&self, number: u64) -> Option<&Segment> {
+        let (front, back) = self.segments.as_slices();
+        [front, back].iter().find_map(|segments| {
+            segments
+                .binary_search_by_key(&number, |x| x.number)
+                .ok()
+                .map(|i| &segments[i])
+        })
+    }
+
+    fn segment_path(&self, number: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.log", number))
+    }
+
+    fn dropped_path(&self) -> PathBuf {
+        self.dir.join("dropped.json")
+    }
+
+    /// Note that `id` is stored
+    fn stored(&mut self, id: &MessageId) {
+        let topic = self
+            .topics
+            .entry((id.publisher_id, id.topic_id.clone()))
+            .or_default();
+        topic.last = topic.last.max(id.topic_sequence_n);
+    }
+
+    /// Write down how far each topic has been dropped, swapping the file in whole so it's never left half written
+    fn save_dropped(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let dropped: Vec<_> = self
+            .topics
+            .iter()
+            .filter(|(_, x)| x.dropped > 0)
+            .map(|((publisher_id, topic_id), x)| (publisher_id, topic_id, x.dropped))
+            .collect();
+
+        let path = self.dropped_path();
+        let saving = path.with_extension("saving");
+        {
+            let mut file = File::create(&saving)?;
+            serde_json::to_writer(&mut file, &dropped)?;
+            file.sync_all()?;
+        }
+        std::fs::rename(&saving, &path)?;
+        Ok(())
+    }
+
+    /// Read a segment back, indexing every message in it. A cut short last line in the `last` segment was never
+    /// finished being written, it's cut off so appending can carry on after it
+    fn load_segment(
+        &mut self,
+        number: u64,
+        last: bool,
+    ) -> Result<Segment, Box<dyn std::error::Error>> {
+        let path = self.segment_path(number);
+        let file = OpenOptions::new().read(true).append(true).open(&path)?;
+
+        let mut reader = BufReader::new(&file);
+        let mut ids = vec![];
+        let mut last_received_at = 0;
+        let mut offset = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let n = reader.read_line(&mut line)?;
+            if n == 0 {
+                break;
+            }
+
+            let complete = line.ends_with('\n');
+            let record: Record = match serde_json::from_str(&line) {
+                Ok(record) if complete => record,
+                _ if last && !complete => {
+                    file.set_len(offset)?;
+                    break;
+                }
+                Ok(_) => {
+                    return Err(format!("segment {:?} ends part way through a line", path).into())
+                }
+                Err(e) => return Err(format!("corrupt segment {:?}: {}", path, e).into()),
+            };
+
+            self.index
+                .entry(record.message.id.clone())
+                .or_insert((number, offset));
+            self.stored(&record.message.id);
+            ids.push(record.message.id);
+            last_received_at = record.received_at;
+            offset += n as u64;
+        }
+
+        Ok(Segment {
+            number,
+            path,
+            file,
+            ids,
+            last_received_at,
+            len: offset,
+        })
+    }
+
+    fn start_segment(&mut self, number: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let path = self.segment_path(number);
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        self.segments.push_back(Segment {
+            number,
+            path,
+            file,
+            ids: vec![],
+            last_received_at: 0,
+            len: 0,
+        });
+        Ok(())
+    }
+
+    fn append(
+        &mut self,
+        message: Message,
+        received_at: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.index.contains_key(&message.id) {
+            return Ok(());
+        }
+
+        let (number, full) = match self.segments.back() {
+            Some(x) => (x.number, x.ids.len() >= self.segment_len),
+            None => (0, true),
+        };
+        if full {
+            self.start_segment(number + 1)?;
+        }
+
+        let segment = self
+            .segments
+            .back_mut()
+            .expect("a segment was just started");
+        let id = message.id.clone();
+        let mut line = serde_json::to_vec(&Record {
+            received_at,
+            message,
+        })?;
+        line.push(b'\n');
+        segment.file.write_all(&line)?;
+        segment.file.flush()?;
+
+        self.index.insert(id.clone(), (segment.number, segment.len));
+        segment.last_received_at = received_at;
+        segment.len += line.len() as u64;
+        segment.ids.push(id.clone());
+        self.stored(&id);
+
+        self.enforce_retention(received_at);
+        Ok(())
+    }
+
+    /// Drop the oldest segments once everything in them is past the retention limits. The segment being appended to
+    /// is always kept
+    fn enforce_retention(&mut self, now: u64) {
+        while self.segments.len() > 1 {
+            let oldest = &self.segments[0];
+            let too_old = match self.retention.max_age {
+                Some(x) => oldest.last_received_at + (x.as_millis() as u64) < now,
+                None => false,
+            };
+            let too_many = match self.retention.max_messages {
+                Some(x) => self.index.len() - oldest.ids.len() >= x,
+                None => false,
+            };
+
+            if !too_old && !too_many {
+                break;
+            }
+
+            let oldest = self.segments.pop_front().expect("there's more than one");
+            for id in oldest.ids {
+                if self.index.get(&id).map(|x| x.0) == Some(oldest.number) {
+                    self.index.remove(&id);
+                }
+                let topic = self
+                    .topics
+                    .entry((id.publisher_id, id.topic_id))
+                    .or_default();
+                topic.dropped = topic.dropped.max(id.topic_sequence_n);
+            }
+            // if this doesn't make it to disk, the worst that happens is that some dropped messages go unanswered
+            // after a restart instead of being gap filled
+            if let Err(e) = self.save_dropped() {
+                tracing::warn!("failed to save how far topics have been dropped: {}", e);
+            }
+            if let Err(e) = std::fs::remove_file(&oldest.path) {
+                tracing::warn!("failed to remove segment {:?}: {}", oldest.path, e);
+            }
+        }
+    }
+}
+
+impl MessageStore for LogStore {
+    fn insert(&mut self, message: Message) -> Result<(), Box<dyn std::error::Error>> {
+        self.append(message, now())
+    }
+
+    fn get(&self, id: &MessageId) -> Result<Option<Message>, Box<dyn std::error::Error>> {
+        let (number, offset) = match self.index.get(id) {
+            Some(x) => *x,
+            None => return Ok(None),
+        };
+
+        let segment = match self.segment(number) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let mut file = &segment.file;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        BufReader::new(file).read_line(&mut line)?;
+
+        let record: Record = serde_json::from_str(&line)?;
+        Ok(Some(record.message))
+    }
+
+    fn topic(&self, publisher_id: ServiceId, topic_id: &str) -> TopicRange {
+        self.topics
+            .get(&(publisher_id, topic_id.to_string()))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(topic_sequence_n: u64) -> Message {
+        Message {
+            id: MessageId {
+                publisher_id: ServiceId {
+                    kind: ServiceKind::Port,
+                    number: 0,
+                },
+                topic_id: "client1".to_owned(),
+                topic_sequence_n,
+            },
+            kind: MessageKind::CancelRequest {
+                id: topic_sequence_n as usize,
+                symbol: ['A', 'D', 'B', 'E'],
+            },
+            replay: false,
+        }
+    }
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("retransmitter-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn stored(store: &LogStore, topic_sequence_n: u64) -> Option<Message> {
+        store.get(&message(topic_sequence_n).id).unwrap()
+    }
+
+    #[test]
+    fn keeps_the_first_message_with_an_id() {
+        let dir = store_dir("first");
+        let mut store = LogStore::open(&dir, Retention::default(), 2).unwrap();
+
+        store.insert(message(1)).unwrap();
+        let mut colliding = message(1);
+        colliding.kind = MessageKind::CancelRequest {
+            id: 100,
+            symbol: ['A', 'D', 'B', 'E'],
+        };
+        store.insert(colliding).unwrap();
+
+        assert_eq!(stored(&store, 1), Some(message(1)));
+        assert_eq!(stored(&store, 2), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rebuilds_the_index_when_reopened() {
+        let dir = store_dir("reopen");
+        {
+            let mut store = LogStore::open(&dir, Retention::default(), 2).unwrap();
+            for n in 1..=5 {
+                store.insert(message(n)).unwrap();
+            }
+        }
+
+        // a message that was cut off half way through being written
+        let mut last = OpenOptions::new()
+            .append(true)
+            .open(dir.join(format!("{:020}.log", 2)))
+            .unwrap();
+        write!(last, "{{\"received_at\":").unwrap();
+
+        let mut store = LogStore::open(&dir, Retention::default(), 2).unwrap();
+        assert_eq!(store.len(), 5);
+        for n in 1..=5 {
+            assert_eq!(stored(&store, n), Some(message(n)));
+        }
+
+        store.insert(message(6)).unwrap();
+        let store = LogStore::open(&dir, Retention::default(), 2).unwrap();
+        assert_eq!(stored(&store, 6), Some(message(6)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn finds_messages_when_a_segment_is_missing() {
+        let dir = store_dir("missing");
+        {
+            let mut store = LogStore::open(&dir, Retention::default(), 2).unwrap();
+            for n in 1..=7 {
+                store.insert(message(n)).unwrap();
+            }
+        }
+        std::fs::remove_file(dir.join(format!("{:020}.log", 1))).unwrap();
+
+        let store = LogStore::open(&dir, Retention::default(), 2).unwrap();
+        assert_eq!(store.len(), 5);
+        for n in 1..=7 {
+            let expected = if n == 3 || n == 4 {
+                None
+            } else {
+                Some(message(n))
+            };
+            assert_eq!(stored(&store, n), expected);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drops_the_oldest_segments_past_the_message_limit() {
+        let dir = store_dir("count");
+        let retention = Retention {
+            max_age: None,
+            max_messages: Some(3),
+        };
+        let mut store = LogStore::open(&dir, retention, 2).unwrap();
+        for n in 1..=7 {
+            store.insert(message(n)).unwrap();
+        }
+
+        // whole segments go at a time, so between 3 and 3 + a segment are kept
+        assert_eq!(store.len(), 3);
+        assert_eq!(stored(&store, 4), None);
+        assert_eq!(stored(&store, 5), Some(message(5)));
+        let segments = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|x| x.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count();
+        assert_eq!(segments, 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn remembers_how_far_topics_go_after_their_messages_are_dropped() {
+        let dir = store_dir("topics");
+        let retention = Retention {
+            max_age: None,
+            max_messages: Some(3),
+        };
+        let id = message(0).id;
+        {
+            let mut store = LogStore::open(&dir, retention, 2).unwrap();
+            assert_eq!(
+                store.topic(id.publisher_id, &id.topic_id),
+                TopicRange::default()
+            );
+            for n in 1..=7 {
+                store.insert(message(n)).unwrap();
+            }
+            assert_eq!(
+                store.topic(id.publisher_id, &id.topic_id),
+                TopicRange {
+                    dropped: 4,
+                    last: 7
+                }
+            );
+        }
+
+        let store = LogStore::open(&dir, retention, 2).unwrap();
+        assert_eq!(
+            store.topic(id.publisher_id, &id.topic_id),
+            TopicRange {
+                dropped: 4,
+                last: 7
+            }
+        );
+        assert_eq!(
+            store.topic(id.publisher_id, "someone else"),
+            TopicRange::default()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drops_the_oldest_segments_past_the_age_limit() {
+        let dir = store_dir("age");
+        let retention = Retention {
+            max_age: Some(Duration::from_secs(60)),
+            max_messages: None,
+        };
+        let mut store = LogStore::open(&dir, retention, 2).unwrap();
+        let start = now();
+        store.append(message(1), start).unwrap();
+        store.append(message(2), start).unwrap();
+        store.append(message(3), start + 30_000).unwrap();
+        assert_eq!(store.len(), 3);
+
+        store.append(message(4), start + 61_000).unwrap();
+        assert_eq!(stored(&store, 2), None);
+        assert_eq!(stored(&store, 3), Some(message(3)));
+        assert_eq!(store.len(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}